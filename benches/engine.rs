@@ -0,0 +1,81 @@
+extern crate test;
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+extern crate time;
+
+use std::io::net::tcp::TcpStream;
+
+use test::Bencher;
+use time::precise_time_ns;
+
+use tensai::client::Client;
+use tensai::engine::Engine;
+use tensai::handshake::Handshake;
+use tensai::listener::RandomPort;
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::torrent::TorrentInfo;
+use tensai::wire::KeepAlive;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+
+fn torrent_info() -> TorrentInfo {
+    common::unhashed_torrent_info(1000, 256)
+}
+
+/// An engine with a running torrent and `count` peers connected to it
+fn connected_engine(info: &TorrentInfo, count: uint) -> (Engine, Vec<TcpStream>) {
+    let mut client = Client::new();
+    client.add_torrent_with_storage(info, box MemoryStorage::new(info) as Box<Storage>).start();
+    let port = client.listen(RandomPort).unwrap();
+    let capabilities = client.capabilities();
+    let mut engine = Engine::new(client).unwrap();
+    engine.limits.global = count;
+    engine.limits.per_torrent = count;
+
+    let mut peers = Vec::new();
+    for i in range(0, count) {
+        let mut peer = TcpStream::connect("127.0.0.1", port).unwrap();
+        let peer_id = format!("-XX0001-{:012u}", i);
+        Handshake::new(info.infohash.as_slice(), peer_id.as_bytes(), &capabilities).write_to(&mut peer).unwrap();
+        peers.push(peer);
+        // one at a time, so the listen backlog never fills up
+        while engine.connections(info.infohash.as_slice()).len() < peers.len() {
+            assert!(engine.step().is_empty());
+        }
+    }
+    (engine, peers)
+}
+
+/// Every peer sends a message, and the engine steps until it has read all
+/// of them
+fn bench_messages(b: &mut Bencher, count: uint) {
+    let info = torrent_info();
+    let (mut engine, mut peers) = connected_engine(&info, count);
+    b.iter(|| {
+        let sent = precise_time_ns();
+        for peer in peers.mut_iter() {
+            KeepAlive.write_to(peer).unwrap();
+        }
+        while !engine.connections(info.infohash.as_slice()).iter().all(|connection| connection.last_received >= sent) {
+            engine.step();
+        }
+    });
+}
+
+#[bench]
+fn messages_from_10_peers(b: &mut Bencher) {
+    bench_messages(b, 10);
+}
+
+#[bench]
+fn messages_from_100_peers(b: &mut Bencher) {
+    bench_messages(b, 100);
+}
+
+#[bench]
+fn messages_from_250_peers(b: &mut Bencher) {
+    bench_messages(b, 250);
+}
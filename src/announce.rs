@@ -3,15 +3,27 @@ extern crate bencode;
 use super::opt_finder;
 use peer::Peer;
 
-use bencode::{FromBencode, Dict, Key, List, ByteString, Bencode};
+use std::collections::TreeMap;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bencode::{FromBencode, ToBencode, Dict, Key, List, ByteString, Number, Bencode};
 
 
 #[deriving(Show)]
 pub enum AnnounceResponse {
-    Failure(String),
+    Failure(String, Option<RetryIn>),
     Success(AnnounceResult)
 }
 
+/// When a tracker wants to hear from us again (BEP 31)
+#[deriving(Show, Clone, PartialEq)]
+pub enum RetryIn {
+    /// Retry after this many minutes
+    RetryAfter(uint),
+    /// Don't announce to this tracker again
+    RetryNever,
+}
+
 #[deriving(Show)]
 pub struct AnnounceResult {
     pub warning_message: Option<String>,
@@ -21,6 +33,41 @@ pub struct AnnounceResult {
     pub complete: uint,
     pub incomplete: uint,
     pub peers: Vec<Peer>,
+    /// Our address as seen by the tracker (BEP 24)
+    pub external_ip: Option<IpAddr>,
+    pub retry_in: Option<RetryIn>,
+}
+
+fn find_retry_in(dict: &Dict) -> Option<RetryIn> {
+    match dict.find(&Key::from_str("retry in")) {
+        Some(&Number(minutes)) if minutes >= 0 => Some(RetryAfter(minutes as uint)),
+        Some(&ByteString(ref never)) if never.as_slice() == b"never" => Some(RetryNever),
+        _ => None
+    }
+}
+
+fn find_external_ip(dict: &Dict) -> Option<IpAddr> {
+    match dict.find(&Key::from_str("external ip")) {
+        Some(&ByteString(ref ip)) if ip.len() == 4 => {
+            Some(Ipv4Addr(*ip.get(0), *ip.get(1), *ip.get(2), *ip.get(3)))
+        },
+        Some(&ByteString(ref ip)) if ip.len() == 16 => {
+            let mut dbytes = [0u16, ..8];
+            for i in range(0u, 8) {
+                dbytes[i] = (*ip.get(i * 2) as u16 << 8) | *ip.get(i * 2 + 1) as u16;
+            }
+            Some(Ipv6Addr(dbytes[0], dbytes[1], dbytes[2], dbytes[3],
+                          dbytes[4], dbytes[5], dbytes[6], dbytes[7]))
+        },
+        _ => None
+    }
+}
+
+fn retry_in_to_bencode(retry_in: &RetryIn) -> Bencode {
+    match *retry_in {
+        RetryAfter(minutes) => Number(minutes as i64),
+        RetryNever => ByteString(Vec::from_slice(b"never"))
+    }
 }
 
 impl FromBencode for AnnounceResponse {
@@ -28,8 +75,8 @@ impl FromBencode for AnnounceResponse {
         match bencode {
             &Dict(ref dict) => {
                 match dict.find(&Key::from_str("failure reason")) {
-                    Some(&ByteString(ref message)) => return Some(Failure(String::from_utf8((message.clone())).ok().expect("unknown error"))),
-                    Some(_) => return Some(Failure("unknown error".to_str())),
+                    Some(&ByteString(ref message)) => return Some(Failure(String::from_utf8((message.clone())).ok().expect("unknown error"), find_retry_in(dict))),
+                    Some(_) => return Some(Failure("unknown error".to_str(), find_retry_in(dict))),
                     None => ()
                 }
                 let mut peers = Vec::new();
@@ -51,6 +98,16 @@ impl FromBencode for AnnounceResponse {
                     },
                     _ => ()
                 }
+                match dict.find(&Key::from_str("peers6")) {
+                    Some(&ByteString(ref peervec)) => {
+                        for bytes in peervec.as_slice().chunks(18) {
+                            if bytes.len() < 18 { break; }
+                            let mut v = [0u8, ..18]; v.copy_from(bytes);
+                            peers.push(Peer::from_18byte(&v));
+                        }
+                    },
+                    _ => ()
+                }
                 Some(Success(AnnounceResult {
                     warning_message: opt_finder(dict, "warning message"),
                     // default to 10 minutes
@@ -59,10 +116,89 @@ impl FromBencode for AnnounceResponse {
                     tracker_id: opt_finder(dict, "tracker id"),
                     complete: opt_finder(dict, "complete").unwrap_or(0u),
                     incomplete: opt_finder(dict, "incomplete").unwrap_or(0u),
-                    peers: peers
+                    peers: peers,
+                    external_ip: find_external_ip(dict),
+                    retry_in: find_retry_in(dict),
                 }))
             },
             _ => None
         }
     }
 }
+
+impl AnnounceResponse {
+    /// Encode the response the way a tracker would send it.
+    ///
+    /// With `compact` set IPv4 peers are packed into `peers` and IPv6 peers
+    /// into `peers6` (BEP 7 and BEP 23), otherwise every peer is sent as a
+    /// dictionary in the `peers` list.
+    pub fn to_bencode(&self, compact: bool) -> Bencode {
+        match *self {
+            Failure(ref message, ref retry_in) => {
+                let mut dict = TreeMap::new();
+                dict.insert(Key::from_str("failure reason"), ByteString(Vec::from_slice(message.as_bytes())));
+                match *retry_in {
+                    Some(ref retry_in) => { dict.insert(Key::from_str("retry in"), retry_in_to_bencode(retry_in)); },
+                    None => ()
+                }
+                Dict(dict)
+            },
+            Success(ref result) => result.to_bencode(compact)
+        }
+    }
+}
+
+impl AnnounceResult {
+    /// See `AnnounceResponse::to_bencode`
+    pub fn to_bencode(&self, compact: bool) -> Bencode {
+        let mut dict = TreeMap::new();
+        match self.warning_message {
+            Some(ref message) => { dict.insert(Key::from_str("warning message"), ByteString(Vec::from_slice(message.as_bytes()))); },
+            None => ()
+        }
+        dict.insert(Key::from_str("interval"), Number(self.interval as i64));
+        match self.min_interval {
+            Some(min_interval) => { dict.insert(Key::from_str("min interval"), Number(min_interval as i64)); },
+            None => ()
+        }
+        match self.tracker_id {
+            Some(ref tracker_id) => { dict.insert(Key::from_str("tracker id"), ByteString(Vec::from_slice(tracker_id.as_bytes()))); },
+            None => ()
+        }
+        dict.insert(Key::from_str("complete"), Number(self.complete as i64));
+        dict.insert(Key::from_str("incomplete"), Number(self.incomplete as i64));
+        match self.external_ip {
+            Some(ip) => {
+                let peer = Peer { address: SocketAddr { ip: ip, port: 0 }, peer_id: None };
+                let bytes = match (peer.to_6byte(), peer.to_18byte()) {
+                    (Some(bytes), _) => Vec::from_slice(bytes.slice_to(4)),
+                    (_, Some(bytes)) => Vec::from_slice(bytes.slice_to(16)),
+                    _ => unreachable!()
+                };
+                dict.insert(Key::from_str("external ip"), ByteString(bytes));
+            },
+            None => ()
+        }
+        match self.retry_in {
+            Some(ref retry_in) => { dict.insert(Key::from_str("retry in"), retry_in_to_bencode(retry_in)); },
+            None => ()
+        }
+        if compact {
+            let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+            for peer in self.peers.iter() {
+                match (peer.to_6byte(), peer.to_18byte()) {
+                    (Some(bytes), _) => peers.push_all(bytes),
+                    (_, Some(bytes)) => peers6.push_all(bytes),
+                    _ => ()
+                }
+            }
+            dict.insert(Key::from_str("peers"), ByteString(peers));
+            if !peers6.is_empty() {
+                dict.insert(Key::from_str("peers6"), ByteString(peers6));
+            }
+        } else {
+            dict.insert(Key::from_str("peers"), List(self.peers.iter().map(|peer| peer.to_bencode()).collect()));
+        }
+        Dict(dict)
+    }
+}
@@ -0,0 +1,53 @@
+//! How many connected peers have each piece of a torrent
+
+use bitfield::Bitfield;
+
+
+#[deriving(Clone, Show)]
+pub struct PieceAvailability {
+    counts: Vec<uint>,
+}
+
+impl PieceAvailability {
+    pub fn new(num_pieces: uint) -> PieceAvailability {
+        PieceAvailability { counts: Vec::from_elem(num_pieces, 0u) }
+    }
+
+    /// Number of connected peers having piece `index`
+    pub fn get(&self, index: uint) -> uint {
+        *self.counts.get(index)
+    }
+
+    pub fn len(&self) -> uint {
+        self.counts.len()
+    }
+
+    /// A peer announced piece `index` with `have`
+    pub fn add_piece(&mut self, index: uint) {
+        *self.counts.get_mut(index) += 1;
+    }
+
+    /// A peer sent `bitfield`, or `have_all` as a full one
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.ones().move_iter() {
+            *self.counts.get_mut(index) += 1;
+        }
+    }
+
+    /// A peer with `bitfield` disconnected. Counts stop at 0, so a bitfield
+    /// that was never added can't wrap them around.
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.ones().move_iter() {
+            let count = self.counts.get_mut(index);
+            if *count > 0 {
+                *count -= 1;
+            }
+        }
+    }
+
+    /// Lowest availability of any piece; pieces nobody has count as 0, so
+    /// this is the number of distributed copies of the whole torrent
+    pub fn distributed_copies(&self) -> uint {
+        self.counts.iter().map(|&count| count).min().unwrap_or(0)
+    }
+}
@@ -0,0 +1,85 @@
+//! Piece bitfields, packed the way the wire protocol sends them: the high
+//! bit of the first byte is piece 0, and spare bits at the end are zero.
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: uint,
+}
+
+impl Bitfield {
+    /// Bitfield of `len` pieces, none of them set
+    pub fn new(len: uint) -> Bitfield {
+        Bitfield { bits: Vec::from_elem((len + 7) / 8, 0u8), len: len }
+    }
+
+    /// Bitfield of `len` pieces, all of them set
+    pub fn full(len: uint) -> Bitfield {
+        let mut bitfield = Bitfield { bits: Vec::from_elem((len + 7) / 8, 0xffu8), len: len };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Bitfield of `len` pieces from wire format. `None` if `bytes` is the
+    /// wrong length or has spare bits set.
+    pub fn from_bytes(bytes: &[u8], len: uint) -> Option<Bitfield> {
+        if bytes.len() != (len + 7) / 8 {
+            return None;
+        }
+        let bitfield = Bitfield { bits: Vec::from_slice(bytes), len: len };
+        let mut spare_cleared = bitfield.clone();
+        spare_cleared.clear_spare_bits();
+        if spare_cleared == bitfield { Some(bitfield) } else { None }
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bits.len() * 8 - self.len;
+        if spare > 0 {
+            let last = self.bits.len() - 1;
+            *self.bits.get_mut(last) &= 0xffu8 << spare;
+        }
+    }
+
+    /// Number of pieces
+    pub fn len(&self) -> uint {
+        self.len
+    }
+
+    pub fn get(&self, index: uint) -> bool {
+        assert!(index < self.len);
+        *self.bits.get(index / 8) & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: uint) {
+        assert!(index < self.len);
+        *self.bits.get_mut(index / 8) |= 0x80 >> (index % 8);
+    }
+
+    pub fn clear(&mut self, index: uint) {
+        assert!(index < self.len);
+        *self.bits.get_mut(index / 8) &= !(0x80 >> (index % 8));
+    }
+
+    /// Number of pieces set
+    pub fn count(&self) -> uint {
+        self.bits.iter().map(|byte| byte.count_ones() as uint).fold(0, |a, b| a + b)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|byte| *byte == 0)
+    }
+
+    /// Indices of the pieces set
+    pub fn ones(&self) -> Vec<uint> {
+        range(0, self.len).filter(|&index| self.get(index)).collect()
+    }
+
+    /// Wire format
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+        self.bits.as_slice()
+    }
+}
@@ -0,0 +1,127 @@
+//! Deciding which peers to upload to: tit-for-tat with an optimistic
+//! unchoke.
+//!
+//! Every `RECHOKE_INTERVAL` the interested peers that give us the most are
+//! unchoked: the fastest uploaders to us while downloading, the fastest
+//! downloaders from us while seeding. One slot goes to a peer picked at
+//! random every `OPTIMISTIC_INTERVAL`, so new peers get a chance to prove
+//! themselves and we find better ones. Peers that stopped sending us data
+//! are snubbed and only get the optimistic slot.
+//!
+//! The choker works on `PeerStats` rather than connections, so it can be
+//! driven with made-up rates.
+
+use std::io::net::ip::SocketAddr;
+use std::rand::{Rng, task_rng};
+
+use connection::PeerConnection;
+
+
+pub static RECHOKE_INTERVAL: u64 = 10 * 1_000_000_000;
+pub static OPTIMISTIC_INTERVAL: u64 = 30 * 1_000_000_000;
+/// Peers connected for less than this are new, and three times as likely
+/// to get the optimistic unchoke
+static NEW_PEER_TIME: u64 = 60 * 1_000_000_000;
+/// Upload slots when nothing else is configured
+pub static DEFAULT_UPLOAD_SLOTS: uint = 4;
+
+/// What the choker needs to know about a peer
+#[deriving(Show, Clone)]
+pub struct PeerStats {
+    pub address: SocketAddr,
+    pub interested: bool,
+    /// Bytes per second the peer sends us
+    pub download_rate: f64,
+    /// Bytes per second we send the peer
+    pub upload_rate: f64,
+    pub connected_at: u64,
+    pub snubbed: bool,
+}
+
+impl PeerStats {
+    pub fn from_connection(connection: &PeerConnection) -> PeerStats {
+        PeerStats {
+            address: connection.address,
+            interested: connection.peer_interested,
+            download_rate: connection.download_rate.get(),
+            upload_rate: connection.upload_rate.get(),
+            connected_at: connection.connected_at,
+            snubbed: connection.snubbed,
+        }
+    }
+}
+
+pub struct Choker {
+    /// Peer holding the optimistic unchoke
+    optimistic: Option<SocketAddr>,
+    last_rechoke: Option<u64>,
+    last_optimistic: u64,
+}
+
+impl Choker {
+    pub fn new() -> Choker {
+        Choker { optimistic: None, last_rechoke: None, last_optimistic: 0 }
+    }
+
+    /// Whether it's time for `rechoke`
+    pub fn is_due(&self, now: u64) -> bool {
+        self.last_rechoke.map_or(true, |last| now - last >= RECHOKE_INTERVAL)
+    }
+
+    /// Make the next rechoke due right away
+    pub fn schedule(&mut self) {
+        self.last_rechoke = None;
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// The peers to unchoke out of `peers`, using `slots` upload slots; all
+    /// others should be choked
+    pub fn rechoke(&mut self, peers: &[PeerStats], slots: uint, seeding: bool, now: u64) -> Vec<SocketAddr> {
+        self.last_rechoke = Some(now);
+        if slots == 0 {
+            self.optimistic = None;
+            return Vec::new();
+        }
+
+        let rate = |peer: &PeerStats| if seeding { peer.upload_rate } else { peer.download_rate };
+        let mut candidates: Vec<&PeerStats> = peers.iter()
+            .filter(|peer| peer.interested && (seeding || !peer.snubbed))
+            .collect();
+        candidates.sort_by(|a, b| rate(*b).partial_cmp(&rate(*a)).unwrap_or(Equal));
+        let mut unchoked: Vec<SocketAddr> = candidates.iter().take(slots - 1).map(|peer| peer.address).collect();
+
+        let optimistic_valid = match self.optimistic {
+            Some(address) => peers.iter().any(|peer| peer.address == address && peer.interested) && !unchoked.contains(&address),
+            None => false
+        };
+        if !optimistic_valid || now - self.last_optimistic >= OPTIMISTIC_INTERVAL {
+            self.optimistic = pick_optimistic(peers, unchoked.as_slice(), now);
+            self.last_optimistic = now;
+        }
+        match self.optimistic {
+            Some(address) => unchoked.push(address),
+            // nobody left for the optimistic slot, give it to the next best
+            None => match candidates.iter().skip(slots - 1).next() {
+                Some(peer) => unchoked.push(peer.address),
+                None => ()
+            }
+        }
+        unchoked
+    }
+}
+
+/// A random interested peer that isn't unchoked already, new peers being
+/// three times as likely
+fn pick_optimistic(peers: &[PeerStats], unchoked: &[SocketAddr], now: u64) -> Option<SocketAddr> {
+    let mut weighted = Vec::new();
+    for peer in peers.iter().filter(|peer| peer.interested && !unchoked.contains(&peer.address)) {
+        let weight = if now - peer.connected_at < NEW_PEER_TIME { 3u } else { 1 };
+        for _ in range(0, weight) {
+            weighted.push(peer.address);
+        }
+    }
+    task_rng().choose(weighted.as_slice()).map(|address| *address)
+}
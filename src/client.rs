@@ -1,15 +1,32 @@
 use std::rand::random;
-use std::io;
-use std::io::{File, IoResult};
+use std::io::{File, UserRWX, IoResult};
+use std::io::fs;
+use std::io::net::ip::{IpAddr, SocketAddr};
 
-use torrent::{Torrent, TorrentInfo, Stopped, TrafficInfo, SessionInfo, SingleFile, MultiFile};
-use super::CLIENT_VERSION;
+use announce::Success;
+use handshake::{Handshake, Capabilities, HandshakeError, UnknownInfoHash, SelfConnection};
+use torrent::{Torrent, TorrentInfo, Checking};
+use super::{CLIENT_VERSION, DEFAULT_PORT};
+use storage::{Storage, StorageResult, StorageError, DiskStorage, DiskOptions, CachedStorage, PieceCache, io_result, write_result};
+use storage::cache::DEFAULT_CACHE_SIZE;
+use resume::ResumeData;
+use choker::DEFAULT_UPLOAD_SLOTS;
+use listener::{Listener, ListenPort};
+use dht;
 
 
 // XXX: other clients just use random bytes and not a valid utf8 string? need to investigate more
 pub struct Client {
     client_rand: String,
     torrents: Vec<Torrent>,
+    external_ip: Option<IpAddr>,
+    node_id: [u8, ..20],
+    /// Where fast-resume data is kept, if anywhere
+    resume_dir: Option<Path>,
+    upload_slots: uint,
+    listener: Option<Listener>,
+    /// Pieces read by peers, shared by every torrent
+    cache: PieceCache,
 }
 
 impl Client {
@@ -17,7 +34,13 @@ impl Client {
     pub fn new() -> Client {
         Client {
             torrents: Vec::new(),
-            client_rand: format!("{:06u}{:06u}", random::<uint>() % 1000000, random::<uint>() % 1000000)
+            client_rand: format!("{:06u}{:06u}", random::<uint>() % 1000000, random::<uint>() % 1000000),
+            external_ip: None,
+            node_id: dht::node_id(None),
+            resume_dir: None,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            listener: None,
+            cache: PieceCache::new(DEFAULT_CACHE_SIZE),
         }
     }
 
@@ -29,7 +52,13 @@ impl Client {
         assert!(client_rand.len() == 12)
         Client {
             torrents: Vec::new(),
-            client_rand: client_rand
+            client_rand: client_rand,
+            external_ip: None,
+            node_id: dht::node_id(None),
+            resume_dir: None,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            listener: None,
+            cache: PieceCache::new(DEFAULT_CACHE_SIZE),
         }
     }
 
@@ -39,44 +68,244 @@ impl Client {
         format!("-TE{:04u}-{:s}", CLIENT_VERSION, self.client_rand)
     }
 
+    /// Protocol extensions we advertise in handshakes, only the ones we
+    /// speak
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities { extensions: true, dht: false, fast: false }
+    }
+
+    /// The handshake we send to peers of `torrent`
+    pub fn handshake_for(&self, torrent: &Torrent) -> Handshake {
+        Handshake::new(torrent.info.infohash.as_slice(), self.peer_id().as_bytes(), &self.capabilities())
+    }
+
+    /// Check a handshake received from a peer and find the torrent the
+    /// connection belongs to
+    pub fn accept_handshake<'a>(&'a mut self, handshake: &Handshake) -> Result<&'a mut Torrent, HandshakeError> {
+        if handshake.peer_id.as_slice() == self.peer_id().as_bytes() {
+            return Err(SelfConnection);
+        }
+        match self.find_torrent(handshake.info_hash.as_slice()) {
+            Some(torrent) => Ok(torrent),
+            None => Err(UnknownInfoHash)
+        }
+    }
+
+    pub fn find_torrent<'a>(&'a mut self, info_hash: &[u8]) -> Option<&'a mut Torrent> {
+        self.torrents.mut_iter().find(|torrent| torrent.info.infohash.as_slice() == info_hash)
+    }
+
+    /// Our address as reported by trackers (BEP 24), if any of them did
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// Record our external address. The DHT node ID is derived from it
+    /// (BEP 42), so it's regenerated whenever the address changes.
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        if self.external_ip != Some(ip) {
+            self.external_ip = Some(ip);
+            self.node_id = dht::node_id(Some(ip));
+        }
+    }
+
+    pub fn dht_node_id(&self) -> [u8, ..20] {
+        self.node_id
+    }
+
+    /// Whether `address` is where other peers reach us, so connecting to
+    /// it would be connecting to ourselves
+    pub fn is_own_address(&self, address: &SocketAddr) -> bool {
+        self.external_ip == Some(address.ip) && address.port == self.listen_port()
+    }
+
+    /// Start accepting peer connections on the port chosen by `port`, and
+    /// return the port bound. That port is the one announced to trackers
+    /// from then on.
+    pub fn listen(&mut self, port: ListenPort) -> IoResult<u16> {
+        let listener = try!(Listener::bind("0.0.0.0", port));
+        let port = listener.port();
+        self.listener = Some(listener);
+        for torrent in self.torrents.mut_iter() {
+            torrent.listen_port = port;
+        }
+        Ok(port)
+    }
+
+    /// The port peers reach us on, to be used in announces, DHT and local
+    /// service discovery. `DEFAULT_PORT` until `listen` is called.
+    pub fn listen_port(&self) -> u16 {
+        self.listener.as_ref().map_or(DEFAULT_PORT, |listener| listener.port())
+    }
+
+    /// The listen socket, `None` until `listen` is called. Connections to
+    /// it are accepted by the `Engine`.
+    pub fn listener<'a>(&'a mut self) -> Option<&'a mut Listener> {
+        self.listener.as_mut()
+    }
+
+    /// Announce every torrent that has a tracker due. The external address
+    /// reported by the trackers is recorded, and our own address is dropped
+    /// from the peers they returned.
+    pub fn announce_torrents(&mut self) {
+        let peer_id = self.peer_id();
+        let mut external_ip = None;
+        for torrent in self.torrents.mut_iter() {
+            torrent.verify_tex_trackers(peer_id.clone());
+            match torrent.announce(peer_id.clone()) {
+                Some(Success(ref result)) if result.external_ip.is_some() => external_ip = result.external_ip,
+                _ => ()
+            }
+        }
+        match external_ip {
+            Some(ip) => self.set_external_ip(ip),
+            None => ()
+        }
+        for index in range(0, self.torrents.len()) {
+            let own: Vec<SocketAddr> = self.torrents.get(index).session.peers.iter()
+                .map(|peer| peer.address)
+                .filter(|address| self.is_own_address(address))
+                .collect();
+            self.torrents.get_mut(index).session.peers.retain(|peer| !own.contains(&peer.address));
+        }
+    }
+
     /// Add a torrent based on information found in `info`
-    /// It'll be set to `Stopped` state by default
+    /// It'll be set to `Stopped` state by default, or `Checking` when some
+    /// of its data exists already
     /// It is assumed that `destination_path` is a valid path and a directory
     /// with read/write permissions.
     ///
+    /// The data is stored in the torrent's files with `DiskStorage`, which
+    /// are created with the default `DiskOptions`: sparse, and keeping any
+    /// existing content. See `add_torrent_with_options` to choose otherwise
+    /// and `add_torrent_with_storage` to store the data elsewhere.
+    pub fn add_torrent<'a>(&'a mut self, info: &TorrentInfo, destination_path: Path) -> StorageResult<&'a mut Torrent> {
+        self.add_torrent_with_options(info, destination_path, &DiskOptions::new())
+    }
+
+    /// Add a torrent stored in files under `destination_path`, created as
+    /// `options` say. Fails without adding anything when there isn't
+    /// enough free space for the torrent.
+    ///
     /// This method will automatically create the subtree for multifile
     /// torrents
-    ///
-    /// Note that this also means that it'll overwrite any existing content
-    /// under the same path with the same name
-    pub fn add_torrent<'a>(&'a mut self, info: &TorrentInfo, destination_path: Path) -> IoResult<&'a Torrent> {
-        match info.metainfo.payload {
-            MultiFile(ref files) => {
-                for file in files.iter() {
-                    let path = match file.path {
-                        Some(ref path) => path,
-                        None => continue
-                    };
-                    let fullpath = destination_path.join_many(path.as_slice());
-                    try!(io::fs::mkdir_recursive(&fullpath.dir_path(), io::UserRWX));
-                    try!(File::create(&fullpath));
-                }
-            }
-            SingleFile(_) => {
-                let file_destination = destination_path.join(info.metainfo.name.clone());
-                try!(File::create(&file_destination));
-            }
+    pub fn add_torrent_with_options<'a>(&'a mut self, info: &TorrentInfo, destination_path: Path, options: &DiskOptions) -> StorageResult<&'a mut Torrent> {
+        let mut storage = DiskStorage::new(info, destination_path);
+        // allocating creates the files, so look for existing data first
+        let check = storage.exists() && !options.overwrite;
+        try!(storage.allocate(options));
+        Ok(self.push_torrent(info, box storage as Box<Storage>, check))
+    }
+
+    /// Add a torrent whose data is kept in `storage`, set to `Stopped`.
+    /// If `storage` has data already, the torrent is `Checking` it instead,
+    /// see `check_torrents`.
+    pub fn add_torrent_with_storage<'a>(&'a mut self, info: &TorrentInfo, storage: Box<Storage>) -> &'a mut Torrent {
+        let check = storage.exists();
+        self.push_torrent(info, storage, check)
+    }
+
+    fn push_torrent<'a>(&'a mut self, info: &TorrentInfo, storage: Box<Storage>, check: bool) -> &'a mut Torrent {
+        let storage = box CachedStorage::new(info, storage, &self.cache) as Box<Storage>;
+        let mut torrent = Torrent::new(info.clone(), storage);
+        torrent.listen_port = self.listen_port();
+        let resumed = match self.load_resume_data(info) {
+            Some(resume) => torrent.apply_resume(&resume),
+            None => false
+        };
+        // resume data that doesn't match the files starts a check already
+        if !resumed && check && torrent.status != Checking {
+            torrent.start_check();
         }
-        self.torrents.push(Torrent {
-            info: info.clone(),
-            status: Stopped,
-            destination_path: destination_path,
-            traffic: TrafficInfo { downloaded_bytes: 0, uploaded_bytes: 0 },
-            session: SessionInfo { peers: Vec::new() }
-        });
+        self.torrents.push(torrent);
         // unwrap because either the call to push fails or it's safe to call it
         // last although i'd prefer if push returned a reference to it
-        Ok(self.torrents.last().unwrap())
+        self.torrents.mut_last().unwrap()
+    }
+
+    /// Peers each torrent uploads to at once, unless the torrent sets its
+    /// own `upload_slots`
+    pub fn upload_slots(&self) -> uint {
+        self.upload_slots
+    }
+
+    pub fn set_upload_slots(&mut self, slots: uint) {
+        self.upload_slots = slots;
+    }
+
+    /// Cache up to `bytes` of the pieces peers read, over all torrents,
+    /// see `CachedStorage`. 0 turns the cache off.
+    pub fn set_cache_size(&mut self, bytes: uint) {
+        self.cache.set_capacity(bytes);
+    }
+
+    /// The cache of the pieces peers read
+    pub fn cache<'a>(&'a self) -> &'a PieceCache {
+        &self.cache
+    }
+
+    /// Keep fast-resume data in `dir`, one `<infohash>.resume` file per
+    /// torrent, see `resume`. Torrents added afterwards are resumed from
+    /// it instead of being checked.
+    pub fn set_resume_dir(&mut self, dir: Path) {
+        self.resume_dir = Some(dir);
+    }
+
+    fn resume_path(&self, info: &TorrentInfo) -> Option<Path> {
+        self.resume_dir.as_ref().map(|dir| dir.join(format!("{}.resume", info.hash_string())))
+    }
+
+    fn load_resume_data(&self, info: &TorrentInfo) -> Option<ResumeData> {
+        let path = match self.resume_path(info) {
+            Some(ref path) if path.exists() => path.clone(),
+            _ => return None
+        };
+        File::open(&path).read_to_end().ok().and_then(|data| ResumeData::decode(data.as_slice()))
+    }
+
+    /// Write the fast-resume data of every torrent to the resume directory
+    pub fn save_resume_data(&mut self) -> StorageResult<()> {
+        let dir = match self.resume_dir {
+            Some(ref dir) => dir.clone(),
+            None => return Ok(())
+        };
+        try!(io_result(fs::mkdir_recursive(&dir, UserRWX), &dir));
+        for index in range(0, self.torrents.len()) {
+            let path = self.resume_path(&self.torrents.get(index).info).unwrap();
+            let resume = try!(self.torrents.get_mut(index).resume_data());
+            // write next to the old data and swap, so a crash never leaves a
+            // torn file behind
+            let temporary = path.with_extension("resume.tmp");
+            try!(write_result(File::create(&temporary).write(resume.encode().as_slice()), &temporary));
+            try!(io_result(fs::rename(&temporary, &path), &path));
+        }
+        Ok(())
+    }
+
+    /// Hash the next piece of every torrent that's `Checking`, so all of
+    /// the checks make progress together and other torrents can be served
+    /// in between calls. Returns the infohashes and errors of the checks
+    /// that failed; those torrents are `Stopped`.
+    pub fn check_torrents(&mut self) -> Vec<(Vec<u8>, StorageError)> {
+        let mut failed = Vec::new();
+        for torrent in self.torrents.mut_iter() {
+            match torrent.check_step() {
+                Ok(_) => (),
+                Err(e) => failed.push((torrent.info.infohash.clone(), e))
+            }
+        }
+        failed
+    }
+
+    /// Whether any torrent is still `Checking`
+    pub fn is_checking(&self) -> bool {
+        self.torrents.iter().any(|torrent| torrent.status == Checking)
+    }
+
+    /// The torrents managed by this client
+    pub fn torrents<'a>(&'a self) -> &'a [Torrent] {
+        self.torrents.as_slice()
     }
 
     /// Get the list of torrents managed by this client
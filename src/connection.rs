@@ -0,0 +1,416 @@
+//! State of a single peer connection
+//!
+//! `PeerConnection` doesn't own a socket. Messages read from the peer are
+//! passed to `receive`, messages for the peer are queued with `send` and
+//! taken out with `take_outgoing`, and `tick` is called periodically to
+//! keep the connection alive. Every method takes the current time from
+//! `time::precise_time_ns` as `now`, so the state machine can be driven
+//! without a clock.
+
+use std::collections::{RingBuf, Deque};
+use std::collections::hashmap::HashMap;
+use std::io::net::ip::SocketAddr;
+
+use bitfield::Bitfield;
+use download::BLOCK_SIZE;
+use extension::{ExtendedHandshake, HANDSHAKE_ID};
+use rate::TransferRate;
+use wire::{Message, KeepAlive, Choke, Unchoke, Interested, NotInterested, Have, Request, Piece, Cancel, Port, Extended, HaveAll, HaveNone, RejectRequest};
+use wire;
+
+
+/// Send a keep-alive when we've been silent for this long
+pub static KEEPALIVE_INTERVAL: u64 = 90 * 1_000_000_000;
+/// Drop peers that have been silent for this long
+pub static PEER_TIMEOUT: u64 = 180 * 1_000_000_000;
+/// Requests the peer sent in flight when we choked it are discarded rather
+/// than treated as a violation for this long
+static CHOKE_GRACE: u64 = 10 * 1_000_000_000;
+/// Cancelled requests remembered so late blocks aren't taken for violations
+static MAX_CANCELLED: uint = 64;
+/// Most unserved requests a peer may have queued with us
+pub static MAX_PEER_REQUESTS: uint = 250;
+/// Largest block a peer may request
+pub static MAX_REQUEST_LENGTH: u32 = 1 << 17;
+/// Requests unanswered for this long are given up on, so the block can be
+/// requested from someone else
+pub static REQUEST_TIMEOUT: u64 = 30 * 1_000_000_000;
+/// We keep enough requests queued with a peer to cover this much time at
+/// its current rate, so the pipe never runs dry while a request travels
+static REQUEST_QUEUE_TIME: f64 = 3.0;
+/// A peer that sent nothing for this long while we wait for blocks is
+/// snubbing us
+pub static SNUB_TIMEOUT: u64 = 60 * 1_000_000_000;
+pub static MIN_REQUEST_DEPTH: uint = 4;
+pub static MAX_REQUEST_DEPTH: uint = 128;
+
+#[deriving(Show, Clone, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// Ways a peer can break the protocol, each of them a reason to drop it
+#[deriving(Show, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Nothing was received for `PEER_TIMEOUT`
+    Timeout,
+    /// A bitfield, `have_all` or `have_none` that wasn't the first message
+    /// after the handshake
+    BitfieldNotFirst,
+    /// A fast extension message without both sides supporting it
+    FastNotNegotiated,
+    /// An extension protocol message without both sides supporting it
+    ExtensionsNotNegotiated,
+    /// A bitfield of the wrong length, or with spare bits set
+    InvalidBitfield,
+    /// A piece index beyond the end of the torrent
+    InvalidPieceIndex(u32),
+    /// A request for a block that isn't in the torrent, is too large, or
+    /// is of a piece we don't have
+    InvalidRequest(BlockRequest),
+    /// A request while we're choking the peer
+    RequestWhileChoked,
+    TooManyRequests,
+    /// A block we didn't request, or already cancelled long ago
+    UnrequestedPiece(BlockRequest),
+    /// Our side tried to request while the peer chokes us
+    RequestWhileChoking,
+}
+
+pub struct PeerConnection {
+    pub address: SocketAddr,
+    pub peer_id: Option<[u8, ..20]>,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Pieces the peer has, `None` until it tells us
+    pub bitfield: Option<Bitfield>,
+    /// Whether both sides support the fast extension (BEP 6)
+    pub fast: bool,
+    /// Whether both sides support the extension protocol (BEP 10)
+    pub extensions: bool,
+    /// The peer's extended handshake, once it sent one
+    pub extended: Option<ExtendedHandshake>,
+    /// When we last sent the peer an `lt_tex` message
+    pub tex_sent_at: Option<u64>,
+    num_pieces: uint,
+    /// Requests we sent that haven't been answered yet
+    pub our_requests: Vec<BlockRequest>,
+    /// When each of `our_requests` was sent
+    requested_at: HashMap<BlockRequest, u64>,
+    /// Requests the peer sent that we haven't served yet, oldest first
+    pub peer_requests: RingBuf<BlockRequest>,
+    /// Requests we cancelled; the block may still arrive
+    cancelled: Vec<BlockRequest>,
+    pub last_received: u64,
+    pub last_sent: u64,
+    pub connected_at: u64,
+    /// When the peer last sent a block, or we started waiting for one
+    last_piece: u64,
+    /// Whether the peer leaves our requests unanswered, see `SNUB_TIMEOUT`
+    pub snubbed: bool,
+    /// Payload bytes received from the peer
+    pub download_rate: TransferRate,
+    /// Payload bytes sent to the peer
+    pub upload_rate: TransferRate,
+    choked_at: Option<u64>,
+    /// A bitfield is only allowed before any other state message
+    bitfield_allowed: bool,
+    outgoing: RingBuf<Message>,
+}
+
+impl PeerConnection {
+    /// State of a freshly handshaken connection: both sides choking and not
+    /// interested
+    pub fn new(address: SocketAddr, num_pieces: uint, now: u64) -> PeerConnection {
+        PeerConnection {
+            address: address,
+            peer_id: None,
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: None,
+            fast: false,
+            extensions: false,
+            extended: None,
+            tex_sent_at: None,
+            num_pieces: num_pieces,
+            our_requests: Vec::new(),
+            requested_at: HashMap::new(),
+            peer_requests: RingBuf::new(),
+            cancelled: Vec::new(),
+            last_received: now,
+            last_sent: now,
+            connected_at: now,
+            last_piece: now,
+            snubbed: false,
+            download_rate: TransferRate::new(now),
+            upload_rate: TransferRate::new(now),
+            choked_at: None,
+            bitfield_allowed: true,
+            outgoing: RingBuf::new(),
+        }
+    }
+
+    fn remember_cancelled(&mut self, request: BlockRequest) {
+        if self.cancelled.len() >= MAX_CANCELLED {
+            self.cancelled.remove(0);
+        }
+        self.cancelled.push(request);
+    }
+
+    /// Take `request` out of `our_requests`, returning whether it was there
+    fn forget_request(&mut self, request: &BlockRequest) -> bool {
+        self.requested_at.remove(request);
+        match self.our_requests.iter().position(|r| r == request) {
+            Some(index) => { self.our_requests.remove(index); true },
+            None => false
+        }
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), ProtocolError> {
+        if (index as uint) < self.num_pieces { Ok(()) } else { Err(InvalidPieceIndex(index)) }
+    }
+
+    /// Handle a message from the peer. An error means the peer broke the
+    /// protocol and should be disconnected.
+    pub fn receive(&mut self, message: &Message, now: u64) -> Result<(), ProtocolError> {
+        self.last_received = now;
+        let bitfield_allowed = self.bitfield_allowed;
+        match *message {
+            KeepAlive | Port(_) | Extended(..) => (),
+            _ => self.bitfield_allowed = false
+        }
+        match *message {
+            KeepAlive | Port(_) => (),
+            Extended(id, ref payload) => {
+                if !self.extensions {
+                    return Err(ExtensionsNotNegotiated);
+                }
+                // a malformed handshake leaves us thinking the peer speaks
+                // no extensions, which is harmless
+                if id == HANDSHAKE_ID {
+                    self.extended = ExtendedHandshake::decode(payload.as_slice());
+                }
+            },
+            Choke => {
+                self.peer_choking = true;
+                // the peer discards our requests when it chokes us, though
+                // some blocks may already be on their way
+                let discarded = ::std::mem::replace(&mut self.our_requests, Vec::new());
+                self.requested_at.clear();
+                for request in discarded.move_iter() {
+                    self.remember_cancelled(request);
+                }
+            },
+            Unchoke => self.peer_choking = false,
+            Interested => self.peer_interested = true,
+            NotInterested => self.peer_interested = false,
+            Have(index) => {
+                try!(self.check_index(index));
+                if self.bitfield.is_none() {
+                    self.bitfield = Some(Bitfield::new(self.num_pieces));
+                }
+                self.bitfield.as_mut().unwrap().set(index as uint);
+            },
+            wire::Bitfield(ref bits) => {
+                if !bitfield_allowed {
+                    return Err(BitfieldNotFirst);
+                }
+                match Bitfield::from_bytes(bits.as_slice(), self.num_pieces) {
+                    Some(bitfield) => self.bitfield = Some(bitfield),
+                    None => return Err(InvalidBitfield)
+                }
+            },
+            HaveAll | HaveNone => {
+                if !self.fast {
+                    return Err(FastNotNegotiated);
+                }
+                if !bitfield_allowed {
+                    return Err(BitfieldNotFirst);
+                }
+                self.bitfield = Some(match *message {
+                    HaveAll => Bitfield::full(self.num_pieces),
+                    _ => Bitfield::new(self.num_pieces)
+                });
+            },
+            Request(piece, begin, length) => {
+                let request = BlockRequest { piece: piece, begin: begin, length: length };
+                try!(self.check_index(piece));
+                if length == 0 || length > MAX_REQUEST_LENGTH {
+                    return Err(InvalidRequest(request));
+                }
+                if self.am_choking {
+                    return match self.choked_at {
+                        Some(choked_at) if now - choked_at < CHOKE_GRACE => Ok(()),
+                        _ => Err(RequestWhileChoked)
+                    };
+                }
+                if self.peer_requests.len() >= MAX_PEER_REQUESTS {
+                    return Err(TooManyRequests);
+                }
+                if !self.peer_requests.iter().any(|r| *r == request) {
+                    self.peer_requests.push_back(request);
+                }
+            },
+            Cancel(piece, begin, length) => {
+                let request = BlockRequest { piece: piece, begin: begin, length: length };
+                let remaining: RingBuf<BlockRequest> = self.peer_requests.iter().filter(|r| **r != request).map(|r| r.clone()).collect();
+                self.peer_requests = remaining;
+            },
+            RejectRequest(piece, begin, length) => {
+                if !self.fast {
+                    return Err(FastNotNegotiated);
+                }
+                self.forget_request(&BlockRequest { piece: piece, begin: begin, length: length });
+            },
+            Piece(piece, begin, ref data) => {
+                let request = BlockRequest { piece: piece, begin: begin, length: data.len() as u32 };
+                if !self.forget_request(&request) {
+                    match self.cancelled.iter().position(|r| *r == request) {
+                        Some(index) => { self.cancelled.remove(index); },
+                        None => return Err(UnrequestedPiece(request))
+                    }
+                }
+                self.download_rate.add(data.len());
+                self.last_piece = now;
+                self.snubbed = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a message for the peer, updating our side of the state. Fails
+    /// only for messages the protocol doesn't allow us to send right now.
+    pub fn send(&mut self, message: Message, now: u64) -> Result<(), ProtocolError> {
+        match message {
+            Choke => {
+                if self.am_choking { return Ok(()); }
+                self.am_choking = true;
+                self.choked_at = Some(now);
+                // choking discards everything the peer asked for
+                self.peer_requests.clear();
+            },
+            Unchoke => {
+                if !self.am_choking { return Ok(()); }
+                self.am_choking = false;
+                self.choked_at = None;
+            },
+            Interested => {
+                if self.am_interested { return Ok(()); }
+                self.am_interested = true;
+            },
+            NotInterested => {
+                if !self.am_interested { return Ok(()); }
+                self.am_interested = false;
+            },
+            Request(piece, begin, length) => {
+                if self.peer_choking {
+                    return Err(RequestWhileChoking);
+                }
+                let request = BlockRequest { piece: piece, begin: begin, length: length };
+                if self.our_requests.is_empty() {
+                    self.last_piece = now;
+                }
+                self.requested_at.insert(request.clone(), now);
+                self.our_requests.push(request);
+            },
+            Cancel(piece, begin, length) => {
+                let request = BlockRequest { piece: piece, begin: begin, length: length };
+                if !self.forget_request(&request) {
+                    return Ok(());
+                }
+                self.remember_cancelled(request);
+            },
+            Piece(piece, begin, ref data) => {
+                let request = BlockRequest { piece: piece, begin: begin, length: data.len() as u32 };
+                let remaining: RingBuf<BlockRequest> = self.peer_requests.iter().filter(|r| **r != request).map(|r| r.clone()).collect();
+                self.peer_requests = remaining;
+                self.upload_rate.add(data.len());
+            },
+            RejectRequest(piece, begin, length) => {
+                if !self.fast {
+                    return Err(FastNotNegotiated);
+                }
+                let request = BlockRequest { piece: piece, begin: begin, length: length };
+                let remaining: RingBuf<BlockRequest> = self.peer_requests.iter().filter(|r| **r != request).map(|r| r.clone()).collect();
+                self.peer_requests = remaining;
+            },
+            _ => ()
+        }
+        self.last_sent = now;
+        self.outgoing.push_back(message);
+        Ok(())
+    }
+
+    /// Messages queued for the peer, oldest first
+    pub fn take_outgoing(&mut self) -> Vec<Message> {
+        let mut messages = Vec::with_capacity(self.outgoing.len());
+        loop {
+            match self.outgoing.pop_front() {
+                Some(message) => messages.push(message),
+                None => return messages
+            }
+        }
+    }
+
+    /// Periodic housekeeping: queue a keep-alive when we've been quiet, and
+    /// fail with `Timeout` when the peer has been
+    pub fn tick(&mut self, now: u64) -> Result<(), ProtocolError> {
+        if now - self.last_received >= PEER_TIMEOUT {
+            return Err(Timeout);
+        }
+        if now - self.last_sent >= KEEPALIVE_INTERVAL {
+            self.send(KeepAlive, now).unwrap();
+        }
+        if !self.our_requests.is_empty() && now - self.last_piece >= SNUB_TIMEOUT {
+            self.snubbed = true;
+        }
+        self.download_rate.update(now);
+        self.upload_rate.update(now);
+        Ok(())
+    }
+
+    /// Cancel the requests that went unanswered for `REQUEST_TIMEOUT` and
+    /// return them, so they can be requested from other peers. Should the
+    /// block arrive after all, it's still accepted.
+    pub fn expire_requests(&mut self, now: u64) -> Vec<BlockRequest> {
+        let expired: Vec<BlockRequest> = self.our_requests.iter()
+            .filter(|request| now - *self.requested_at.find(*request).unwrap() >= REQUEST_TIMEOUT)
+            .map(|request| request.clone())
+            .collect();
+        for request in expired.iter() {
+            self.send(Cancel(request.piece, request.begin, request.length), now).unwrap();
+        }
+        expired
+    }
+
+    /// How many requests to keep queued with the peer. It follows the rate
+    /// the peer delivers at, so fast peers are kept busy and slow ones don't
+    /// hoard blocks others could deliver sooner. Snubbing peers get a
+    /// single request.
+    pub fn request_depth(&self) -> uint {
+        if self.snubbed {
+            return 1;
+        }
+        let depth = (self.download_rate.get() * REQUEST_QUEUE_TIME / BLOCK_SIZE as f64) as uint;
+        depth.max(MIN_REQUEST_DEPTH).min(MAX_REQUEST_DEPTH)
+    }
+
+    /// Whether the peer has piece `index`, as far as we know
+    pub fn has_piece(&self, index: uint) -> bool {
+        match self.bitfield {
+            Some(ref bitfield) if index < self.num_pieces => bitfield.get(index),
+            _ => false
+        }
+    }
+
+    /// Whether we may send requests to the peer
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
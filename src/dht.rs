@@ -0,0 +1,77 @@
+//! DHT node ID generation and verification based on the external address
+//! (BEP 42)
+
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rand::{Rng, task_rng};
+
+
+static V4_MASK: [u8, ..4] = [0x03, 0x0f, 0x3f, 0xff];
+static V6_MASK: [u8, ..8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// CRC32-C (Castagnoli), bitwise; node IDs are generated rarely enough that
+/// a table isn't worth it
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in bytes.iter() {
+        crc ^= byte as u32;
+        for _ in range(0u, 8) {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The masked address bytes BEP 42 hashes for `ip`, with `r` mixed in
+fn masked_ip(ip: IpAddr, r: u8) -> Vec<u8> {
+    let mut bytes: Vec<u8> = match ip {
+        Ipv4Addr(a, b, c, d) => {
+            [a, b, c, d].iter().zip(V4_MASK.iter()).map(|(&byte, &mask)| byte & mask).collect()
+        },
+        Ipv6Addr(a, b, c, d, _, _, _, _) => {
+            let mut prefix = Vec::new();
+            for &dbyte in [a, b, c, d].iter() {
+                prefix.push((dbyte >> 8) as u8);
+                prefix.push(dbyte as u8);
+            }
+            prefix.iter().zip(V6_MASK.iter()).map(|(&byte, &mask)| byte & mask).collect()
+        }
+    };
+    *bytes.get_mut(0) |= (r & 0x7) << 5;
+    bytes
+}
+
+/// Node ID for a node reachable at `ip`, or an entirely random one if we
+/// don't know our external address yet
+pub fn node_id(ip: Option<IpAddr>) -> [u8, ..20] {
+    let mut rng = task_rng();
+    let mut id = [0u8, ..20];
+    rng.fill_bytes(id);
+    match ip {
+        Some(ip) => {
+            // only the low bits of r go into the hash, all of it into the ID
+            let r = rng.gen::<u8>();
+            let crc = crc32c(masked_ip(ip, r).as_slice());
+            id[0] = (crc >> 24) as u8;
+            id[1] = (crc >> 16) as u8;
+            id[2] = ((crc >> 8) as u8 & 0xf8) | (rng.gen::<u8>() & 0x7);
+            id[19] = r;
+        },
+        None => ()
+    }
+    id
+}
+
+/// Whether `id` is a valid node ID for a node at `ip`. Local addresses are
+/// exempt, as BEP 42 asks.
+pub fn is_valid_node_id(id: &[u8, ..20], ip: IpAddr) -> bool {
+    match ip {
+        Ipv4Addr(10, _, _, _) | Ipv4Addr(127, _, _, _) | Ipv4Addr(192, 168, _, _) => return true,
+        Ipv4Addr(172, b, _, _) if b >= 16 && b < 32 => return true,
+        Ipv4Addr(169, 254, _, _) => return true,
+        _ => ()
+    }
+    let crc = crc32c(masked_ip(ip, id[19]).as_slice());
+    id[0] == (crc >> 24) as u8 &&
+        id[1] == (crc >> 16) as u8 &&
+        id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
@@ -0,0 +1,190 @@
+//! Block-level state of the pieces being downloaded
+
+use std::io::net::ip::SocketAddr;
+
+use connection::BlockRequest;
+
+
+/// Size of the blocks pieces are requested in. The last block of the last
+/// piece may be shorter.
+pub static BLOCK_SIZE: uint = 16384;
+
+#[deriving(Show, Clone, PartialEq)]
+enum BlockState {
+    Open,
+    /// Requested from these peers; more than one only in end-game mode
+    Requested(Vec<SocketAddr>),
+    /// Received from this peer; `None` for blocks restored from resume data
+    Received(Option<SocketAddr>),
+}
+
+/// Outcome of a block arriving
+#[deriving(Show)]
+pub struct BlockReceived {
+    /// The block had already arrived from another peer
+    pub duplicate: bool,
+    /// Other peers the block was requested from, which should be sent a
+    /// `cancel`
+    pub cancel: Vec<SocketAddr>,
+    /// Every block of the piece has arrived
+    pub piece_complete: bool,
+}
+
+pub struct PieceDownload {
+    pub index: uint,
+    blocks: Vec<BlockRequest>,
+    states: Vec<BlockState>,
+    /// Received blocks that made it to storage
+    written: Vec<bool>,
+}
+
+impl PieceDownload {
+    pub fn new(index: uint, blocks: Vec<BlockRequest>) -> PieceDownload {
+        let states = Vec::from_elem(blocks.len(), Open);
+        let written = Vec::from_elem(blocks.len(), false);
+        PieceDownload { index: index, blocks: blocks, states: states, written: written }
+    }
+
+    /// A download whose blocks with `received` set are already stored, as
+    /// recorded in resume data
+    pub fn restore(index: uint, blocks: Vec<BlockRequest>, received: &[bool]) -> PieceDownload {
+        let mut download = PieceDownload::new(index, blocks);
+        for ((state, written), &received) in download.states.mut_iter().zip(download.written.mut_iter()).zip(received.iter()) {
+            if received {
+                *state = Received(None);
+                *written = true;
+            }
+        }
+        download
+    }
+
+    /// Which blocks have been received and written to storage
+    pub fn written_blocks(&self) -> Vec<bool> {
+        self.written.clone()
+    }
+
+    /// `length` bytes at `begin` of the piece were written to storage.
+    /// Only blocks received since the last `reset` count, a write queued
+    /// before it may still come back.
+    pub fn blocks_written(&mut self, begin: uint, length: uint) {
+        for (block, (state, written)) in self.blocks.iter().zip(self.states.iter().zip(self.written.mut_iter())) {
+            let (start, end) = (block.begin as uint, block.begin as uint + block.length as uint);
+            let received = match *state { Received(_) => true, _ => false };
+            if received && start >= begin && end <= begin + length {
+                *written = true;
+            }
+        }
+    }
+
+    fn position(&self, request: &BlockRequest) -> Option<uint> {
+        self.blocks.iter().position(|block| block == request)
+    }
+
+    /// Next block to request from `peer`. Only blocks nobody was asked for
+    /// are returned, unless `endgame` is set, in which case blocks that are
+    /// requested from other peers but not yet received are fair game too.
+    pub fn pick_block(&self, peer: &SocketAddr, endgame: bool) -> Option<BlockRequest> {
+        let open = self.states.iter().position(|state| *state == Open);
+        let position = match open {
+            Some(position) => Some(position),
+            None if endgame => self.states.iter().position(|state| match *state {
+                Requested(ref peers) => !peers.contains(peer),
+                _ => false
+            }),
+            None => None
+        };
+        position.map(|position| self.blocks.get(position).clone())
+    }
+
+    pub fn mark_requested(&mut self, request: &BlockRequest, peer: SocketAddr) {
+        let position = match self.position(request) {
+            Some(position) => position,
+            None => return
+        };
+        let state = self.states.get_mut(position);
+        match *state {
+            Open => *state = Requested(vec![peer]),
+            Requested(ref mut peers) => if !peers.contains(&peer) { peers.push(peer) },
+            Received(_) => ()
+        }
+    }
+
+    /// The request for a block from `peer` won't be answered, because the
+    /// peer choked us, disconnected or took too long
+    pub fn request_failed(&mut self, request: &BlockRequest, peer: &SocketAddr) {
+        let position = match self.position(request) {
+            Some(position) => position,
+            None => return
+        };
+        let state = self.states.get_mut(position);
+        let reopen = match *state {
+            Requested(ref mut peers) => {
+                peers.retain(|p| p != peer);
+                peers.is_empty()
+            },
+            _ => false
+        };
+        if reopen {
+            *state = Open;
+        }
+    }
+
+    /// Record a block arriving from `peer`. `None` if it isn't a block of
+    /// this piece at all.
+    pub fn block_received(&mut self, request: &BlockRequest, peer: &SocketAddr) -> Option<BlockReceived> {
+        let position = match self.position(request) {
+            Some(position) => position,
+            None => return None
+        };
+        let (duplicate, cancel) = match *self.states.get(position) {
+            Received(_) => return Some(BlockReceived { duplicate: true, cancel: Vec::new(), piece_complete: self.is_complete() }),
+            Requested(ref peers) => (false, peers.iter().filter(|p| *p != peer).map(|p| *p).collect()),
+            Open => (false, Vec::new())
+        };
+        *self.states.get_mut(position) = Received(Some(*peer));
+        Some(BlockReceived {
+            duplicate: duplicate,
+            cancel: cancel,
+            piece_complete: self.is_complete(),
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.states.iter().all(|state| match *state { Received(_) => true, _ => false })
+    }
+
+    /// The blocks received so far and the peer each came from
+    pub fn senders(&self) -> Vec<(BlockRequest, SocketAddr)> {
+        self.blocks.iter().zip(self.states.iter()).filter_map(|(block, state)| match *state {
+            Received(Some(peer)) => Some((block.clone(), peer)),
+            _ => None
+        }).collect()
+    }
+
+    /// Peers that delivered blocks of this piece, each once
+    pub fn contributors(&self) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for state in self.states.iter() {
+            match *state {
+                Received(Some(peer)) if !peers.contains(&peer) => peers.push(peer),
+                _ => ()
+            }
+        }
+        peers
+    }
+
+    /// Start over, after the piece failed verification
+    pub fn reset(&mut self) {
+        for state in self.states.mut_iter() {
+            *state = Open;
+        }
+        for written in self.written.mut_iter() {
+            *written = false;
+        }
+    }
+
+    /// Whether no block is left that nobody was asked for
+    pub fn is_fully_requested(&self) -> bool {
+        self.states.iter().all(|state| *state != Open)
+    }
+}
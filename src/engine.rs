@@ -0,0 +1,987 @@
+//! Running the peer connections of a client's torrents.
+//!
+//! The engine is an event loop: every peer socket and the listen socket
+//! are non-blocking and registered with mio, which waits for any of them to
+//! become ready with epoll, along with a timer for the periodic work. All
+//! of it runs on the thread calling `step`. Tracker announces block, so
+//! they run on a fixed set of announce threads, and their responses come
+//! back through the event loop like any other event.
+//!
+//! The engine owns the client and the state of every connection: it feeds
+//! the messages that arrive to the torrents, keeps requests queued with
+//! every peer that unchoked us, serves the peers we unchoked and writes out
+//! what the connections queued, as far as the sockets take it.
+//!
+//! Storage is accessed on the threads of a `DiskPool`: blocks that arrive
+//! are written and completed pieces hashed there, and blocks peers asked
+//! for are read there. While more is queued for writing than the pool's
+//! budget, no more blocks are requested from peers.
+//!
+//! Peers are taken from each downloading torrent's `SessionInfo`, within
+//! the `ConnectionLimits`. Peers that couldn't be reached, or that
+//! disconnected, are tried again after a growing delay, see
+//! `ConnectBackoff`.
+
+use std::collections::hashmap::{HashMap, HashSet};
+use std::io::{IoResult, IoError, ResourceUnavailable};
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::uint;
+
+use mio::{EventLoop, Handler, Token, ReadHint, Interest, PollOpt, MioResult, Ready, WouldBlock, IoReader, IoWriter};
+use mio::net::{InetAddr, Socket};
+use mio::net::tcp::TcpSocket;
+use time::precise_time_ns;
+
+use announce::{AnnounceResponse, Success};
+use client::Client;
+use connection::{PeerConnection, BlockRequest};
+use extension::HANDSHAKE_ID;
+use handshake::{Handshake, HANDSHAKE_LENGTH};
+use listener::io_error;
+use storage::{StorageError, DiskPool, DiskResult};
+use storage::pool::{Written, Read, Hashed, DEFAULT_THREADS, DEFAULT_BUDGET};
+use torrent::{Torrent, Downloading, Seeding, Stopped};
+use wire::{Message, Decoder, Piece, Cancel, Have, Extended};
+
+
+/// Token of the listen socket; connections use their id, starting at 1
+static LISTENER: Token = Token(0);
+
+/// Milliseconds between runs of the periodic work: peer timeouts,
+/// rechoking, new connections and announces
+pub static TICK_INTERVAL: u64 = 100;
+/// Time a peer has to accept a connection and send its handshake
+pub static CONNECT_TIMEOUT: u64 = 10 * 1_000_000_000;
+/// Connections being made at once; most peers from trackers can't be
+/// reached, so this keeps them from crowding out the rest
+pub static MAX_HALF_OPEN: uint = 16;
+/// Blocks read for a single peer at a time
+pub static MAX_SERVE_BLOCKS: uint = 16;
+/// Peers with this many bytes the socket didn't take yet aren't served
+/// more blocks until it does
+pub static MAX_BUFFERED: uint = 1 << 20;
+/// Nanoseconds of hashing per step while torrents are checked, so their
+/// connections aren't starved
+static CHECK_TIME: u64 = 50 * 1_000_000;
+/// Bytes read from a socket at once
+static READ_SIZE: uint = 16384;
+/// Threads running announces; a tracker that doesn't answer holds up one
+/// of them until the announce times out
+static ANNOUNCE_THREADS: uint = 4;
+
+/// Delay before connecting again to a peer that failed, doubled on every
+/// consecutive failure up to `MAX_CONNECT_BACKOFF`
+static CONNECT_BACKOFF: u64 = 30 * 1_000_000_000;
+static MAX_CONNECT_BACKOFF: u64 = 30 * 60 * 1_000_000_000;
+/// Peers that failed this many times in a row are given up on
+pub static MAX_CONNECT_FAILURES: uint = 8;
+
+/// How many peers to be connected to at once, counting connections being
+/// made
+#[deriving(Show, Clone)]
+pub struct ConnectionLimits {
+    /// Over every torrent
+    pub global: uint,
+    pub per_torrent: uint,
+}
+
+impl ConnectionLimits {
+    pub fn new() -> ConnectionLimits {
+        ConnectionLimits { global: 200, per_torrent: 50 }
+    }
+}
+
+/// When peers that failed may be connected to again
+pub struct ConnectBackoff {
+    /// Consecutive failures and time of the next attempt
+    peers: HashMap<SocketAddr, (uint, u64)>,
+}
+
+impl ConnectBackoff {
+    pub fn new() -> ConnectBackoff {
+        ConnectBackoff { peers: HashMap::new() }
+    }
+
+    /// Whether connecting to `address` may be tried at `now`
+    pub fn can_connect(&self, address: &SocketAddr, now: u64) -> bool {
+        match self.peers.find(address) {
+            Some(&(failures, retry_at)) => failures < MAX_CONNECT_FAILURES && now >= retry_at,
+            None => true
+        }
+    }
+
+    /// Connecting to `address` failed, or the connection was lost
+    pub fn failed(&mut self, address: &SocketAddr, now: u64) {
+        let entry = self.peers.find_or_insert(*address, (0, now));
+        let failures = match *entry { (failures, _) => failures + 1 };
+        let delay = (CONNECT_BACKOFF << (failures - 1).min(16)).min(MAX_CONNECT_BACKOFF);
+        *entry = (failures, now + delay);
+    }
+
+    /// The handshake with `address` went through, its failures are
+    /// forgotten
+    pub fn connected(&mut self, address: &SocketAddr) {
+        self.peers.remove(address);
+    }
+}
+
+/// What the engine's other threads tell the event loop
+pub enum Notice {
+    /// Infohash of the torrent and the responses, see `AnnounceJob::run`
+    Announced(Vec<u8>, Vec<(uint, Option<AnnounceResponse>)>),
+    /// Infohash of the torrent and the responses of the trackers learned
+    /// through `lt_tex`, see `TexJob::run`
+    TrackersVerified(Vec<u8>, Vec<(String, Option<AnnounceResponse>)>),
+    Disk(DiskResult),
+}
+
+type Loop = EventLoop<(), Notice>;
+
+#[deriving(PartialEq)]
+enum LinkState {
+    /// Waiting for the connection to a peer to be made
+    Connecting,
+    /// Waiting for the peer's handshake
+    Handshaking,
+    Established,
+}
+
+/// A connection, established or being made
+struct Link {
+    /// Token of the socket
+    id: uint,
+    socket: TcpSocket,
+    state: LinkState,
+    /// Whether we made the connection
+    outgoing: bool,
+    /// `None` for incoming connections until the peer's handshake names
+    /// the torrent
+    info_hash: Option<Vec<u8>>,
+    /// When the connection was started, for `CONNECT_TIMEOUT`
+    started: u64,
+    /// The peer's handshake as far as it arrived
+    handshake: Vec<u8>,
+    decoder: Decoder,
+    /// Bytes queued for the socket, of which the first `sent` were written
+    output: Vec<u8>,
+    sent: uint,
+    /// Requests of the peer whose blocks are being read
+    reading: Vec<BlockRequest>,
+}
+
+impl Link {
+    /// Queue `data` for the peer and write as much of it as the socket
+    /// takes. Returns whether the connection is still good.
+    fn send(&mut self, data: &[u8]) -> bool {
+        self.output.push_all(data);
+        self.flush()
+    }
+
+    fn send_messages(&mut self, messages: &[Message]) -> bool {
+        let mut data = Vec::new();
+        for message in messages.iter() {
+            data.push_all(message.encode().as_slice());
+        }
+        self.send(data.as_slice())
+    }
+
+    /// Write out queued data until the socket would block
+    fn flush(&mut self) -> bool {
+        if self.state == Connecting {
+            return true;
+        }
+        while self.sent < self.output.len() {
+            match self.socket.write_slice(self.output.slice_from(self.sent)) {
+                Ok(Ready(written)) => self.sent += written,
+                Ok(WouldBlock) => break,
+                Err(_) => return false
+            }
+        }
+        // drop what was written once it's most of the buffer, so each byte
+        // is only moved a few times
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+        } else if self.sent > self.output.len() / 2 {
+            self.output = Vec::from_slice(self.output.slice_from(self.sent));
+            self.sent = 0;
+        }
+        true
+    }
+
+    /// Bytes the socket didn't take yet
+    fn buffered(&self) -> uint {
+        self.output.len() - self.sent
+    }
+
+    /// Everything the socket has to read, and whether the connection is
+    /// still open
+    fn read(&mut self) -> (Vec<u8>, bool) {
+        let mut data = Vec::new();
+        let mut buffer = [0u8, ..READ_SIZE];
+        loop {
+            match self.socket.read_slice(buffer.as_mut_slice()) {
+                Ok(Ready(0)) | Err(_) => return (data, false),
+                Ok(Ready(read)) => data.push_all(buffer.slice_to(read)),
+                Ok(WouldBlock) => return (data, true)
+            }
+        }
+    }
+}
+
+pub struct Engine {
+    pub client: Client,
+    pub limits: ConnectionLimits,
+    /// Established connections of every torrent, by infohash
+    swarms: HashMap<Vec<u8>, Vec<PeerConnection>>,
+    /// Every connection by peer address
+    links: HashMap<SocketAddr, Link>,
+    /// Peer address of every connection by id
+    addresses: HashMap<uint, SocketAddr>,
+    backoff: ConnectBackoff,
+    next_id: uint,
+    /// Taken out while it runs with the engine as its handler
+    event_loop: Option<Loop>,
+    /// Port of the listen socket registered with the event loop
+    listening: Option<u16>,
+    /// Set when accepting failed, to the number of connections to drop
+    /// below before trying again, see `accept`
+    accept_paused: Option<uint>,
+    /// Queue of the announce threads, each job returning its notice
+    announcer: Sender<proc():Send -> Notice>,
+    disk: DiskPool,
+    /// Torrents with an announce under way
+    announcing: HashSet<Vec<u8>>,
+    /// Storage errors since the last `step`
+    failed: Vec<(Vec<u8>, StorageError)>,
+}
+
+impl Engine {
+    pub fn new(client: Client) -> IoResult<Engine> {
+        let mut event_loop: Loop = try!(EventLoop::new().map_err(io_error));
+        // the timer only fails when it's full, which one timeout can't do
+        event_loop.timeout((), Duration::milliseconds(TICK_INTERVAL as i64)).unwrap();
+        let (announcer, jobs) = channel::<proc():Send -> Notice>();
+        let jobs = Arc::new(Mutex::new(jobs));
+        for _ in range(0, ANNOUNCE_THREADS) {
+            let jobs = jobs.clone();
+            let notices = event_loop.channel();
+            spawn(proc() {
+                loop {
+                    // the queue closes when the engine is dropped
+                    let job = match jobs.lock().recv_opt() {
+                        Ok(job) => job,
+                        Err(()) => break
+                    };
+                    // the engine may be gone by the time the job is done
+                    let _ = notices.send(job());
+                }
+            });
+        }
+        let notices = event_loop.channel();
+        let (disk, results) = DiskPool::new(DEFAULT_THREADS, DEFAULT_BUDGET);
+        spawn(proc() {
+            for result in results.iter() {
+                if notices.send(Disk(result)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Engine {
+            client: client,
+            limits: ConnectionLimits::new(),
+            swarms: HashMap::new(),
+            links: HashMap::new(),
+            addresses: HashMap::new(),
+            backoff: ConnectBackoff::new(),
+            next_id: 1,
+            event_loop: Some(event_loop),
+            listening: None,
+            accept_paused: None,
+            announcer: announcer,
+            disk: disk,
+            announcing: HashSet::new(),
+            failed: Vec::new(),
+        })
+    }
+
+    /// Established connections of the torrent with `info_hash`
+    pub fn connections<'a>(&'a self, info_hash: &[u8]) -> &'a [PeerConnection] {
+        match self.swarms.find(&Vec::from_slice(info_hash)) {
+            Some(swarm) => swarm.as_slice(),
+            None => &[]
+        }
+    }
+
+    /// Connections established or being made, over every torrent
+    pub fn num_connections(&self) -> uint {
+        self.links.len()
+    }
+
+    /// Wait for sockets to become ready or for the next tick, at most
+    /// `TICK_INTERVAL`, and handle all of it: accept incoming connections,
+    /// handle the data that arrived, keep `Downloading` and `Seeding`
+    /// torrents exchanging data, connect to more peers and announce.
+    /// Connections of other torrents are closed. Torrents being checked
+    /// get `CHECK_TIME` of hashing first.
+    ///
+    /// Returns the infohashes and errors of torrents whose storage failed;
+    /// those torrents are `Stopped`.
+    pub fn step(&mut self) -> Vec<(Vec<u8>, StorageError)> {
+        self.check_torrents();
+        let mut event_loop = self.event_loop.take().unwrap();
+        self.register_listener(&mut event_loop);
+        // a wait interrupted by a signal just makes for a shorter step
+        let _ = event_loop.run_once(self);
+        self.event_loop = Some(event_loop);
+        self.disk.submit();
+        mem::replace(&mut self.failed, Vec::new())
+    }
+
+    fn check_torrents(&mut self) {
+        let start = precise_time_ns();
+        while self.client.is_checking() && precise_time_ns() - start < CHECK_TIME {
+            let failed = self.client.check_torrents();
+            self.failed.push_all_move(failed);
+        }
+    }
+
+    /// Register the client's listen socket with the event loop once it's
+    /// bound
+    fn register_listener(&mut self, event_loop: &mut Loop) {
+        let listener = match self.client.listener() {
+            Some(listener) => listener,
+            None => return
+        };
+        if self.listening == Some(listener.port()) {
+            return;
+        }
+        // should registering fail, it's tried again on the next step
+        if event_loop.register_opt(listener.acceptor(), LISTENER, Interest::readable(), PollOpt::edge()).is_ok() {
+            self.listening = Some(listener.port());
+        }
+    }
+
+    fn torrent_failed(&mut self, info_hash: Vec<u8>, error: StorageError) {
+        match self.client.find_torrent(info_hash.as_slice()) {
+            Some(torrent) => torrent.status = Stopped,
+            None => ()
+        }
+        self.failed.push((info_hash, error));
+    }
+
+    fn address(&self, token: Token) -> Option<SocketAddr> {
+        let Token(id) = token;
+        self.addresses.find(&id).map(|address| *address)
+    }
+
+    /// Connections to the torrent with `info_hash`, established or not
+    fn torrent_links(&self, info_hash: &[u8]) -> uint {
+        self.links.values().filter(|link| link.info_hash.as_ref().map_or(false, |hash| hash.as_slice() == info_hash)).count()
+    }
+
+    /// Register `socket` and keep track of its connection. Returns whether
+    /// that worked; if not, the socket is closed.
+    fn add_link(&mut self, event_loop: &mut Loop, address: SocketAddr, socket: TcpSocket,
+                info_hash: Option<Vec<u8>>, now: u64) -> bool {
+        let id = self.next_id;
+        self.next_id += 1;
+        let interest = Interest::readable() | Interest::writable() | Interest::hup();
+        if event_loop.register_opt(&socket, Token(id), interest, PollOpt::edge()).is_err() {
+            return false;
+        }
+        let outgoing = info_hash.is_some();
+        self.addresses.insert(id, address);
+        self.links.insert(address, Link {
+            id: id,
+            socket: socket,
+            state: if outgoing { Connecting } else { Handshaking },
+            outgoing: outgoing,
+            info_hash: info_hash,
+            started: now,
+            handshake: Vec::new(),
+            decoder: Decoder::new(),
+            output: Vec::new(),
+            sent: 0,
+            reading: Vec::new(),
+        });
+        true
+    }
+
+    /// Take the connections waiting on the listen socket. When that fails,
+    /// the rest wait for `tick`: until one of our connections closes if we
+    /// ran out of file descriptors, otherwise until the next tick.
+    fn accept(&mut self, event_loop: &mut Loop, now: u64) {
+        loop {
+            let socket = match self.client.listener().map(|listener| listener.accept()) {
+                Some(Ok(Some(socket))) => socket,
+                Some(Err(ref error)) => {
+                    self.accept_paused = Some(if out_of_descriptors(error) { self.links.len() } else { uint::MAX });
+                    return;
+                },
+                _ => return
+            };
+            let address = match socket.getpeername() {
+                Ok(InetAddr(ip, port)) => SocketAddr { ip: ip, port: port },
+                _ => continue
+            };
+            if self.links.contains_key(&address) || self.links.len() >= self.limits.global {
+                // dropping the socket closes the connection
+                continue;
+            }
+            self.add_link(event_loop, address, socket, None, now);
+        }
+    }
+
+    fn read(&mut self, address: &SocketAddr, now: u64) {
+        let (data, open) = self.links.find_mut(address).unwrap().read();
+        if !data.is_empty() {
+            self.received(address, data.as_slice(), now);
+        }
+        if !open {
+            self.disconnect(address, now);
+        }
+    }
+
+    /// Handle data that arrived from the peer at `address`: first its
+    /// handshake, then messages
+    fn received(&mut self, address: &SocketAddr, data: &[u8], now: u64) {
+        let mut data = data;
+        if self.links.find(address).unwrap().state == Handshaking {
+            let handshake = {
+                let link = self.links.find_mut(address).unwrap();
+                let needed = (HANDSHAKE_LENGTH - link.handshake.len()).min(data.len());
+                link.handshake.push_all(data.slice_to(needed));
+                data = data.slice_from(needed);
+                if link.handshake.len() < HANDSHAKE_LENGTH {
+                    return;
+                }
+                Handshake::decode(link.handshake.as_slice())
+            };
+            let accepted = match handshake {
+                Ok(ref handshake) => self.handshaken(address, handshake, now),
+                Err(_) => false
+            };
+            if !accepted {
+                self.disconnect(address, now);
+                return;
+            }
+        }
+
+        let mut messages = Vec::new();
+        let mut broken = false;
+        {
+            let link = self.links.find_mut(address).unwrap();
+            link.decoder.feed(data);
+            loop {
+                match link.decoder.next() {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => break,
+                    Err(_) => { broken = true; break; }
+                }
+            }
+        }
+        for message in messages.iter() {
+            self.receive(address, message, now);
+            if !self.links.contains_key(address) {
+                return;
+            }
+        }
+        if broken {
+            self.disconnect(address, now);
+            return;
+        }
+        self.service(address, now);
+    }
+
+    /// Set up the connection to `address` once the peer's handshake
+    /// arrived. Returns whether the handshake is acceptable: for a running
+    /// torrent with room for the peer (the torrent we asked for, when we
+    /// made the connection) and from neither ourselves nor a banned peer.
+    /// Incoming connections are answered with our handshake.
+    fn handshaken(&mut self, address: &SocketAddr, handshake: &Handshake, now: u64) -> bool {
+        let (expected, outgoing) = match self.links.find(address) {
+            Some(link) => (link.info_hash.clone(), link.outgoing),
+            None => return false
+        };
+        let info_hash = Vec::from_slice(handshake.info_hash.as_slice());
+        match expected {
+            Some(ref expected) if *expected != info_hash => return false,
+            None if self.torrent_links(info_hash.as_slice()) >= self.limits.per_torrent => return false,
+            _ => ()
+        }
+        let capabilities = self.client.capabilities();
+        let common = capabilities.intersect(&handshake.capabilities());
+        let reply = Handshake::new(info_hash.as_slice(), self.client.peer_id().as_bytes(), &capabilities);
+        let connection = match self.client.accept_handshake(handshake) {
+            Ok(torrent) if is_running(torrent) && !torrent.is_banned(address.ip) => {
+                let mut connection = PeerConnection::new(*address, torrent.info.num_pieces(), now);
+                connection.peer_id = Some(handshake.peer_id);
+                connection.fast = common.fast;
+                connection.extensions = common.extensions;
+                match torrent.bitfield_message(common.fast) {
+                    Some(message) => connection.send(message, now).unwrap(),
+                    None => ()
+                }
+                if common.extensions {
+                    let payload = torrent.extended_handshake().encode();
+                    connection.send(Extended(HANDSHAKE_ID, payload), now).unwrap();
+                }
+                connection
+            },
+            _ => return false
+        };
+        {
+            let link = self.links.find_mut(address).unwrap();
+            link.state = Established;
+            link.info_hash = Some(info_hash.clone());
+            if !outgoing && !link.send(reply.encode().as_slice()) {
+                return false;
+            }
+        }
+        self.backoff.connected(address);
+        self.swarms.find_or_insert(info_hash, Vec::new()).push(connection);
+        true
+    }
+
+    /// Handle a message from the peer at `address`. Blocks are queued for
+    /// writing and cancelled with the other peers they were requested
+    /// from, and completed pieces are queued for hashing, see
+    /// `piece_hashed`.
+    fn receive(&mut self, address: &SocketAddr, message: &Message, now: u64) {
+        let info_hash = match self.links.find(address).and_then(|link| link.info_hash.clone()) {
+            Some(info_hash) => info_hash,
+            None => return
+        };
+        let good = {
+            let (torrent, swarm) = match (self.client.find_torrent(info_hash.as_slice()), self.swarms.find_mut(&info_hash)) {
+                (Some(torrent), Some(swarm)) => (torrent, swarm),
+                _ => return
+            };
+            let index = match swarm.iter().position(|connection| connection.address == *address) {
+                Some(index) => index,
+                None => return
+            };
+            if torrent.receive(swarm.get_mut(index), message, now).is_err() {
+                false
+            } else {
+                match *message {
+                    Piece(piece, begin, ref data) => {
+                        let request = BlockRequest { piece: piece, begin: begin, length: data.len() as u32 };
+                        match torrent.block_received(&request, address) {
+                            Some(ref received) if !received.duplicate => {
+                                self.disk.write(info_hash.as_slice(), &torrent.storage, piece as uint, begin as uint, data.clone());
+                                for connection in swarm.mut_iter().filter(|connection| received.cancel.contains(&connection.address)) {
+                                    connection.send(Cancel(piece, begin, request.length), now).unwrap();
+                                }
+                                if received.piece_complete {
+                                    let index = piece as uint;
+                                    self.disk.hash(info_hash.as_slice(), &torrent.storage, index, torrent.info.piece_size(index), torrent.info.piece_hash(index));
+                                }
+                            },
+                            _ => ()
+                        }
+                    },
+                    _ => ()
+                }
+                true
+            }
+        };
+        if !good {
+            self.disconnect(address, now);
+        }
+    }
+
+    /// Keep the connection to `address` going right away, rather than at
+    /// the next tick, see `exchange`
+    fn service(&mut self, address: &SocketAddr, now: u64) {
+        let info_hash = match self.links.find(address).and_then(|link| link.info_hash.clone()) {
+            Some(info_hash) => info_hash,
+            None => return
+        };
+        let good = {
+            let (torrent, swarm) = match (self.client.find_torrent(info_hash.as_slice()), self.swarms.find_mut(&info_hash)) {
+                (Some(torrent), Some(swarm)) => (torrent, swarm),
+                _ => return
+            };
+            if !is_running(torrent) {
+                return;
+            }
+            let connection = match swarm.mut_iter().find(|connection| connection.address == *address) {
+                Some(connection) => connection,
+                None => return
+            };
+            exchange(torrent, connection, self.links.find_mut(address).unwrap(), &mut self.disk, now)
+        };
+        if !good {
+            self.disconnect(address, now);
+        }
+    }
+
+    /// Handle what a disk job came to
+    fn disk_done(&mut self, result: DiskResult, now: u64) {
+        match result {
+            Written(info_hash, _, _, _, Err(e)) => self.torrent_failed(info_hash, e),
+            Written(info_hash, piece, begin, length, Ok(())) => {
+                match self.client.find_torrent(info_hash.as_slice()) {
+                    Some(torrent) => torrent.blocks_written(piece, begin, length),
+                    None => ()
+                }
+            },
+            Read(info_hash, address, piece, begin, Ok(data)) => self.block_read(info_hash, &address, piece, begin, data, now),
+            Read(info_hash, address, piece, begin, Err(_)) => self.read_failed(info_hash, &address, piece, begin, now),
+            Hashed(info_hash, index, Ok((passed, data))) => self.piece_hashed(info_hash, index, passed, data.as_slice(), now),
+            Hashed(info_hash, _, Err(e)) => self.torrent_failed(info_hash, e)
+        }
+    }
+
+    /// A block the peer at `address` asked for was read; send it, unless
+    /// the peer doesn't want it anymore
+    fn block_read(&mut self, info_hash: Vec<u8>, address: &SocketAddr, piece: uint, begin: uint, data: Vec<u8>, now: u64) {
+        let request = BlockRequest { piece: piece as u32, begin: begin as u32, length: data.len() as u32 };
+        match self.links.find_mut(address) {
+            Some(link) => link.reading.retain(|reading| *reading != request),
+            None => return
+        }
+        {
+            let (torrent, swarm) = match (self.client.find_torrent(info_hash.as_slice()), self.swarms.find_mut(&info_hash)) {
+                (Some(torrent), Some(swarm)) => (torrent, swarm),
+                _ => return
+            };
+            match swarm.mut_iter().find(|connection| connection.address == *address) {
+                Some(connection) => { torrent.serve_block(connection, &request, data, now); },
+                None => return
+            }
+        }
+        self.service(address, now);
+    }
+
+    /// A block the peer at `address` asked for couldn't be read. Only that
+    /// request fails: the rest of the torrent may read fine.
+    fn read_failed(&mut self, info_hash: Vec<u8>, address: &SocketAddr, piece: uint, begin: uint, now: u64) {
+        let request = match self.links.find_mut(address) {
+            Some(link) => match link.reading.iter().position(|reading| reading.piece as uint == piece && reading.begin as uint == begin) {
+                Some(position) => link.reading.remove(position).unwrap(),
+                None => return
+            },
+            None => return
+        };
+        {
+            let (torrent, swarm) = match (self.client.find_torrent(info_hash.as_slice()), self.swarms.find_mut(&info_hash)) {
+                (Some(torrent), Some(swarm)) => (torrent, swarm),
+                _ => return
+            };
+            match swarm.mut_iter().find(|connection| connection.address == *address) {
+                Some(connection) => torrent.serve_failed(connection, &request, now),
+                None => return
+            }
+        }
+        self.service(address, now);
+    }
+
+    /// Piece `index` was hashed: tell every peer about it if it passed,
+    /// and drop the peers that are banned now
+    fn piece_hashed(&mut self, info_hash: Vec<u8>, index: uint, passed: bool, data: &[u8], now: u64) {
+        let mut dropped = Vec::new();
+        {
+            let torrent = match self.client.find_torrent(info_hash.as_slice()) {
+                Some(torrent) => torrent,
+                None => return
+            };
+            torrent.piece_hashed(index, passed, data);
+            match self.swarms.find_mut(&info_hash) {
+                Some(swarm) => for connection in swarm.mut_iter() {
+                    if passed {
+                        connection.send(Have(index as u32), now).unwrap();
+                    }
+                    if torrent.is_banned(connection.address.ip) {
+                        dropped.push(connection.address);
+                    }
+                },
+                None => ()
+            }
+        }
+        for address in dropped.iter() {
+            self.disconnect(address, now);
+        }
+    }
+
+    /// The periodic work: connection timeouts, keep-alives and rechoking,
+    /// new connections and announces
+    fn tick(&mut self, event_loop: &mut Loop, now: u64) {
+        let expired: Vec<SocketAddr> = self.links.iter()
+            .filter(|&(_, link)| link.state != Established && now - link.started >= CONNECT_TIMEOUT)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in expired.iter() {
+            self.disconnect(address, now);
+        }
+        match self.accept_paused {
+            Some(links) if self.links.len() < links => {
+                self.accept_paused = None;
+                self.accept(event_loop, now);
+            },
+            _ => ()
+        }
+        for index in range(0, self.client.torrents().len()) {
+            let info_hash = self.client.torrents()[index].info.infohash.clone();
+            self.run_torrent(info_hash.as_slice(), now);
+        }
+        self.connect_peers(event_loop, now);
+        self.announce();
+    }
+
+    /// Time out, rechoke and keep going all connections of a torrent, or
+    /// close them if it isn't running
+    fn run_torrent(&mut self, info_hash: &[u8], now: u64) {
+        let slots = self.client.upload_slots();
+        let mut dropped = Vec::new();
+        {
+            let (torrent, swarm) = match (self.client.find_torrent(info_hash), self.swarms.find_mut(&Vec::from_slice(info_hash))) {
+                (Some(torrent), Some(swarm)) => (torrent, swarm),
+                _ => return
+            };
+            if !is_running(torrent) {
+                for connection in swarm.iter() {
+                    dropped.push(connection.address);
+                }
+            } else {
+                for connection in swarm.mut_iter() {
+                    if connection.tick(now).is_err() {
+                        dropped.push(connection.address);
+                    }
+                }
+                torrent.rechoke(swarm.as_mut_slice(), slots, now);
+                for connection in swarm.mut_iter() {
+                    if dropped.contains(&connection.address) {
+                        continue;
+                    }
+                    torrent.send_tex(connection, now);
+                    let link = self.links.find_mut(&connection.address).unwrap();
+                    if !exchange(torrent, connection, link, &mut self.disk, now) {
+                        dropped.push(connection.address);
+                    }
+                }
+            }
+        }
+        for address in dropped.iter() {
+            self.disconnect(address, now);
+        }
+    }
+
+    /// Start connecting to peers of downloading torrents, as far as the
+    /// limits allow
+    fn connect_peers(&mut self, event_loop: &mut Loop, now: u64) {
+        let mut half_open = self.links.values().filter(|link| link.state != Established).count();
+        let mut attempts = Vec::new();
+        for torrent in self.client.torrents().iter().filter(|torrent| torrent.status == Downloading) {
+            let info_hash = torrent.info.infohash.as_slice();
+            let mut count = self.torrent_links(info_hash);
+            for peer in torrent.session.peers.iter() {
+                if half_open >= MAX_HALF_OPEN || count >= self.limits.per_torrent || self.links.len() + attempts.len() >= self.limits.global {
+                    break;
+                }
+                let address = peer.address;
+                if self.links.contains_key(&address) || attempts.iter().any(|&(ref a, _, _)| *a == address)
+                    || !self.backoff.can_connect(&address, now) || torrent.is_banned(address.ip)
+                    || self.client.is_own_address(&address) {
+                    continue;
+                }
+                attempts.push((address, Vec::from_slice(info_hash), self.client.handshake_for(torrent)));
+                half_open += 1;
+                count += 1;
+            }
+        }
+        for (address, info_hash, handshake) in attempts.move_iter() {
+            let connected = match connect(address) {
+                Ok(socket) => self.add_link(event_loop, address, socket, Some(info_hash), now),
+                Err(_) => false
+            };
+            if connected {
+                // sent once the connection is made
+                self.links.find_mut(&address).unwrap().output.push_all(handshake.encode().as_slice());
+            } else {
+                self.backoff.failed(&address, now);
+            }
+        }
+    }
+
+    /// Hand the announces that are due, and those verifying the trackers
+    /// learned through `lt_tex`, to the announce thread
+    fn announce(&mut self) {
+        let peer_id = self.client.peer_id();
+        for torrent in self.client.get_torrents().mut_iter().filter(|torrent| is_running(&**torrent)) {
+            let info_hash = torrent.info.infohash.clone();
+            // the thread only stops with the engine, so sending can't fail
+            // while we run
+            match torrent.tex_job(peer_id.clone()) {
+                Some(job) => {
+                    let info_hash = info_hash.clone();
+                    let _ = self.announcer.send_opt(proc() TrackersVerified(info_hash, job.run()));
+                },
+                None => ()
+            }
+            if self.announcing.contains(&info_hash) {
+                continue;
+            }
+            match torrent.announce_job(peer_id.clone()) {
+                Some(job) => {
+                    self.announcing.insert(info_hash.clone());
+                    let _ = self.announcer.send_opt(proc() Announced(info_hash, job.run()));
+                },
+                None => ()
+            }
+        }
+    }
+
+    fn announced(&mut self, info_hash: Vec<u8>, results: Vec<(uint, Option<AnnounceResponse>)>) {
+        let response = match self.client.find_torrent(info_hash.as_slice()) {
+            Some(torrent) => torrent.announce_done(results),
+            None => None
+        };
+        self.announcing.remove(&info_hash);
+        match response {
+            Some(Success(ref result)) if result.external_ip.is_some() => self.client.set_external_ip(result.external_ip.unwrap()),
+            _ => ()
+        }
+        let own: Vec<SocketAddr> = match self.client.torrents().iter().find(|torrent| torrent.info.infohash == info_hash) {
+            Some(torrent) => torrent.session.peers.iter()
+                .map(|peer| peer.address)
+                .filter(|address| self.client.is_own_address(address))
+                .collect(),
+            None => return
+        };
+        match self.client.find_torrent(info_hash.as_slice()) {
+            Some(torrent) => torrent.session.peers.retain(|peer| !own.contains(&peer.address)),
+            None => ()
+        }
+    }
+
+    /// Close the connection to `address` and forget it. Peers we connected
+    /// to are connected to again only after a backoff.
+    fn disconnect(&mut self, address: &SocketAddr, now: u64) {
+        // dropping the link closes the socket, which also takes it out of
+        // the event loop
+        let link = match self.links.pop(address) {
+            Some(link) => link,
+            None => return
+        };
+        self.addresses.remove(&link.id);
+        if link.outgoing {
+            self.backoff.failed(address, now);
+        }
+        let info_hash = match link.info_hash {
+            Some(ref info_hash) => info_hash,
+            None => return
+        };
+        let connection = match self.swarms.find_mut(info_hash) {
+            Some(swarm) => swarm.iter().position(|connection| connection.address == *address).and_then(|index| swarm.remove(index)),
+            None => None
+        };
+        match (connection, self.client.find_torrent(info_hash.as_slice())) {
+            (Some(ref connection), Some(torrent)) => torrent.peer_disconnected(connection),
+            _ => ()
+        }
+    }
+}
+
+impl Handler<(), Notice> for Engine {
+    fn readable(&mut self, event_loop: &mut Loop, token: Token, _: ReadHint) {
+        let now = precise_time_ns();
+        if token == LISTENER {
+            self.accept(event_loop, now);
+            return;
+        }
+        match self.address(token) {
+            Some(address) => self.read(&address, now),
+            None => ()
+        }
+    }
+
+    fn writable(&mut self, _: &mut Loop, token: Token) {
+        let now = precise_time_ns();
+        let address = match self.address(token) {
+            Some(address) => address,
+            None => return
+        };
+        let (good, established) = {
+            let link = self.links.find_mut(&address).unwrap();
+            if link.state == Connecting {
+                link.state = Handshaking;
+            }
+            (link.flush(), link.state == Established)
+        };
+        if !good {
+            self.disconnect(&address, now);
+        } else if established {
+            // the socket took what was queued, there's room for more blocks
+            self.service(&address, now);
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut Loop, _: ()) {
+        self.tick(event_loop, precise_time_ns());
+        event_loop.timeout((), Duration::milliseconds(TICK_INTERVAL as i64)).unwrap();
+    }
+
+    fn notify(&mut self, _: &mut Loop, notice: Notice) {
+        match notice {
+            Announced(info_hash, results) => self.announced(info_hash, results),
+            TrackersVerified(info_hash, results) => match self.client.find_torrent(info_hash.as_slice()) {
+                Some(torrent) => torrent.tex_done(results),
+                None => ()
+            },
+            Disk(result) => self.disk_done(result, precise_time_ns())
+        }
+    }
+}
+
+/// Whether the torrent exchanges data with peers
+fn is_running(torrent: &Torrent) -> bool {
+    torrent.status == Downloading || torrent.status == Seeding
+}
+
+/// Keep one connection going: expire our requests and, unless the disk is
+/// congested, top them up; have the blocks the peer asked for read as far as
+/// the socket keeps up, and write out what the connection queued. Returns
+/// whether the connection is still good.
+fn exchange(torrent: &mut Torrent, connection: &mut PeerConnection, link: &mut Link, disk: &mut DiskPool, now: u64) -> bool {
+    torrent.expire_requests(connection, now);
+    torrent.update_interest(connection, now);
+    if !disk.is_congested() {
+        torrent.fill_requests(connection, now);
+    }
+    if link.buffered() < MAX_BUFFERED {
+        for request in connection.peer_requests.iter() {
+            if link.reading.len() >= MAX_SERVE_BLOCKS {
+                break;
+            }
+            if link.reading.contains(request) {
+                continue;
+            }
+            disk.read(torrent.info.infohash.as_slice(), &torrent.storage, connection.address,
+                      request.piece as uint, request.begin as uint, request.length as uint);
+            link.reading.push(request.clone());
+        }
+    }
+    link.send_messages(connection.take_outgoing().as_slice())
+}
+
+/// Whether accepting failed because we, or the whole system, ran out of
+/// file descriptors, see `Listener::accept`
+fn out_of_descriptors(error: &IoError) -> bool {
+    error.kind == ResourceUnavailable
+}
+
+/// Start a non-blocking connection to `address`
+fn connect(address: SocketAddr) -> MioResult<TcpSocket> {
+    let socket = try!(match address.ip {
+        Ipv4Addr(..) => TcpSocket::v4(),
+        _ => TcpSocket::v6()
+    });
+    try!(socket.connect(&InetAddr(address.ip, address.port)));
+    Ok(socket)
+}
@@ -0,0 +1,75 @@
+//! The extension protocol (BEP 10)
+//!
+//! Peers that both set the extension bit in their handshakes may send
+//! `Extended` messages. The first one, with message ID 0, is the extended
+//! handshake: a bencoded dictionary whose `m` maps the name of every
+//! extension the sender understands to the message ID it wants to receive
+//! that extension's messages under. An ID of 0 disables the extension.
+
+use std::collections::TreeMap;
+
+use bencode;
+use bencode::{Dict, ByteString, Number, Key};
+
+
+/// Message ID of the extended handshake
+pub static HANDSHAKE_ID: u8 = 0;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct ExtendedHandshake {
+    /// Extensions the sender understands, with the message IDs to send them
+    /// under
+    pub messages: Vec<(String, u8)>,
+    /// Hash of the sender's tracker list, see `tex::tracker_list_hash`
+    pub tracker_hash: Option<Vec<u8>>,
+}
+
+impl ExtendedHandshake {
+    pub fn new() -> ExtendedHandshake {
+        ExtendedHandshake { messages: Vec::new(), tracker_hash: None }
+    }
+
+    /// Message ID the sender wants messages of extension `name` under,
+    /// `None` if it doesn't speak it
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.messages.iter().find(|&&(ref extension, _)| extension.as_slice() == name).map(|&(_, id)| id)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut messages = TreeMap::new();
+        for &(ref name, id) in self.messages.iter() {
+            messages.insert(Key::from_str(name.as_slice()), Number(id as i64));
+        }
+        let mut dict = TreeMap::new();
+        dict.insert(Key::from_str("m"), Dict(messages));
+        match self.tracker_hash {
+            Some(ref hash) => { dict.insert(Key::from_str("tr"), ByteString(hash.clone())); },
+            None => ()
+        }
+        Dict(dict).to_bytes().unwrap()
+    }
+
+    /// Decode an extended handshake. Disabled extensions and IDs that don't
+    /// fit a message ID are left out.
+    pub fn decode(payload: &[u8]) -> Option<ExtendedHandshake> {
+        let dict = match bencode::from_vec(Vec::from_slice(payload)) {
+            Ok(Dict(dict)) => dict,
+            _ => return None
+        };
+        let mut messages = Vec::new();
+        match dict.find(&Key::from_str("m")) {
+            Some(&Dict(ref m)) => for (name, id) in m.iter() {
+                match (String::from_utf8(Vec::from_slice(name.as_slice())), id) {
+                    (Ok(name), &Number(id)) if id > 0 && id < 256 => messages.push((name, id as u8)),
+                    _ => ()
+                }
+            },
+            _ => return None
+        }
+        let tracker_hash = match dict.find(&Key::from_str("tr")) {
+            Some(&ByteString(ref hash)) if hash.len() == 20 => Some(hash.clone()),
+            _ => None
+        };
+        Some(ExtendedHandshake { messages: messages, tracker_hash: tracker_hash })
+    }
+}
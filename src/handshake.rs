@@ -0,0 +1,143 @@
+//! The 68-byte handshake that opens every peer connection, and the
+//! capabilities peers advertise in its reserved bytes
+
+use std::io::{IoError, IoResult, MemWriter};
+
+
+pub static PROTOCOL: &'static [u8] = b"BitTorrent protocol";
+
+/// Length of the whole handshake: protocol string length and the protocol
+/// string itself, 8 reserved bytes, infohash and peer ID
+pub static HANDSHAKE_LENGTH: uint = 68;
+
+/// Reserved byte and bit of the extension protocol (BEP 10)
+static EXTENSIONS_BIT: (uint, u8) = (5, 0x10);
+/// Reserved byte and bit of the DHT port message (BEP 5)
+static DHT_BIT: (uint, u8) = (7, 0x01);
+/// Reserved byte and bit of the fast extension (BEP 6)
+static FAST_BIT: (uint, u8) = (7, 0x04);
+
+#[deriving(Show)]
+pub enum HandshakeError {
+    InvalidProtocol,
+    /// The infohash doesn't belong to a torrent we serve
+    UnknownInfoHash,
+    /// The peer ID is our own, we connected to ourselves
+    SelfConnection,
+    HandshakeIoError(IoError),
+}
+
+/// Protocol extensions a peer advertised
+#[deriving(Show, Clone, PartialEq)]
+pub struct Capabilities {
+    pub extensions: bool,
+    pub dht: bool,
+    pub fast: bool,
+}
+
+impl Capabilities {
+    /// Capabilities both sides have, which are the ones that may be used
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            extensions: self.extensions && other.extensions,
+            dht: self.dht && other.dht,
+            fast: self.fast && other.fast,
+        }
+    }
+}
+
+#[deriving(Clone)]
+pub struct Handshake {
+    pub reserved: [u8, ..8],
+    pub info_hash: [u8, ..20],
+    pub peer_id: [u8, ..20],
+}
+
+impl Handshake {
+    /// Handshake advertising `capabilities`
+    pub fn new(info_hash: &[u8], peer_id: &[u8], capabilities: &Capabilities) -> Handshake {
+        let mut handshake = Handshake {
+            reserved: [0u8, ..8],
+            info_hash: [0u8, ..20],
+            peer_id: [0u8, ..20],
+        };
+        handshake.info_hash.copy_from(info_hash);
+        handshake.peer_id.copy_from(peer_id);
+        handshake.set_bit(EXTENSIONS_BIT, capabilities.extensions);
+        handshake.set_bit(DHT_BIT, capabilities.dht);
+        handshake.set_bit(FAST_BIT, capabilities.fast);
+        handshake
+    }
+
+    fn set_bit(&mut self, (byte, bit): (uint, u8), value: bool) {
+        if value {
+            self.reserved[byte] |= bit;
+        } else {
+            self.reserved[byte] &= !bit;
+        }
+    }
+
+    fn bit(&self, (byte, bit): (uint, u8)) -> bool {
+        self.reserved[byte] & bit != 0
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            extensions: self.bit(EXTENSIONS_BIT),
+            dht: self.bit(DHT_BIT),
+            fast: self.bit(FAST_BIT),
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut Writer) -> IoResult<()> {
+        try!(writer.write_u8(PROTOCOL.len() as u8));
+        try!(writer.write(PROTOCOL));
+        try!(writer.write(self.reserved));
+        try!(writer.write(self.info_hash));
+        writer.write(self.peer_id)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = MemWriter::with_capacity(HANDSHAKE_LENGTH);
+        self.write_to(&mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    /// Decode a complete handshake. Only the protocol is checked here, the
+    /// infohash and peer ID are up to the `Client`.
+    pub fn decode(bytes: &[u8]) -> Result<Handshake, HandshakeError> {
+        if bytes.len() < HANDSHAKE_LENGTH || bytes[0] as uint != PROTOCOL.len() ||
+           bytes.slice(1, 20) != PROTOCOL {
+            return Err(InvalidProtocol);
+        }
+        let mut handshake = Handshake {
+            reserved: [0u8, ..8],
+            info_hash: [0u8, ..20],
+            peer_id: [0u8, ..20],
+        };
+        handshake.reserved.copy_from(bytes.slice(20, 28));
+        handshake.info_hash.copy_from(bytes.slice(28, 48));
+        handshake.peer_id.copy_from(bytes.slice(48, 68));
+        Ok(handshake)
+    }
+
+    pub fn read_from(reader: &mut Reader) -> Result<Handshake, HandshakeError> {
+        // check the protocol before waiting for the rest, whatever is on the
+        // other end might never send 68 bytes
+        let pstrlen = match reader.read_u8() {
+            Ok(pstrlen) => pstrlen,
+            Err(e) => return Err(HandshakeIoError(e))
+        };
+        if pstrlen as uint != PROTOCOL.len() {
+            return Err(InvalidProtocol);
+        }
+        match reader.read_exact(HANDSHAKE_LENGTH - 1) {
+            Ok(rest) => {
+                let mut bytes = vec![pstrlen];
+                bytes.push_all(rest.as_slice());
+                Handshake::decode(bytes.as_slice())
+            },
+            Err(e) => Err(HandshakeIoError(e))
+        }
+    }
+}
@@ -3,6 +3,9 @@ extern crate bencode;
 extern crate crypto = "rust-crypto";
 extern crate curl;
 extern crate url;
+extern crate time;
+extern crate libc;
+extern crate mio;
 
 use std::rand::{Rng, task_rng};
 
@@ -13,9 +16,31 @@ pub mod scrape;
 pub mod client;
 pub mod announce;
 pub mod peer;
+pub mod tracker;
+pub mod dht;
+pub mod tex;
+pub mod wire;
+pub mod handshake;
+pub mod extension;
+pub mod connection;
+pub mod bitfield;
+pub mod availability;
+pub mod picker;
+pub mod download;
+pub mod rate;
+pub mod smartban;
+pub mod storage;
+pub mod resume;
+pub mod choker;
+pub mod listener;
+pub mod engine;
 
 pub static CLIENT_VERSION: uint = 1;
 
+/// Port we tell trackers and peers we're listening on until the client
+/// binds its listen port
+pub static DEFAULT_PORT: u16 = 44000;
+
 fn opt_finder<T: FromBencode>(dict: &Dict, key: &str) -> Option<T> {
     match dict.find(&Key::from_str(key)) {
         Some(value) => FromBencode::from_bencode(value),
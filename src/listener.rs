@@ -0,0 +1,115 @@
+//! Accepting incoming peer connections
+//!
+//! The listen socket is non-blocking, so the engine can wait for it
+//! together with the peer connections; see `engine`.
+
+use std::io::{IoResult, IoError, OtherIoError, InvalidInput, ResourceUnavailable};
+use std::io::net::ip::{IpAddr, Ipv4Addr};
+use std::os;
+use std::rand::random;
+
+use libc::{EMFILE, ENFILE};
+
+use mio::{MioError, Ready, WouldBlock, IoAcceptor};
+use mio::net::{InetAddr, Socket};
+use mio::net::tcp::{TcpSocket, TcpAcceptor};
+
+
+/// Connections waiting to be accepted before the system refuses more
+static BACKLOG: uint = 128;
+
+/// Which port to listen on
+#[deriving(Show, Clone, PartialEq)]
+pub enum ListenPort {
+    Port(u16),
+    /// The first free port of an inclusive range
+    PortRange(u16, u16),
+    /// Any free port the system picks
+    RandomPort,
+}
+
+pub struct Listener {
+    acceptor: TcpAcceptor,
+    port: u16,
+}
+
+impl Listener {
+    /// Listen on `ip` and the port chosen by `port`
+    pub fn bind(ip: &str, port: ListenPort) -> IoResult<Listener> {
+        let ip: IpAddr = match from_str(ip) {
+            Some(ip) => ip,
+            None => return Err(IoError { kind: InvalidInput, desc: "invalid listen address", detail: Some(ip.to_str()) })
+        };
+        let ports: Vec<u16> = match port {
+            Port(port) => vec![port],
+            PortRange(first, last) => {
+                let (first, last) = (first.min(last), first.max(last));
+                // start at a random port of the range, so clients sharing a
+                // range don't all race for its first port
+                let count = (last - first) as uint + 1;
+                let start = random::<uint>() % count;
+                range(0, count).map(|i| first + ((start + i) % count) as u16).collect()
+            },
+            RandomPort => vec![0]
+        };
+        let mut error = None;
+        for &port in ports.iter() {
+            match listen(ip, port) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => error = Some(e)
+            }
+        }
+        Err(error.unwrap())
+    }
+
+    /// The port actually bound, which is what peers should be told
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The listen socket, for registering with an event loop
+    pub fn acceptor<'a>(&'a self) -> &'a TcpAcceptor {
+        &self.acceptor
+    }
+
+    /// The next incoming connection, non-blocking, `None` if there's none
+    /// waiting. Running out of file descriptors, ours or the whole
+    /// system's, is a `ResourceUnavailable` error.
+    pub fn accept(&mut self) -> IoResult<Option<TcpSocket>> {
+        match self.acceptor.accept() {
+            Ok(Ready(socket)) => Ok(Some(socket)),
+            Ok(WouldBlock) => Ok(None),
+            Err(e) => {
+                // read before anything else can overwrite it
+                let errno = os::errno();
+                let mut error = io_error(e);
+                if errno == EMFILE as int || errno == ENFILE as int {
+                    error.kind = ResourceUnavailable;
+                }
+                Err(error)
+            }
+        }
+    }
+}
+
+fn listen(ip: IpAddr, port: u16) -> IoResult<Listener> {
+    let socket = match ip {
+        Ipv4Addr(..) => TcpSocket::v4(),
+        _ => TcpSocket::v6()
+    };
+    let acceptor = try!(socket.and_then(|socket| socket.bind(&InetAddr(ip, port)))
+                              .and_then(|listener| listener.listen(BACKLOG))
+                              .map_err(io_error));
+    let port = match acceptor.getsockname() {
+        Ok(InetAddr(_, port)) => port,
+        Ok(_) => port,
+        Err(e) => return Err(io_error(e))
+    };
+    Ok(Listener { acceptor: acceptor, port: port })
+}
+
+/// Report an error of the event library like any other I/O error
+pub fn io_error(error: MioError) -> IoError {
+    IoError { kind: OtherIoError, desc: "socket error", detail: Some(format!("{}", error)) }
+}
+
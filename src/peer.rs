@@ -3,12 +3,14 @@ extern crate bencode;
 use std::io::net::ip::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::from_utf8;
 use std::fmt::{Show, Formatter, FormatError};
+use std::collections::TreeMap;
 
-use bencode::{Bencode, FromBencode, Dict, Key, ByteString};
+use bencode::{Bencode, FromBencode, ToBencode, Dict, Key, ByteString, Number};
 
 use super::opt_finder;
 
 
+#[deriving(Clone)]
 pub struct Peer {
     pub address: SocketAddr,
     pub peer_id: Option<[u8, ..20]>,
@@ -32,11 +34,11 @@ impl Peer {
         }
     }
     /// Same as from_6byte, except for IPv6 peers
-    pub fn from_18byte(bytes: [u8, ..18]) -> Peer {
+    pub fn from_18byte(bytes: &[u8, ..18]) -> Peer {
         use std::iter::count;
         let mut dbytes = [0u16, ..9]; 
         for i in count(0u, 2).take(9) {
-            dbytes[i] = dbyte(bytes[i], bytes[i+1]);
+            dbytes[i / 2] = dbyte(bytes[i], bytes[i+1]);
         }
         Peer {
             address: SocketAddr {
@@ -47,6 +49,45 @@ impl Peer {
             peer_id: None
         }
     }
+
+    /// Compact 6-byte representation of an IPv4 peer, `None` for IPv6 peers
+    pub fn to_6byte(&self) -> Option<[u8, ..6]> {
+        match self.address.ip {
+            Ipv4Addr(a, b, c, d) => {
+                let port = self.address.port;
+                Some([a, b, c, d, (port >> 8) as u8, port as u8])
+            },
+            _ => None
+        }
+    }
+
+    /// Compact 18-byte representation of an IPv6 peer, `None` for IPv4 peers
+    pub fn to_18byte(&self) -> Option<[u8, ..18]> {
+        match self.address.ip {
+            Ipv6Addr(a, b, c, d, e, f, g, h) => {
+                let mut bytes = [0u8, ..18];
+                for (i, &dbyte) in [a, b, c, d, e, f, g, h, self.address.port].iter().enumerate() {
+                    bytes[i * 2] = (dbyte >> 8) as u8;
+                    bytes[i * 2 + 1] = dbyte as u8;
+                }
+                Some(bytes)
+            },
+            _ => None
+        }
+    }
+}
+
+impl ToBencode for Peer {
+    fn to_bencode(&self) -> Bencode {
+        let mut dict = TreeMap::new();
+        dict.insert(Key::from_str("ip"), ByteString(Vec::from_slice(self.address.ip.to_str().as_bytes())));
+        dict.insert(Key::from_str("port"), Number(self.address.port as i64));
+        match self.peer_id {
+            Some(ref peer_id) => { dict.insert(Key::from_str("peer id"), ByteString(Vec::from_slice(peer_id.as_slice()))); },
+            None => ()
+        }
+        Dict(dict)
+    }
 }
 
 impl FromBencode for Peer {
@@ -62,7 +103,9 @@ impl FromBencode for Peer {
                         port: opt_finder(dict, "port").expect("invalid peer port")
                     },
                     peer_id: {
-                        match dict.find(&Key::from_str("peer_id")) {
+                        // BEP 3 says "peer id", but some trackers send "peer_id"
+                        let peer_id = dict.find(&Key::from_str("peer id")).or_else(|| dict.find(&Key::from_str("peer_id")));
+                        match peer_id {
                             Some(&ByteString(ref vector)) => {
                                 let mut vec = [0u8, ..20];
                                 for i in range(0, 20) {
@@ -0,0 +1,123 @@
+//! Choosing which piece to download next
+
+use std::rand::{Rng, task_rng};
+
+use availability::PieceAvailability;
+use bitfield::Bitfield;
+
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum PickStrategy {
+    /// Pieces the fewest peers have first, which keeps the swarm healthy
+    RarestFirst,
+    /// Pieces in index order, for streaming
+    Sequential,
+}
+
+/// Pieces with this priority aren't downloaded at all
+pub static PRIORITY_SKIP: u8 = 0;
+pub static PRIORITY_NORMAL: u8 = 4;
+pub static PRIORITY_HIGHEST: u8 = 7;
+
+/// Until we have this many pieces, rarest first picks at random instead, so
+/// we get something to share as soon as possible
+pub static RANDOM_FIRST_PIECES: uint = 4;
+
+pub struct PiecePicker {
+    pub strategy: PickStrategy,
+    priorities: Vec<u8>,
+    /// Pieces being downloaded, oldest first
+    partial: Vec<uint>,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: uint, strategy: PickStrategy) -> PiecePicker {
+        PiecePicker {
+            strategy: strategy,
+            priorities: Vec::from_elem(num_pieces, PRIORITY_NORMAL),
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn priority(&self, index: uint) -> u8 {
+        *self.priorities.get(index)
+    }
+
+    /// Set the priority of piece `index`, from `PRIORITY_SKIP` to
+    /// `PRIORITY_HIGHEST`. Higher priority pieces are always picked first,
+    /// whatever the strategy.
+    pub fn set_priority(&mut self, index: uint, priority: u8) {
+        *self.priorities.get_mut(index) = priority.min(PRIORITY_HIGHEST);
+    }
+
+    /// Mark piece `index` as being downloaded
+    pub fn piece_started(&mut self, index: uint) {
+        if !self.partial.contains(&index) {
+            self.partial.push(index);
+        }
+    }
+
+    /// Piece `index` is no longer being downloaded, either because it's done
+    /// or because it was given up on
+    pub fn piece_stopped(&mut self, index: uint) {
+        match self.partial.iter().position(|&partial| partial == index) {
+            Some(position) => { self.partial.remove(position); },
+            None => ()
+        }
+    }
+
+    pub fn is_partial(&self, index: uint) -> bool {
+        self.partial.contains(&index)
+    }
+
+    /// Pieces being downloaded, oldest first
+    pub fn partial_pieces<'a>(&'a self) -> &'a [uint] {
+        self.partial.as_slice()
+    }
+
+    /// Next piece to download from a peer having `peer_has`, `None` if it
+    /// has nothing we want. Pieces already being downloaded come first, so
+    /// they're finished before new ones are started.
+    pub fn pick(&self, have: &Bitfield, peer_has: &Bitfield, availability: &PieceAvailability) -> Option<uint> {
+        let wanted = |index: uint| !have.get(index) && peer_has.get(index) && self.priority(index) != PRIORITY_SKIP;
+
+        let partial = self.partial.iter()
+            .map(|&index| index)
+            .filter(|&index| wanted(index))
+            .fold(None, |best: Option<uint>, index| match best {
+                Some(best) if self.priority(best) >= self.priority(index) => Some(best),
+                _ => Some(index)
+            });
+        if partial.is_some() {
+            return partial;
+        }
+        self.pick_new(have, peer_has, availability)
+    }
+
+    /// Like `pick`, but only considers pieces that aren't being downloaded
+    /// yet
+    pub fn pick_new(&self, have: &Bitfield, peer_has: &Bitfield, availability: &PieceAvailability) -> Option<uint> {
+        let wanted = |index: uint| {
+            !have.get(index) && peer_has.get(index) && self.priority(index) != PRIORITY_SKIP && !self.is_partial(index)
+        };
+        let candidates: Vec<uint> = range(0, have.len()).filter(|&index| wanted(index)).collect();
+        let top_priority = match candidates.iter().map(|&index| self.priority(index)).max() {
+            Some(priority) => priority,
+            None => return None
+        };
+        let candidates: Vec<uint> = candidates.move_iter().filter(|&index| self.priority(index) == top_priority).collect();
+
+        match self.strategy {
+            Sequential => candidates.iter().map(|&index| index).min(),
+            RarestFirst if have.count() < RANDOM_FIRST_PIECES => {
+                Some(*task_rng().choose(candidates.as_slice()).unwrap())
+            },
+            RarestFirst => {
+                let rarest = candidates.iter().map(|&index| availability.get(index)).min().unwrap();
+                let rarest: Vec<uint> = candidates.move_iter().filter(|&index| availability.get(index) == rarest).collect();
+                // break ties at random so peers don't all go for the same piece
+                Some(*task_rng().choose(rarest.as_slice()).unwrap())
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Transfer rates averaged over the last few seconds
+
+/// How often the average is updated
+static RATE_INTERVAL: u64 = 1_000_000_000;
+/// Weight of the newest sample in the average
+static RATE_WEIGHT: f64 = 0.25;
+
+/// Bytes per second, updated from `tick` with the same nanosecond clock the
+/// connection uses
+#[deriving(Show, Clone)]
+pub struct TransferRate {
+    rate: f64,
+    /// Bytes transferred since the last update
+    pending: uint,
+    last_update: u64,
+    /// Bytes transferred in total
+    pub total: uint,
+}
+
+impl TransferRate {
+    pub fn new(now: u64) -> TransferRate {
+        TransferRate { rate: 0.0, pending: 0, last_update: now, total: 0 }
+    }
+
+    pub fn add(&mut self, bytes: uint) {
+        self.pending += bytes;
+        self.total += bytes;
+    }
+
+    /// Fold the bytes transferred since the last update into the average,
+    /// at most once every `RATE_INTERVAL`
+    pub fn update(&mut self, now: u64) {
+        let elapsed = now - self.last_update;
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+        let sample = self.pending as f64 / (elapsed as f64 / 1_000_000_000f64);
+        self.rate = self.rate * (1.0 - RATE_WEIGHT) + sample * RATE_WEIGHT;
+        self.pending = 0;
+        self.last_update = now;
+    }
+
+    /// Average in bytes per second
+    pub fn get(&self) -> f64 {
+        self.rate
+    }
+}
@@ -0,0 +1,236 @@
+//! Fast-resume data, saved per torrent so restarting doesn't take a full
+//! recheck.
+//!
+//! The data is a bencoded dictionary:
+//!
+//! * `file-format`: the string `tensai resume file`
+//! * `file-version`: 1
+//! * `info-hash`: the 20-byte infohash of the torrent
+//! * `pieces`: the bitfield of pieces we have, as in the `bitfield` message
+//! * `partial`: a list of dictionaries, one per piece being downloaded,
+//!   with `piece`, its index, and `blocks`, a bitfield of the blocks
+//!   already stored
+//! * `files`: a list with a dictionary per file of the torrent, holding
+//!   `size` and `mtime` in milliseconds when the resume data was saved; the
+//!   dictionary is empty for files that didn't exist
+//! * `uploaded`, `downloaded`, `duplicate`: the `TrafficInfo` counters
+//! * `trackers`: a list of dictionaries with each tracker's `url`, `tier`,
+//!   `failures`, `disabled` (0 or 1) and, when known, `tracker id`,
+//!   `last announce` and `next announce` in seconds since the epoch,
+//!   `seeders`, `leechers` and `downloaded`
+//! * `peers` and `peers6`: known peers in the compact format of tracker
+//!   responses
+//!
+//! The pieces are only trusted when the size and mtime of every file still
+//! match, otherwise the torrent is checked again.
+
+use std::collections::TreeMap;
+
+use bencode;
+use bencode::{Bencode, FromBencode, ToBencode, Dict, List, Key, ByteString, Number};
+use time::Timespec;
+
+use peer::Peer;
+use torrent::TrackerStatus;
+
+
+static FILE_FORMAT: &'static str = "tensai resume file";
+static FILE_VERSION: i64 = 1;
+
+#[deriving(Clone)]
+pub struct ResumeData {
+    pub info_hash: Vec<u8>,
+    /// Bitfield of the pieces we have
+    pub pieces: Vec<u8>,
+    /// Index and bitfield of stored blocks of every piece being downloaded
+    pub partial: Vec<(uint, Vec<u8>)>,
+    /// Size and mtime of every file, see `Storage::file_stats`
+    pub files: Vec<Option<(u64, u64)>>,
+    pub uploaded: uint,
+    pub downloaded: uint,
+    pub duplicate: uint,
+    pub trackers: Vec<TrackerStatus>,
+    pub peers: Vec<Peer>,
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bencode().to_bytes().unwrap()
+    }
+
+    /// Parse resume data, `None` if it's malformed or of another version
+    pub fn decode(data: &[u8]) -> Option<ResumeData> {
+        bencode::from_vec(Vec::from_slice(data)).ok().and_then(|bencode| FromBencode::from_bencode(&bencode))
+    }
+}
+
+fn key(key: &str) -> Key {
+    Key::from_str(key)
+}
+
+fn bytes(value: &[u8]) -> Bencode {
+    ByteString(Vec::from_slice(value))
+}
+
+fn number(dict: &TreeMap<Key, Bencode>, name: &str) -> Option<i64> {
+    match dict.find(&key(name)) {
+        Some(&Number(value)) => Some(value),
+        _ => None
+    }
+}
+
+fn byte_string<'a>(dict: &'a TreeMap<Key, Bencode>, name: &str) -> Option<&'a [u8]> {
+    match dict.find(&key(name)) {
+        Some(&ByteString(ref value)) => Some(value.as_slice()),
+        _ => None
+    }
+}
+
+fn list<'a>(dict: &'a TreeMap<Key, Bencode>, name: &str) -> Option<&'a [Bencode]> {
+    match dict.find(&key(name)) {
+        Some(&List(ref value)) => Some(value.as_slice()),
+        _ => None
+    }
+}
+
+fn encode_tracker(tracker: &TrackerStatus) -> Bencode {
+    let mut dict = TreeMap::new();
+    dict.insert(key("url"), bytes(tracker.url.as_bytes()));
+    dict.insert(key("tier"), Number(tracker.tier as i64));
+    dict.insert(key("failures"), Number(tracker.failures as i64));
+    dict.insert(key("disabled"), Number(if tracker.disabled { 1 } else { 0 }));
+    match tracker.tracker_id {
+        Some(ref id) => { dict.insert(key("tracker id"), bytes(id.as_bytes())); },
+        None => ()
+    }
+    let optional = [("last announce", tracker.last_announce.map(|time| time.sec)),
+                    ("next announce", tracker.next_announce.map(|time| time.sec)),
+                    ("seeders", tracker.seeders.map(|count| count as i64)),
+                    ("leechers", tracker.leechers.map(|count| count as i64)),
+                    ("downloaded", tracker.downloaded.map(|count| count as i64))];
+    for &(name, value) in optional.iter() {
+        match value {
+            Some(value) => { dict.insert(key(name), Number(value)); },
+            None => ()
+        }
+    }
+    Dict(dict)
+}
+
+fn decode_tracker(bencode: &Bencode) -> Option<TrackerStatus> {
+    let dict = match *bencode { Dict(ref dict) => dict, _ => return None };
+    let url = match byte_string(dict, "url").and_then(|url| String::from_utf8(Vec::from_slice(url)).ok()) {
+        Some(url) => url,
+        None => return None
+    };
+    let mut tracker = TrackerStatus::new(url, number(dict, "tier").unwrap_or(0) as uint);
+    tracker.failures = number(dict, "failures").unwrap_or(0) as uint;
+    tracker.disabled = number(dict, "disabled").unwrap_or(0) != 0;
+    tracker.tracker_id = byte_string(dict, "tracker id").and_then(|id| String::from_utf8(Vec::from_slice(id)).ok());
+    tracker.last_announce = number(dict, "last announce").map(|sec| Timespec::new(sec, 0));
+    tracker.next_announce = number(dict, "next announce").map(|sec| Timespec::new(sec, 0));
+    tracker.seeders = number(dict, "seeders").map(|count| count as uint);
+    tracker.leechers = number(dict, "leechers").map(|count| count as uint);
+    tracker.downloaded = number(dict, "downloaded").map(|count| count as uint);
+    Some(tracker)
+}
+
+impl ToBencode for ResumeData {
+    fn to_bencode(&self) -> Bencode {
+        let mut dict = TreeMap::new();
+        dict.insert(key("file-format"), bytes(FILE_FORMAT.as_bytes()));
+        dict.insert(key("file-version"), Number(FILE_VERSION));
+        dict.insert(key("info-hash"), bytes(self.info_hash.as_slice()));
+        dict.insert(key("pieces"), bytes(self.pieces.as_slice()));
+        dict.insert(key("partial"), List(self.partial.iter().map(|&(piece, ref blocks)| {
+            let mut partial = TreeMap::new();
+            partial.insert(key("piece"), Number(piece as i64));
+            partial.insert(key("blocks"), bytes(blocks.as_slice()));
+            Dict(partial)
+        }).collect()));
+        dict.insert(key("files"), List(self.files.iter().map(|stats| {
+            let mut file = TreeMap::new();
+            match *stats {
+                Some((size, mtime)) => {
+                    file.insert(key("size"), Number(size as i64));
+                    file.insert(key("mtime"), Number(mtime as i64));
+                },
+                None => ()
+            }
+            Dict(file)
+        }).collect()));
+        dict.insert(key("uploaded"), Number(self.uploaded as i64));
+        dict.insert(key("downloaded"), Number(self.downloaded as i64));
+        dict.insert(key("duplicate"), Number(self.duplicate as i64));
+        dict.insert(key("trackers"), List(self.trackers.iter().map(encode_tracker).collect()));
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for peer in self.peers.iter() {
+            match peer.to_6byte() {
+                Some(compact) => peers.push_all(compact),
+                None => ()
+            }
+            match peer.to_18byte() {
+                Some(compact) => peers6.push_all(compact),
+                None => ()
+            }
+        }
+        dict.insert(key("peers"), ByteString(peers));
+        dict.insert(key("peers6"), ByteString(peers6));
+        Dict(dict)
+    }
+}
+
+impl FromBencode for ResumeData {
+    fn from_bencode(bencode: &Bencode) -> Option<ResumeData> {
+        let dict = match *bencode { Dict(ref dict) => dict, _ => return None };
+        if byte_string(dict, "file-format") != Some(FILE_FORMAT.as_bytes()) || number(dict, "file-version") != Some(FILE_VERSION) {
+            return None;
+        }
+        let (info_hash, pieces) = match (byte_string(dict, "info-hash"), byte_string(dict, "pieces")) {
+            (Some(info_hash), Some(pieces)) => (Vec::from_slice(info_hash), Vec::from_slice(pieces)),
+            _ => return None
+        };
+        let partial = list(dict, "partial").unwrap_or(&[]).iter().filter_map(|partial| match *partial {
+            Dict(ref partial) => match (number(partial, "piece"), byte_string(partial, "blocks")) {
+                (Some(piece), Some(blocks)) => Some((piece as uint, Vec::from_slice(blocks))),
+                _ => None
+            },
+            _ => None
+        }).collect();
+        let files = list(dict, "files").unwrap_or(&[]).iter().map(|file| match *file {
+            Dict(ref file) => match (number(file, "size"), number(file, "mtime")) {
+                (Some(size), Some(mtime)) => Some((size as u64, mtime as u64)),
+                _ => None
+            },
+            _ => None
+        }).collect();
+        let trackers = list(dict, "trackers").unwrap_or(&[]).iter().filter_map(decode_tracker).collect();
+        let mut peers = Vec::new();
+        for compact in byte_string(dict, "peers").unwrap_or(&[]).chunks(6) {
+            if compact.len() == 6 {
+                let mut bytes = [0u8, ..6];
+                bytes.copy_from(compact);
+                peers.push(Peer::from_6byte(&bytes));
+            }
+        }
+        for compact in byte_string(dict, "peers6").unwrap_or(&[]).chunks(18) {
+            if compact.len() == 18 {
+                let mut bytes = [0u8, ..18];
+                bytes.copy_from(compact);
+                peers.push(Peer::from_18byte(&bytes));
+            }
+        }
+        Some(ResumeData {
+            info_hash: info_hash,
+            pieces: pieces,
+            partial: partial,
+            files: files,
+            uploaded: number(dict, "uploaded").unwrap_or(0) as uint,
+            downloaded: number(dict, "downloaded").unwrap_or(0) as uint,
+            duplicate: number(dict, "duplicate").unwrap_or(0) as uint,
+            trackers: trackers,
+            peers: peers,
+        })
+    }
+}
@@ -1,8 +1,9 @@
 extern crate bencode;
 
 use std::collections::hashmap::HashMap;
+use std::collections::TreeMap;
 
-use bencode::{FromBencode, Dict, Key, Bencode};
+use bencode::{FromBencode, ToBencode, Dict, Key, Bencode, ByteString, Number};
 
 use super::opt_finder;
 
@@ -49,10 +50,36 @@ impl FromBencode for TorrentScrape {
                     complete: opt_finder(dict, "complete").expect("Invalid 'complete' number in TorrentScrape"),
                     downloaded: opt_finder(dict, "downloaded").expect("Invalid 'downloaded' number in TorrentScrape"),
                     incomplete: opt_finder(dict, "incomplete").expect("Invalid 'incomplete' number in TorrentScrape"),
-                    name: opt_finder(dict, "name").expect("Invalid 'name' in TorrentScrape")
+                    name: opt_finder(dict, "name")
                 })
             },
             _ => None
         }
     }
 }
+
+impl ToBencode for ScrapeInfo {
+    fn to_bencode(&self) -> Bencode {
+        let mut files = TreeMap::new();
+        for (infohash, scrape) in self.torrents.iter() {
+            files.insert(Key::from_slice(infohash.as_slice()), scrape.to_bencode());
+        }
+        let mut dict = TreeMap::new();
+        dict.insert(Key::from_str("files"), Dict(files));
+        Dict(dict)
+    }
+}
+
+impl ToBencode for TorrentScrape {
+    fn to_bencode(&self) -> Bencode {
+        let mut dict = TreeMap::new();
+        dict.insert(Key::from_str("complete"), Number(self.complete as i64));
+        dict.insert(Key::from_str("downloaded"), Number(self.downloaded as i64));
+        dict.insert(Key::from_str("incomplete"), Number(self.incomplete as i64));
+        match self.name {
+            Some(ref name) => { dict.insert(Key::from_str("name"), ByteString(Vec::from_slice(name.as_bytes()))); },
+            None => ()
+        }
+        Dict(dict)
+    }
+}
@@ -0,0 +1,76 @@
+//! Finding the peers that sent corrupt blocks
+//!
+//! A piece failing its hash check doesn't say which block was bad. So the
+//! hash of every block of a failed piece is remembered along with the peer
+//! that sent it. Once the piece has been downloaded correctly, each
+//! remembered block is compared with the good one, and the peers whose
+//! blocks differ are banned by IP.
+
+use std::collections::hashmap::{HashMap, HashSet};
+use std::io::net::ip::IpAddr;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use connection::BlockRequest;
+
+
+struct BlockRecord {
+    block: BlockRequest,
+    peer: IpAddr,
+    hash: [u8, ..20],
+}
+
+pub struct SmartBan {
+    /// Blocks of the pieces that failed verification, by piece index
+    records: HashMap<uint, Vec<BlockRecord>>,
+    banned: HashSet<IpAddr>,
+}
+
+fn block_hash(block: &BlockRequest, piece: &[u8]) -> [u8, ..20] {
+    let begin = block.begin as uint;
+    let mut hasher = Sha1::new();
+    hasher.input(piece.slice(begin, begin + block.length as uint));
+    let mut hash = [0u8, ..20];
+    hasher.result(hash);
+    hash
+}
+
+impl SmartBan {
+    pub fn new() -> SmartBan {
+        SmartBan { records: HashMap::new(), banned: HashSet::new() }
+    }
+
+    /// Piece `index` failed verification: remember who sent which of its
+    /// `blocks`, as found in `piece`
+    pub fn piece_failed(&mut self, index: uint, blocks: &[(BlockRequest, IpAddr)], piece: &[u8]) {
+        let records = self.records.find_or_insert_with(index, |_| Vec::new());
+        for &(ref block, peer) in blocks.iter() {
+            records.push(BlockRecord { block: block.clone(), peer: peer, hash: block_hash(block, piece) });
+        }
+    }
+
+    /// Piece `index` passed verification with the data in `piece`. Every
+    /// peer that once sent a different block for it is banned, and returned
+    /// so its connections can be dropped.
+    pub fn piece_passed(&mut self, index: uint, piece: &[u8]) -> Vec<IpAddr> {
+        let records = match self.records.pop(&index) {
+            Some(records) => records,
+            None => return Vec::new()
+        };
+        let mut banned = Vec::new();
+        for record in records.iter() {
+            if record.hash != block_hash(&record.block, piece) && !banned.contains(&record.peer) {
+                banned.push(record.peer);
+            }
+        }
+        for peer in banned.iter() {
+            self.banned.insert(*peer);
+        }
+        banned
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+}
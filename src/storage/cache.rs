@@ -0,0 +1,337 @@
+//! Caching pieces read from storage, for seeding.
+//!
+//! Peers downloading from us ask for the blocks of a piece one after
+//! another, and the pieces of a popular torrent are asked for by many
+//! peers. A `CachedStorage` reads whole pieces for them from the storage it
+//! wraps and keeps the most recently used ones in memory. When the piece
+//! before the one read was read recently, someone is going through the
+//! torrent in order, and the pieces after it are read ahead.
+//!
+//! Only blocks read with `read_for_peer` are cached; hashing and other
+//! reads of our own go straight to the storage. The cached pieces of every
+//! torrent share one `PieceCache`, so its size is a budget for the whole
+//! client, and the least recently used piece of any torrent makes room
+//! first.
+
+use std::collections::{RingBuf, Deque};
+use std::collections::hashmap::HashMap;
+use std::sync::{Arc, Mutex};
+
+use torrent::TorrentInfo;
+use super::{Storage, StorageResult, FileLayout, BlockOutOfRange};
+
+
+/// Bytes of pieces cached over every torrent, see `Client::set_cache_size`
+pub static DEFAULT_CACHE_SIZE: uint = 16 * 1024 * 1024;
+/// Pieces read ahead for sequential readers
+pub static DEFAULT_READ_AHEAD: uint = 2;
+/// Pieces remembered as read recently, to spot sequential readers
+static RECENT_PIECES: uint = 32;
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct CacheStats {
+    /// Blocks read from cached pieces
+    pub hits: uint,
+    /// Blocks whose piece had to be read from storage
+    pub misses: uint,
+    /// Pieces read ahead of the peers asking for them
+    pub read_ahead: uint,
+}
+
+struct Pieces {
+    /// Bytes of pieces kept at most
+    capacity: uint,
+    /// Bytes of the cached pieces
+    size: uint,
+    /// Data of the cached pieces by storage id and index, with the tick of
+    /// their last use
+    pieces: HashMap<(uint, uint), (Vec<u8>, uint)>,
+    /// Storage id, index and tick of every use of a piece, least recent
+    /// first. Only a piece's last use counts, the ones before are stale
+    /// and skipped.
+    recency: RingBuf<(uint, uint, uint)>,
+    tick: uint,
+    next_id: uint,
+}
+
+impl Pieces {
+    fn contains(&self, id: uint, index: uint) -> bool {
+        self.pieces.contains_key(&(id, index))
+    }
+
+    /// Piece `index` of storage `id`, if it's cached, making it the most
+    /// recently used
+    fn touch<'a>(&'a mut self, id: uint, index: uint) -> Option<&'a Vec<u8>> {
+        if !self.contains(id, index) {
+            return None;
+        }
+        self.mark_used(id, index);
+        self.pieces.find(&(id, index)).map(|&(ref data, _)| data)
+    }
+
+    fn mark_used(&mut self, id: uint, index: uint) {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.pieces.find_mut(&(id, index)) {
+            Some(&(_, ref mut used)) => *used = tick,
+            None => return
+        }
+        self.recency.push_back((id, index, tick));
+        if self.recency.len() > 2 * self.pieces.len() {
+            self.compact();
+        }
+    }
+
+    /// Drop the stale uses from `recency`
+    fn compact(&mut self) {
+        let pieces = &self.pieces;
+        let recency: RingBuf<(uint, uint, uint)> = self.recency.iter()
+            .filter(|&&(id, index, tick)| pieces.find(&(id, index)).map_or(false, |&(_, used)| used == tick))
+            .map(|used| *used)
+            .collect();
+        self.recency = recency;
+    }
+
+    fn insert(&mut self, id: uint, index: uint, data: Vec<u8>) {
+        self.remove(id, index);
+        self.size += data.len();
+        self.pieces.insert((id, index), (data, 0));
+        self.mark_used(id, index);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            let (id, index, tick) = match self.recency.pop_front() {
+                Some(used) => used,
+                None => break
+            };
+            let last_use = self.pieces.find(&(id, index)).map_or(false, |&(_, used)| used == tick);
+            if last_use {
+                self.remove(id, index);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: uint, index: uint) {
+        match self.pieces.pop(&(id, index)) {
+            Some((data, _)) => self.size -= data.len(),
+            None => ()
+        }
+    }
+
+    fn remove_all(&mut self, id: uint) {
+        let keys: Vec<(uint, uint)> = self.pieces.keys().filter(|&&(owner, _)| owner == id).map(|key| *key).collect();
+        for &(_, index) in keys.iter() {
+            self.remove(id, index);
+        }
+    }
+
+    /// Bytes of the cached pieces of storage `id`
+    fn size_of(&self, id: uint) -> uint {
+        self.pieces.iter().filter(|&(&(owner, _), _)| owner == id).map(|(_, &(ref data, _))| data.len()).fold(0, |a, b| a + b)
+    }
+}
+
+/// Memory for the pieces of any number of `CachedStorage`s, shared by
+/// cloning it
+#[deriving(Clone)]
+pub struct PieceCache {
+    pieces: Arc<Mutex<Pieces>>,
+}
+
+impl PieceCache {
+    /// A cache of up to `capacity` bytes of pieces
+    pub fn new(capacity: uint) -> PieceCache {
+        PieceCache {
+            pieces: Arc::new(Mutex::new(Pieces {
+                capacity: capacity,
+                size: 0,
+                pieces: HashMap::new(),
+                recency: RingBuf::new(),
+                tick: 0,
+                next_id: 0,
+            }))
+        }
+    }
+
+    pub fn capacity(&self) -> uint {
+        self.pieces.lock().capacity
+    }
+
+    /// Change the size of the cache, dropping the least recently used
+    /// pieces that no longer fit. 0 turns caching off.
+    pub fn set_capacity(&self, capacity: uint) {
+        let mut pieces = self.pieces.lock();
+        pieces.capacity = capacity;
+        pieces.evict();
+    }
+
+    /// Bytes of the pieces cached now
+    pub fn size(&self) -> uint {
+        self.pieces.lock().size
+    }
+
+    fn register(&self) -> uint {
+        let mut pieces = self.pieces.lock();
+        pieces.next_id += 1;
+        pieces.next_id
+    }
+}
+
+pub struct CachedStorage {
+    storage: Box<Storage>,
+    layout: FileLayout,
+    cache: PieceCache,
+    /// This storage's pieces in `cache`
+    id: uint,
+    /// Pieces read lately, oldest first
+    recent: Vec<uint>,
+    /// Pieces read ahead for sequential readers
+    pub read_ahead: uint,
+    stats: CacheStats,
+}
+
+impl CachedStorage {
+    /// Cache the pieces of `info` that peers read from `storage` in `cache`
+    pub fn new(info: &TorrentInfo, storage: Box<Storage>, cache: &PieceCache) -> CachedStorage {
+        CachedStorage {
+            storage: storage,
+            layout: FileLayout::new(info),
+            cache: cache.clone(),
+            id: cache.register(),
+            recent: Vec::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            stats: CacheStats { hits: 0, misses: 0, read_ahead: 0 },
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.clone()
+    }
+
+    /// Bytes of this storage's pieces cached now
+    pub fn size(&self) -> uint {
+        self.cache.pieces.lock().size_of(self.id)
+    }
+
+    fn num_pieces(&self) -> uint {
+        (self.layout.total_length + self.layout.piece_length - 1) / self.layout.piece_length
+    }
+
+    fn piece_size(&self, index: uint) -> uint {
+        self.layout.piece_length.min(self.layout.total_length - index * self.layout.piece_length)
+    }
+
+    /// `length` bytes at `begin` of piece `index`, if it's cached, making
+    /// it the most recently used
+    fn cached_block(&self, index: uint, begin: uint, length: uint) -> Option<Vec<u8>> {
+        let mut pieces = self.cache.pieces.lock();
+        pieces.touch(self.id, index).map(|data| Vec::from_slice(data.slice(begin, begin + length)))
+    }
+
+    /// Read piece `index` from storage into the cache, returning `length`
+    /// bytes of it at `begin`
+    fn load(&mut self, index: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        let data = try!(self.storage.read_block(index, 0, self.piece_size(index)));
+        let block = Vec::from_slice(data.slice(begin, begin + length));
+        self.cache.pieces.lock().insert(self.id, index, data);
+        Ok(block)
+    }
+
+    /// Read the pieces after `index` that aren't cached. Pieces that can't
+    /// be read are left to be asked for.
+    fn read_ahead_of(&mut self, index: uint) {
+        for next in range(index + 1, (index + 1 + self.read_ahead).min(self.num_pieces())) {
+            if self.cache.pieces.lock().contains(self.id, next) {
+                continue;
+            }
+            if self.load(next, 0, 0).is_err() {
+                break;
+            }
+            self.stats.read_ahead += 1;
+        }
+    }
+}
+
+impl Drop for CachedStorage {
+    fn drop(&mut self) {
+        self.cache.pieces.lock().remove_all(self.id);
+    }
+}
+
+impl Storage for CachedStorage {
+    /// Blocks of cached pieces come from the cache, the rest from storage
+    /// without caching them
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        if self.layout.payload_offset(piece, begin, length).is_none() {
+            return Err(BlockOutOfRange);
+        }
+        match self.cached_block(piece, begin, length) {
+            Some(block) => Ok(block),
+            None => self.storage.read_block(piece, begin, length)
+        }
+    }
+
+    fn read_for_peer(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        if self.layout.payload_offset(piece, begin, length).is_none() {
+            return Err(BlockOutOfRange);
+        }
+        // pieces bigger than the whole cache are read as they're asked for
+        if self.piece_size(piece) > self.cache.capacity() {
+            self.stats.misses += 1;
+            return self.storage.read_block(piece, begin, length);
+        }
+        let block = match self.cached_block(piece, begin, length) {
+            Some(block) => {
+                self.stats.hits += 1;
+                block
+            },
+            None => {
+                self.stats.misses += 1;
+                try!(self.load(piece, begin, length))
+            }
+        };
+        let sequential = piece > 0 && self.recent.contains(&(piece - 1));
+        if !self.recent.contains(&piece) {
+            if self.recent.len() >= RECENT_PIECES {
+                self.recent.remove(0);
+            }
+            self.recent.push(piece);
+        }
+        if sequential {
+            self.read_ahead_of(piece);
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()> {
+        self.cache.pieces.lock().remove(self.id, piece);
+        self.storage.write_block(piece, begin, data)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.storage.flush()
+    }
+
+    fn exists(&self) -> bool {
+        self.storage.exists()
+    }
+
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>> {
+        self.storage.file_stats()
+    }
+
+    fn move_to(&mut self, destination: &Path) -> StorageResult<()> {
+        self.storage.move_to(destination)
+    }
+
+    fn delete(&mut self) -> StorageResult<()> {
+        self.cache.pieces.lock().remove_all(self.id);
+        self.storage.delete()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.stats())
+    }
+}
@@ -0,0 +1,319 @@
+//! Storage in the torrent's files under a destination directory
+
+use std::io::{File, Open, ReadWrite, SeekSet, UserRWX};
+use std::io::fs;
+
+use torrent::TorrentInfo;
+use super::{Storage, StorageResult, FileLayout, MissingData, InsufficientSpace, io_result, write_result};
+
+
+/// Files kept open at once; the least recently used one is closed to make
+/// room for another
+pub static MAX_OPEN_FILES: uint = 32;
+
+/// How the files are created when a torrent is added
+#[deriving(Show, Clone, PartialEq)]
+pub enum Allocation {
+    /// At full length, without allocating space for the data; the space is
+    /// taken as blocks are written
+    Sparse,
+    /// At full length with all of the space allocated up front, so the disk
+    /// can't fill up halfway through the download
+    Full,
+    /// Not until the first block is written to them
+    Lazy,
+}
+
+#[deriving(Show, Clone)]
+pub struct DiskOptions {
+    pub allocation: Allocation,
+    /// Throw away data found in existing files. Without it, existing files
+    /// are only ever extended to their full length.
+    pub overwrite: bool,
+}
+
+impl DiskOptions {
+    pub fn new() -> DiskOptions {
+        DiskOptions { allocation: Sparse, overwrite: false }
+    }
+}
+
+pub struct DiskStorage {
+    root: Path,
+    layout: FileLayout,
+    /// Open files by layout index, least recently used first
+    open: Vec<(uint, File)>,
+}
+
+impl DiskStorage {
+    /// Storage for the files of `info` under the directory `root`. Files
+    /// and their directories are created as blocks are written to them.
+    pub fn new(info: &TorrentInfo, root: Path) -> DiskStorage {
+        DiskStorage { root: root, layout: FileLayout::new(info), open: Vec::new() }
+    }
+
+    pub fn layout<'a>(&'a self) -> &'a FileLayout {
+        &self.layout
+    }
+
+    /// Full path of file `index` of the layout
+    pub fn path(&self, index: uint) -> Path {
+        let &(ref path, _) = self.layout.files.get(index);
+        self.root.join(path)
+    }
+
+    /// Bytes already on disk for file `index`, at most its length
+    fn existing_length(&self, index: uint) -> u64 {
+        let &(_, length) = self.layout.files.get(index);
+        match fs::stat(&self.path(index)) {
+            Ok(stat) => stat.size.min(length as u64),
+            Err(_) => 0
+        }
+    }
+
+    /// Bytes of disk blocks allocated to file `index`, at most its length.
+    /// A sparse file has fewer than its size says.
+    fn allocated_length(&self, index: uint) -> u64 {
+        let &(_, length) = self.layout.files.get(index);
+        match fs::stat(&self.path(index)) {
+            Ok(stat) => (stat.unstable.blocks * 512).min(length as u64),
+            Err(_) => 0
+        }
+    }
+
+    /// Fail with `InsufficientSpace` unless the disk has room for the part
+    /// of the payload that isn't on it yet: the space not allocated yet when
+    /// `allocation` is `Full`, the bytes past the end of the files
+    /// otherwise. Passes when the free space can't be determined.
+    pub fn check_space(&self, overwrite: bool, allocation: Allocation) -> StorageResult<()> {
+        let existing = if overwrite {
+            0
+        } else if allocation == Full {
+            range(0, self.layout.files.len()).map(|index| self.allocated_length(index)).fold(0, |a, b| a + b)
+        } else {
+            range(0, self.layout.files.len()).map(|index| self.existing_length(index)).fold(0, |a, b| a + b)
+        };
+        let needed = self.layout.total_length as u64 - existing;
+        match free_space(&self.root) {
+            Some(available) if available < needed => Err(InsufficientSpace(needed, available)),
+            _ => Ok(())
+        }
+    }
+
+    /// Create the files as `options` say, after checking there's space
+    /// for them
+    pub fn allocate(&mut self, options: &DiskOptions) -> StorageResult<()> {
+        try!(self.check_space(options.overwrite, options.allocation));
+        self.open.clear();
+        for index in range(0, self.layout.files.len()) {
+            let path = self.path(index);
+            let &(_, length) = self.layout.files.get(index);
+            if options.overwrite && path.exists() {
+                try!(io_result(File::create(&path), &path));
+            }
+            if options.allocation == Lazy {
+                continue;
+            }
+            try!(io_result(fs::mkdir_recursive(&path.dir_path(), UserRWX), &path));
+            let mut file = try!(io_result(File::open_mode(&path, Open, ReadWrite), &path));
+            let existing = self.existing_length(index);
+            if existing < length as u64 {
+                try!(io_result(file.truncate(length as i64), &path));
+            }
+            if options.allocation == Full {
+                try!(preallocate(&path, existing, length as u64));
+            }
+        }
+        Ok(())
+    }
+
+    /// The open file `index`, opening it first if needed. Reading a file
+    /// that doesn't exist fails instead of creating it.
+    fn file<'a>(&'a mut self, index: uint, create: bool) -> StorageResult<&'a mut File> {
+        match self.open.iter().position(|&(open, _)| open == index) {
+            Some(position) => {
+                let entry = self.open.remove(position).unwrap();
+                self.open.push(entry);
+            },
+            None => {
+                let path = self.path(index);
+                if !create && !path.exists() {
+                    return Err(MissingData);
+                }
+                try!(io_result(fs::mkdir_recursive(&path.dir_path(), UserRWX), &path));
+                let file = try!(io_result(File::open_mode(&path, Open, ReadWrite), &path));
+                if self.open.len() >= MAX_OPEN_FILES {
+                    self.open.remove(0);
+                }
+                self.open.push((index, file));
+            }
+        }
+        let &(_, ref mut file) = self.open.mut_last().unwrap();
+        Ok(file)
+    }
+}
+
+impl Storage for DiskStorage {
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        let mut block = Vec::with_capacity(length);
+        for span in try!(self.layout.spans(piece, begin, length)).iter() {
+            let path = self.path(span.file);
+            let file = try!(self.file(span.file, false));
+            try!(io_result(file.seek(span.offset as i64, SeekSet), &path));
+            let data = try!(io_result(file.read_exact(span.length), &path));
+            block.push_all(data.as_slice());
+        }
+        Ok(block)
+    }
+
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()> {
+        let mut written = 0u;
+        for span in try!(self.layout.spans(piece, begin, data.len())).iter() {
+            let path = self.path(span.file);
+            let file = try!(self.file(span.file, true));
+            try!(io_result(file.seek(span.offset as i64, SeekSet), &path));
+            try!(write_result(file.write(data.slice(written, written + span.length)), &path));
+            written += span.length;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        for index in range(0, self.open.len()) {
+            let path = match *self.open.get(index) { (file, _) => self.path(file) };
+            let &(_, ref mut file) = self.open.get_mut(index);
+            try!(write_result(file.datasync(), &path));
+        }
+        Ok(())
+    }
+
+    fn exists(&self) -> bool {
+        range(0, self.layout.files.len()).any(|index| self.path(index).exists())
+    }
+
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>> {
+        range(0, self.layout.files.len()).map(|index| {
+            fs::stat(&self.path(index)).ok().map(|stat| (stat.size, stat.modified))
+        }).collect()
+    }
+
+    fn move_to(&mut self, destination: &Path) -> StorageResult<()> {
+        try!(self.flush());
+        self.open.clear();
+        for index in range(0, self.layout.files.len()) {
+            let from = self.path(index);
+            if !from.exists() {
+                continue;
+            }
+            let &(ref relative, _) = self.layout.files.get(index);
+            let to = destination.join(relative);
+            try!(io_result(fs::mkdir_recursive(&to.dir_path(), UserRWX), &to));
+            try!(io_result(fs::rename(&from, &to), &to));
+        }
+        self.root = destination.clone();
+        Ok(())
+    }
+
+    fn delete(&mut self) -> StorageResult<()> {
+        self.open.clear();
+        for index in range(0, self.layout.files.len()) {
+            let path = self.path(index);
+            if path.exists() {
+                try!(io_result(fs::unlink(&path), &path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Allocate the first `length` bytes of the file at `path`, of which the
+/// first `existing` hold data
+#[cfg(target_os = "linux")]
+fn preallocate(path: &Path, _existing: u64, length: u64) -> StorageResult<()> {
+    use libc::{c_int, off_t, open, close, O_RDWR, ENOSPC};
+    use std::io::IoError;
+    use super::{StorageError, DiskFull};
+
+    extern {
+        fn posix_fallocate(fd: c_int, offset: off_t, len: off_t) -> c_int;
+    }
+
+    let fd = path.with_c_str(|path| unsafe { open(path, O_RDWR, 0) });
+    if fd < 0 {
+        return io_result(Err(IoError::last_error()), path);
+    }
+    // the error comes back as the result, errno is left alone
+    let result = unsafe { posix_fallocate(fd, 0, length as off_t) };
+    unsafe { close(fd); }
+    match result {
+        0 => Ok(()),
+        ENOSPC => Err(DiskFull),
+        _ => Err(StorageError::from_io_error(IoError::from_errno(result as uint, true), path))
+    }
+}
+
+/// Allocate the first `length` bytes of the file at `path`, of which the
+/// first `existing` hold data, by writing zeros after them where there's no
+/// `fallocate`
+#[cfg(not(target_os = "linux"))]
+fn preallocate(path: &Path, existing: u64, length: u64) -> StorageResult<()> {
+    let mut file = try!(io_result(File::open_mode(path, Open, ReadWrite), path));
+    let zeros = [0u8, ..16384];
+    let mut offset = existing;
+    try!(io_result(file.seek(offset as i64, SeekSet), path));
+    while offset < length {
+        let chunk = (length - offset).min(zeros.len() as u64) as uint;
+        try!(write_result(file.write(zeros.slice_to(chunk)), path));
+        offset += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Bytes available to us on the filesystem `path` is on. `None` where we
+/// can't tell, which only skips the space check.
+///
+/// `StatVfs` is glibc's `struct statvfs` on 64-bit Linux. On 32-bit its
+/// block counts are 32 bits wide unless built for large files, so we don't
+/// guess there.
+#[cfg(target_os = "linux", target_word_size = "64")]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use libc::{c_char, c_int, c_ulong};
+    use std::mem;
+
+    #[repr(C)]
+    struct StatVfs {
+        f_bsize: c_ulong,
+        f_frsize: c_ulong,
+        f_blocks: u64,
+        f_bfree: u64,
+        f_bavail: u64,
+        f_files: u64,
+        f_ffree: u64,
+        f_favail: u64,
+        f_fsid: c_ulong,
+        f_flag: c_ulong,
+        f_namemax: c_ulong,
+        f_spare: [c_int, ..6],
+    }
+
+    extern {
+        fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+    }
+
+    // the destination may not exist yet, ask about the nearest parent
+    let mut path = path.clone();
+    while !path.exists() && path.pop() {}
+    let mut buf: StatVfs = unsafe { mem::zeroed() };
+    let result = path.with_c_str(|path| unsafe { statvfs(path, &mut buf) });
+    if result == 0 { Some(buf.f_bavail * buf.f_frsize as u64) } else { None }
+}
+
+#[cfg(target_os = "linux", target_word_size = "32")]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,69 @@
+//! Storage in memory, for tests and for embedders that keep the data
+//! somewhere other than files
+
+use torrent::TorrentInfo;
+use super::{Storage, StorageResult, FileLayout, BlockOutOfRange, MissingData};
+
+
+pub struct MemoryStorage {
+    layout: FileLayout,
+    /// The whole payload, allocated on the first write
+    data: Option<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &TorrentInfo) -> MemoryStorage {
+        MemoryStorage { layout: FileLayout::new(info), data: None }
+    }
+
+    /// The payload as written so far, `None` before the first write
+    pub fn data<'a>(&'a self) -> Option<&'a [u8]> {
+        self.data.as_ref().map(|data| data.as_slice())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        let offset = match self.layout.payload_offset(piece, begin, length) {
+            Some(offset) => offset,
+            None => return Err(BlockOutOfRange)
+        };
+        match self.data {
+            Some(ref data) => Ok(Vec::from_slice(data.slice(offset, offset + length))),
+            None => Err(MissingData)
+        }
+    }
+
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()> {
+        let offset = match self.layout.payload_offset(piece, begin, data.len()) {
+            Some(offset) => offset,
+            None => return Err(BlockOutOfRange)
+        };
+        if self.data.is_none() {
+            self.data = Some(Vec::from_elem(self.layout.total_length, 0u8));
+        }
+        self.data.as_mut().unwrap().mut_slice(offset, offset + data.len()).copy_from(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn exists(&self) -> bool {
+        self.data.is_some()
+    }
+
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>> {
+        Vec::new()
+    }
+
+    fn move_to(&mut self, _destination: &Path) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self) -> StorageResult<()> {
+        self.data = None;
+        Ok(())
+    }
+}
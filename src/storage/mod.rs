@@ -0,0 +1,183 @@
+//! Where the data of a torrent is kept.
+//!
+//! The torrent sees its payload as one long run of pieces; a `Storage`
+//! reads and writes blocks of it by piece index and offset. `disk` keeps the
+//! payload in the torrent's files, mapping blocks across file boundaries
+//! with a `FileLayout`, and is what `Client::add_torrent` uses. `memory`
+//! keeps it in memory, for tests and for embedders that put the data
+//! elsewhere. `cache` keeps the pieces peers recently read from another
+//! storage in memory, within a budget shared by every torrent. `pool` runs
+//! storage access on worker threads, away from the peer connections.
+
+use std::io::{IoError, IoResult, ShortWrite, EndOfFile, FileNotFound};
+use std::io;
+use std::os;
+
+use libc::ENOSPC;
+
+use torrent::{TorrentInfo, SingleFile, MultiFile};
+
+pub use self::disk::{DiskStorage, DiskOptions, Allocation, Sparse, Full, Lazy};
+pub use self::memory::MemoryStorage;
+pub use self::pool::{DiskPool, DiskResult, SharedStorage};
+pub use self::cache::{CachedStorage, CacheStats, PieceCache};
+
+pub mod cache;
+pub mod disk;
+pub mod memory;
+pub mod pool;
+
+
+#[deriving(Show)]
+pub enum StorageError {
+    /// A block beyond the end of the torrent
+    BlockOutOfRange,
+    /// The block was never written, its file is missing or too short
+    MissingData,
+    DiskFull,
+    /// Bytes needed and bytes free, when checking before adding a torrent
+    InsufficientSpace(u64, u64),
+    /// No permission to access the file at this path
+    AccessDenied(String),
+    StorageIoError(IoError),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl StorageError {
+    /// Classify an error from accessing the file at `path`
+    pub fn from_io_error(error: IoError, path: &Path) -> StorageError {
+        match error.kind {
+            io::PermissionDenied => AccessDenied(format!("{}", path.display())),
+            EndOfFile | FileNotFound => MissingData,
+            // a write that doesn't fit is cut short; a failing one is
+            // caught by `write_result`
+            ShortWrite(_) => DiskFull,
+            _ => StorageIoError(error)
+        }
+    }
+}
+
+pub trait Storage {
+    /// Read `length` bytes at offset `begin` of piece `piece`
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>>;
+
+    /// Read a block a peer asked for. Backends with a read cache keep its
+    /// piece around for the peer's next requests; everything else, like
+    /// hashing, uses `read_block` and leaves the cache alone.
+    fn read_for_peer(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        self.read_block(piece, begin, length)
+    }
+
+    /// Write `data` at offset `begin` of piece `piece`
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()>;
+
+    /// Make sure everything written so far is stored for good
+    fn flush(&mut self) -> StorageResult<()>;
+
+    /// Whether any of the torrent's data exists already
+    fn exists(&self) -> bool;
+
+    /// Size and modification time in milliseconds of every file, `None` for
+    /// files that don't exist. Resume data records them to tell whether the
+    /// data changed behind our back. Backends without files return nothing.
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>>;
+
+    /// Move the data to `destination`, for backends where that means
+    /// anything
+    fn move_to(&mut self, destination: &Path) -> StorageResult<()>;
+
+    /// Delete all of the torrent's data
+    fn delete(&mut self) -> StorageResult<()>;
+
+    /// Hits and misses of the read cache, for backends that have one
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// Part of a block that falls into a single file
+#[deriving(Show, Clone, PartialEq)]
+pub struct FileSpan {
+    /// Index of the file in the layout
+    pub file: uint,
+    /// Offset in the file
+    pub offset: u64,
+    pub length: uint,
+}
+
+/// The torrent's files, in payload order
+#[deriving(Clone)]
+pub struct FileLayout {
+    /// Path relative to the destination directory and length of every file
+    pub files: Vec<(Path, uint)>,
+    pub piece_length: uint,
+    pub total_length: uint,
+}
+
+impl FileLayout {
+    pub fn new(info: &TorrentInfo) -> FileLayout {
+        let files = match info.metainfo.payload {
+            SingleFile(ref file) => vec![(Path::new(info.metainfo.name.clone()), file.length)],
+            MultiFile(ref files) => files.iter().filter_map(|file| {
+                file.path.as_ref().map(|path| (Path::new(".").join_many(path.as_slice()), file.length))
+            }).collect()
+        };
+        FileLayout {
+            total_length: files.iter().map(|&(_, length)| length).fold(0, |a, b| a + b),
+            files: files,
+            piece_length: info.metainfo.piece_length as uint,
+        }
+    }
+
+    /// Offset of a block in the whole payload, `None` if the block doesn't
+    /// fit inside the payload
+    pub fn payload_offset(&self, piece: uint, begin: uint, length: uint) -> Option<uint> {
+        let offset = piece * self.piece_length + begin;
+        if begin + length > self.piece_length || offset + length > self.total_length {
+            None
+        } else {
+            Some(offset)
+        }
+    }
+
+    /// The parts of the files a block is stored in
+    pub fn spans(&self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<FileSpan>> {
+        let mut offset = match self.payload_offset(piece, begin, length) {
+            Some(offset) => offset,
+            None => return Err(BlockOutOfRange)
+        };
+        let mut remaining = length;
+        let mut file_start = 0u;
+        let mut spans = Vec::new();
+        for (index, &(_, file_length)) in self.files.iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            if offset < file_start + file_length {
+                let span_length = remaining.min(file_start + file_length - offset);
+                spans.push(FileSpan { file: index, offset: (offset - file_start) as u64, length: span_length });
+                offset += span_length;
+                remaining -= span_length;
+            }
+            file_start += file_length;
+        }
+        Ok(spans)
+    }
+}
+
+/// Turn an `IoResult` from accessing `path` into a `StorageResult`
+pub fn io_result<T>(result: IoResult<T>, path: &Path) -> StorageResult<T> {
+    result.map_err(|e| StorageError::from_io_error(e, path))
+}
+
+/// `io_result` for writing to `path`, where a write that failed with ENOSPC
+/// is `DiskFull`. Call it on the result of the write itself, before
+/// anything else can change the error number.
+pub fn write_result<T>(result: IoResult<T>, path: &Path) -> StorageResult<T> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(_) if os::errno() == ENOSPC as int => Err(DiskFull),
+        Err(e) => Err(StorageError::from_io_error(e, path))
+    }
+}
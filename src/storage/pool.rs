@@ -0,0 +1,251 @@
+//! Disk I/O off the network thread.
+//!
+//! A `DiskPool` runs reads, writes and piece hashes on worker threads, on
+//! storages shared with them as `SharedStorage`. All jobs of a torrent go
+//! to the same worker, so they run in the order they were queued: a piece
+//! is hashed only after its blocks are written. Different torrents are
+//! spread over the workers and proceed in parallel.
+//!
+//! Blocks to write are held back until `submit`, and blocks adjacent in the
+//! same piece are merged into a single write. Everything queued for
+//! writing counts against a memory budget; past it `is_congested` tells
+//! the caller to stop asking peers for more data until the workers catch
+//! up.
+//!
+//! Results come back on the receiver returned by `DiskPool::new`, tagged
+//! with the key the caller queued the job under, the torrent's infohash.
+
+use std::collections::hashmap::HashMap;
+use std::io::net::ip::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use super::{Storage, StorageResult, CacheStats};
+
+
+/// Worker threads of a `DiskPool::new` pool
+pub static DEFAULT_THREADS: uint = 4;
+/// Bytes queued for writing before the pool is congested
+pub static DEFAULT_BUDGET: uint = 64 * 1024 * 1024;
+
+/// A storage shared with the disk threads. It's a `Storage` itself, each
+/// call locking the shared one.
+#[deriving(Clone)]
+pub struct SharedStorage {
+    storage: Arc<Mutex<Box<Storage>>>,
+}
+
+impl SharedStorage {
+    pub fn new(storage: Box<Storage>) -> SharedStorage {
+        SharedStorage { storage: Arc::new(Mutex::new(storage)) }
+    }
+}
+
+impl Storage for SharedStorage {
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        self.storage.lock().read_block(piece, begin, length)
+    }
+
+    fn read_for_peer(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        self.storage.lock().read_for_peer(piece, begin, length)
+    }
+
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()> {
+        self.storage.lock().write_block(piece, begin, data)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.storage.lock().flush()
+    }
+
+    fn exists(&self) -> bool {
+        self.storage.lock().exists()
+    }
+
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>> {
+        self.storage.lock().file_stats()
+    }
+
+    fn move_to(&mut self, destination: &Path) -> StorageResult<()> {
+        self.storage.lock().move_to(destination)
+    }
+
+    fn delete(&mut self) -> StorageResult<()> {
+        self.storage.lock().delete()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.storage.lock().cache_stats()
+    }
+}
+
+/// What a job of the pool came to, with the key it was queued under
+pub enum DiskResult {
+    /// Piece, offset and length of a write, after coalescing
+    Written(Vec<u8>, uint, uint, uint, StorageResult<()>),
+    /// A block read for the peer at the address: piece, offset and data
+    Read(Vec<u8>, SocketAddr, uint, uint, StorageResult<Vec<u8>>),
+    /// A piece read back from storage: whether it matches its hash, and its
+    /// data
+    Hashed(Vec<u8>, uint, StorageResult<(bool, Vec<u8>)>),
+}
+
+enum Job {
+    WriteJob(SharedStorage, Vec<u8>, uint, uint, Vec<u8>),
+    ReadJob(SharedStorage, Vec<u8>, SocketAddr, uint, uint, uint),
+    /// Storage, key, piece, length and expected hash
+    HashJob(SharedStorage, Vec<u8>, uint, uint, Vec<u8>),
+}
+
+/// Writes held back until `submit`
+struct PendingWrites {
+    storage: SharedStorage,
+    /// Piece, offset and data of every block
+    blocks: Vec<(uint, uint, Vec<u8>)>,
+}
+
+pub struct DiskPool {
+    workers: Vec<Sender<Job>>,
+    /// Worker the jobs of every key go to
+    assigned: HashMap<Vec<u8>, uint>,
+    pending: HashMap<Vec<u8>, PendingWrites>,
+    /// Bytes given to `write` and not written yet, shared with the workers
+    queued: Arc<AtomicUint>,
+    /// Bytes queued for writing before the pool is congested
+    pub budget: uint,
+}
+
+impl DiskPool {
+    /// Start a pool of `threads` workers, which stop once the pool is
+    /// dropped and the jobs queued so far are done
+    pub fn new(threads: uint, budget: uint) -> (DiskPool, Receiver<DiskResult>) {
+        let (results, receiver) = channel();
+        let queued = Arc::new(AtomicUint::new(0));
+        let mut workers = Vec::new();
+        for _ in range(0, threads.max(1)) {
+            let (jobs, queue) = channel();
+            let results = results.clone();
+            let queued = queued.clone();
+            spawn(proc() {
+                for job in queue.iter() {
+                    if results.send_opt(run(job, &*queued)).is_err() {
+                        break;
+                    }
+                }
+            });
+            workers.push(jobs);
+        }
+        let pool = DiskPool {
+            workers: workers,
+            assigned: HashMap::new(),
+            pending: HashMap::new(),
+            queued: queued,
+            budget: budget,
+        };
+        (pool, receiver)
+    }
+
+    /// Write a block to `storage` at offset `begin` of `piece`. It's held
+    /// back until `submit`, so it can be merged with adjacent blocks.
+    pub fn write(&mut self, key: &[u8], storage: &SharedStorage, piece: uint, begin: uint, data: Vec<u8>) {
+        self.queued.fetch_add(data.len(), SeqCst);
+        let pending = self.pending.find_or_insert(Vec::from_slice(key), PendingWrites { storage: storage.clone(), blocks: Vec::new() });
+        pending.blocks.push((piece, begin, data));
+    }
+
+    /// Read a block from `storage` for the peer at `peer`
+    pub fn read(&mut self, key: &[u8], storage: &SharedStorage, peer: SocketAddr, piece: uint, begin: uint, length: uint) {
+        self.queue(key, ReadJob(storage.clone(), Vec::from_slice(key), peer, piece, begin, length));
+    }
+
+    /// Read `piece` back from `storage` once the writes queued for it are
+    /// done, and compare it with `hash`
+    pub fn hash(&mut self, key: &[u8], storage: &SharedStorage, piece: uint, length: uint, hash: &[u8]) {
+        self.submit_key(key);
+        self.queue(key, HashJob(storage.clone(), Vec::from_slice(key), piece, length, Vec::from_slice(hash)));
+    }
+
+    /// Hand the writes held back to the workers, adjacent blocks merged
+    pub fn submit(&mut self) {
+        let keys: Vec<Vec<u8>> = self.pending.keys().map(|key| key.clone()).collect();
+        for key in keys.iter() {
+            self.submit_key(key.as_slice());
+        }
+    }
+
+    fn submit_key(&mut self, key: &[u8]) {
+        let PendingWrites { storage, mut blocks } = match self.pending.pop(&Vec::from_slice(key)) {
+            Some(pending) => pending,
+            None => return
+        };
+        blocks.sort_by(|&(a_piece, a_begin, _), &(b_piece, b_begin, _)| (a_piece, a_begin).cmp(&(b_piece, b_begin)));
+        let mut runs: Vec<(uint, uint, Vec<u8>)> = Vec::new();
+        for (piece, begin, data) in blocks.move_iter() {
+            let adjacent = match runs.last() {
+                Some(&(run_piece, run_begin, ref run_data)) => run_piece == piece && run_begin + run_data.len() == begin,
+                None => false
+            };
+            if adjacent {
+                match runs.mut_last() {
+                    Some(&(_, _, ref mut run_data)) => run_data.push_all(data.as_slice()),
+                    None => ()
+                }
+            } else {
+                runs.push((piece, begin, data));
+            }
+        }
+        for (piece, begin, data) in runs.move_iter() {
+            self.queue(key, WriteJob(storage.clone(), Vec::from_slice(key), piece, begin, data));
+        }
+    }
+
+    fn queue(&mut self, key: &[u8], job: Job) {
+        let next = self.assigned.len() % self.workers.len();
+        let worker = *self.assigned.find_or_insert(Vec::from_slice(key), next);
+        // workers only stop once the pool is gone
+        let _ = self.workers.get(worker).send_opt(job);
+    }
+
+    /// Bytes given to `write` and not written yet
+    pub fn queued_bytes(&self) -> uint {
+        self.queued.load(SeqCst)
+    }
+
+    /// Whether more is queued for writing than the budget allows
+    pub fn is_congested(&self) -> bool {
+        self.queued_bytes() >= self.budget
+    }
+}
+
+impl Drop for DiskPool {
+    fn drop(&mut self) {
+        // the workers finish what's queued before they stop
+        self.submit();
+    }
+}
+
+fn run(job: Job, queued: &AtomicUint) -> DiskResult {
+    match job {
+        WriteJob(mut storage, key, piece, begin, data) => {
+            let result = storage.write_block(piece, begin, data.as_slice());
+            queued.fetch_sub(data.len(), SeqCst);
+            Written(key, piece, begin, data.len(), result)
+        },
+        ReadJob(mut storage, key, peer, piece, begin, length) => {
+            Read(key, peer, piece, begin, storage.read_for_peer(piece, begin, length))
+        },
+        HashJob(mut storage, key, piece, length, expected) => {
+            let result = storage.read_block(piece, 0, length).map(|data| {
+                let mut hasher = Sha1::new();
+                hasher.input(data.as_slice());
+                let mut hash = [0u8, ..20];
+                hasher.result(hash);
+                (hash.as_slice() == expected.as_slice(), data)
+            });
+            Hashed(key, piece, result)
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Tracker exchange (`lt_tex`) extension message
+//!
+//! Peers send each other the trackers they have verified to be working, as a
+//! bencoded dictionary with the new URLs under `added`. Trackers learned this
+//! way are only candidates until they answer an announce of our own.
+
+use std::collections::hashmap::{HashMap, HashSet};
+use std::collections::TreeMap;
+use std::io::net::ip::SocketAddr;
+
+use bencode;
+use bencode::{Bencode, Dict, List, ByteString, Key};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+
+/// Name of the extension in the extension protocol (BEP 10) handshake
+pub static EXTENSION_NAME: &'static str = "lt_tex";
+/// Message ID we ask peers to send `lt_tex` messages under
+pub static MESSAGE_ID: u8 = 1;
+/// Nanoseconds between the `lt_tex` messages sent to a peer
+pub static TEX_INTERVAL: u64 = 60 * 1_000_000_000;
+
+/// Most URLs accepted from or sent in a single message
+static MAX_URLS: uint = 50;
+static MAX_URL_LENGTH: uint = 512;
+
+/// Encode an `lt_tex` message announcing `added`
+pub fn encode_message(added: &[String]) -> Vec<u8> {
+    let urls = added.iter()
+        .take(MAX_URLS)
+        .map(|url| ByteString(Vec::from_slice(url.as_bytes())))
+        .collect();
+    let mut dict = TreeMap::new();
+    dict.insert(Key::from_str("added"), List(urls));
+    Dict(dict).to_bytes().unwrap()
+}
+
+/// Decode an `lt_tex` message, keeping only URLs of tracker protocols we
+/// speak
+pub fn decode_message(payload: &[u8]) -> Option<Vec<String>> {
+    let message = match bencode::from_vec(Vec::from_slice(payload)) {
+        Ok(message) => message,
+        Err(_) => return None
+    };
+    let added = match message {
+        Dict(ref dict) => match dict.find(&Key::from_str("added")) {
+            Some(&List(ref added)) => added,
+            _ => return None
+        },
+        _ => return None
+    };
+    Some(added.iter()
+        .filter_map(|url| match *url {
+            ByteString(ref url) if url.len() <= MAX_URL_LENGTH => String::from_utf8(url.clone()).ok(),
+            _ => None
+        })
+        .filter(|url| is_tracker_url(url.as_slice()))
+        .take(MAX_URLS)
+        .collect())
+}
+
+fn is_tracker_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("udp://")
+}
+
+/// Hash of a tracker list, sent as `tr` in the extension handshake so peers
+/// with identical lists can skip exchanging them
+pub fn tracker_list_hash(urls: &[String]) -> [u8, ..20] {
+    let mut sorted = Vec::from_slice(urls);
+    sorted.sort();
+    let mut hasher = Sha1::new();
+    for url in sorted.iter() {
+        hasher.input_str(url.as_slice());
+    }
+    let mut hash = [0u8, ..20];
+    hasher.result(hash);
+    hash
+}
+
+/// Per-torrent tracker exchange state
+pub struct TrackerExchange {
+    /// Trackers we already told each peer about
+    sent: HashMap<SocketAddr, HashSet<String>>,
+    /// Trackers learned from peers, waiting for an announce to verify them
+    candidates: Vec<String>,
+    /// Candidates that failed verification, so peers can't make us retry
+    /// them forever
+    rejected: HashSet<String>,
+}
+
+impl TrackerExchange {
+    pub fn new() -> TrackerExchange {
+        TrackerExchange {
+            sent: HashMap::new(),
+            candidates: Vec::new(),
+            rejected: HashSet::new(),
+        }
+    }
+
+    /// Of the `working` trackers, the ones `peer` hasn't heard about from us
+    /// yet. They're remembered as sent.
+    pub fn unsent(&mut self, peer: SocketAddr, working: &[String]) -> Vec<String> {
+        let sent = self.sent.find_or_insert_with(peer, |_| HashSet::new());
+        let unsent: Vec<String> = working.iter()
+            .filter(|url| !sent.contains(*url))
+            .take(MAX_URLS)
+            .map(|url| url.clone())
+            .collect();
+        for url in unsent.iter() {
+            sent.insert(url.clone());
+        }
+        unsent
+    }
+
+    /// Queue trackers received from a peer for verification, skipping the
+    /// ones in `known`
+    pub fn receive(&mut self, urls: Vec<String>, known: &[String]) {
+        for url in urls.move_iter() {
+            if !known.contains(&url) && !self.rejected.contains(&url) && !self.candidates.contains(&url) {
+                self.candidates.push(url);
+            }
+        }
+    }
+
+    /// Take the candidates waiting for verification
+    pub fn take_candidates(&mut self) -> Vec<String> {
+        ::std::mem::replace(&mut self.candidates, Vec::new())
+    }
+
+    pub fn reject(&mut self, url: String) {
+        self.rejected.insert(url);
+    }
+
+    /// Forget what we sent to a disconnected peer
+    pub fn peer_disconnected(&mut self, peer: &SocketAddr) {
+        self.sent.remove(peer);
+    }
+}
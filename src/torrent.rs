@@ -6,18 +6,37 @@ extern crate curl;
 use std::io::{File};
 use std::num::ToStrRadix;
 use std::str::raw::from_utf8_owned;
-use std::iter::AdditiveIterator;
-use std::rand::task_rng;
+use std::iter::{AdditiveIterator, range_step};
+use std::collections::{RingBuf, Deque};
+use std::collections::hashmap::HashMap;
+use std::rand::{Rng, task_rng};
+use std::io::net::ip::{SocketAddr, IpAddr};
 use url::Url;
+use time::{Timespec, get_time};
 
 use bencode::{FromBencode, Dict, Key, Bencode, List, ByteString, Number};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
 
-use super::{random_string, opt_finder};
+use super::{random_string, opt_finder, DEFAULT_PORT};
 use scrape::{TorrentScrape, ScrapeInfo};
 use peer::Peer;
-use announce::{AnnounceResponse, AnnounceResult};
+use tex;
+use tex::TrackerExchange;
+use extension::ExtendedHandshake;
+use bitfield::Bitfield;
+use availability::PieceAvailability;
+use picker::{PiecePicker, RarestFirst, PRIORITY_SKIP};
+use download::{PieceDownload, BlockReceived, BLOCK_SIZE};
+use smartban::SmartBan;
+use storage::{Storage, StorageResult, MissingData, SharedStorage};
+use resume::ResumeData;
+use choker::{Choker, PeerStats};
+use connection::{PeerConnection, ProtocolError, BlockRequest, InvalidRequest};
+use wire::{Message, Have, HaveAll, HaveNone, Request, Piece, RejectRequest};
+use wire;
+use announce::{AnnounceResponse, AnnounceResult, Success, Failure, RetryIn, RetryAfter, RetryNever};
+use tracker::{AnnounceEvent, Started, Completed};
 
 
 #[deriving(Clone, Show)]
@@ -47,7 +66,8 @@ pub struct MetaInfo {
 #[deriving(Clone, Show)]
 pub struct TorrentInfo {
     pub announce: String,
-    pub announce_list: Option<Vec<String>>,
+    /// Tiers of tracker URLs (BEP 12)
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub creation_date: Option<int>,
     pub comment: Option<String>,
     pub created_by: Option<String>,
@@ -177,6 +197,61 @@ impl TorrentInfo {
         unsafe { url::encode_component(from_utf8_owned(self.infohash.clone()).as_slice()) }
     }
 
+    /// Tracker URLs grouped into tiers, with the order inside each tier
+    /// shuffled as BEP 12 asks. Falls back to `announce` when there's no
+    /// usable `announce-list`.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let mut tiers: Vec<Vec<String>> = match self.announce_list {
+            Some(ref tiers) => tiers.iter().filter(|tier| !tier.is_empty()).map(|tier| tier.clone()).collect(),
+            None => Vec::new()
+        };
+        if tiers.is_empty() {
+            return vec![vec![self.announce.clone()]];
+        }
+        for tier in tiers.mut_iter() {
+            task_rng().shuffle(tier.as_mut_slice());
+        }
+        tiers
+    }
+
+    /// Expected SHA-1 hash of piece `index`
+    pub fn piece_hash<'a>(&'a self, index: uint) -> &'a [u8] {
+        self.metainfo.pieces.slice(index * 20, index * 20 + 20)
+    }
+
+    /// Whether `data` is piece `index`, going by its hash
+    pub fn verify_piece(&self, index: uint, data: &[u8]) -> bool {
+        let mut hasher = Sha1::new();
+        hasher.input(data);
+        let mut hash = [0u8, ..20];
+        hasher.result(hash);
+        hash.as_slice() == self.piece_hash(index)
+    }
+
+    pub fn num_pieces(&self) -> uint {
+        self.metainfo.pieces.len() / 20
+    }
+
+    /// Size of piece `index`; the last piece is usually shorter
+    pub fn piece_size(&self, index: uint) -> uint {
+        let piece_length = self.metainfo.piece_length as uint;
+        if index + 1 < self.num_pieces() {
+            piece_length
+        } else {
+            self.payload_size() - piece_length * index
+        }
+    }
+
+    /// The blocks piece `index` is requested in
+    pub fn blocks(&self, index: uint) -> Vec<BlockRequest> {
+        let piece_size = self.piece_size(index);
+        range_step(0u, piece_size, BLOCK_SIZE).map(|begin| BlockRequest {
+            piece: index as u32,
+            begin: begin as u32,
+            length: BLOCK_SIZE.min(piece_size - begin) as u32
+        }).collect()
+    }
+
     pub fn payload_size(&self) -> uint {
         match self.metainfo.payload {
             SingleFile(ref file) => file.length,
@@ -198,8 +273,10 @@ fn to_hex(rr: &[u8]) -> String {
     return s;
 }
 
+#[deriving(Show, Clone, PartialEq)]
 pub enum Status {
     Stopped, // The torrent is completely stopped, no TX/RX
+    Checking, // Existing data is being hashed, no TX/RX
     Downloading, // The torrent is downloading
     Seeding // The torrent is downloading
 }
@@ -207,21 +284,686 @@ pub enum Status {
 pub struct Torrent {
     pub info: TorrentInfo,
     pub status: Status,
-    pub destination_path: Path,
+    /// Where the torrent's data is kept, shared with the disk threads
+    pub storage: SharedStorage,
     pub traffic: TrafficInfo,
     pub session: SessionInfo,
+    pub trackers: Vec<TrackerStatus>,
+    pub tex: TrackerExchange,
+    /// Pieces we have
+    pub have: Bitfield,
+    /// How many connected peers have each piece
+    pub availability: PieceAvailability,
+    pub picker: PiecePicker,
+    /// Block state of the pieces being downloaded
+    pub downloads: HashMap<uint, PieceDownload>,
+    /// Number of downloaded pieces that failed verification
+    pub hash_failures: uint,
+    /// Trust in the peers at each address, raised for every good piece they
+    /// helped download and lowered for every bad one
+    trust: HashMap<IpAddr, int>,
+    smart_ban: SmartBan,
+    /// Next piece to hash while `Checking`
+    next_check: uint,
+    pub choker: Choker,
+    /// Upload slots of this torrent, overriding the client's
+    pub upload_slots: Option<uint>,
+    /// Port announced to trackers, the one the client listens on
+    pub listen_port: u16,
+    /// Whether the download finished while the torrent was running, to be
+    /// announced as `completed`
+    pub completed: bool,
 }
 
 pub struct TrafficInfo {
     pub uploaded_bytes: uint,
     pub downloaded_bytes: uint,
+    /// Bytes of blocks that arrived more than once, the price of end-game
+    /// mode
+    pub duplicate_bytes: uint,
 }
 
 pub struct SessionInfo {
     pub peers: Vec<Peer>,
 }
 
+/// Seconds to wait before retrying a failed tracker, doubled on every
+/// consecutive failure up to `MAX_RETRY_BACKOFF`
+static RETRY_BACKOFF: i64 = 60;
+static MAX_RETRY_BACKOFF: i64 = 3600;
+/// Milliseconds an announce to a single tracker may take before the
+/// tracker counts as unreachable
+static ANNOUNCE_TIMEOUT: uint = 30000;
+
+/// Trust gained for helping with a good piece and lost for a bad one; a few
+/// bad pieces outweigh many good ones
+pub static TRUST_GAIN: int = 1;
+pub static TRUST_PENALTY: int = 2;
+/// Peers at this trust are banned
+pub static MIN_TRUST: int = -7;
+static MAX_TRUST: int = 8;
+
+/// What we know about one of the torrent's trackers
+#[deriving(Clone, Show)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: uint,
+    pub last_announce: Option<Timespec>,
+    pub next_announce: Option<Timespec>,
+    /// Failure reason or transport error of the last announce, if it failed
+    pub last_error: Option<String>,
+    /// Warning message of the last successful announce
+    pub last_warning: Option<String>,
+    pub seeders: Option<uint>,
+    pub leechers: Option<uint>,
+    /// Number of completed downloads, only known after a scrape
+    pub downloaded: Option<uint>,
+    /// Number of peers in the last successful announce
+    pub peers_received: uint,
+    pub tracker_id: Option<String>,
+    /// Whether the last announce succeeded
+    pub working: bool,
+    /// Consecutive failed announces
+    pub failures: uint,
+    /// Set when the tracker asked never to be contacted again (BEP 31)
+    pub disabled: bool,
+    /// Whether the tracker answered an announce with the `started` event
+    pub started: bool,
+    /// Whether the tracker answered an announce with the `completed` event
+    pub completed: bool,
+    /// Event of the announce in flight
+    event: Option<AnnounceEvent>,
+}
+
+impl TrackerStatus {
+    pub fn new(url: String, tier: uint) -> TrackerStatus {
+        TrackerStatus {
+            url: url,
+            tier: tier,
+            last_announce: None,
+            next_announce: None,
+            last_error: None,
+            last_warning: None,
+            seeders: None,
+            leechers: None,
+            downloaded: None,
+            peers_received: 0,
+            tracker_id: None,
+            working: false,
+            failures: 0,
+            disabled: false,
+            started: false,
+            completed: false,
+            event: None,
+        }
+    }
+
+    /// One status per tracker of `info`, in tier order
+    pub fn from_info(info: &TorrentInfo) -> Vec<TrackerStatus> {
+        let mut trackers = Vec::new();
+        for (tier, urls) in info.tracker_tiers().move_iter().enumerate() {
+            for url in urls.move_iter() {
+                trackers.push(TrackerStatus::new(url, tier));
+            }
+        }
+        trackers
+    }
+
+    /// Record the outcome of an announce made at `now`; `None` means the
+    /// tracker couldn't be reached at all
+    pub fn update(&mut self, response: &Option<AnnounceResponse>, now: Timespec) {
+        self.last_announce = Some(now);
+        match *response {
+            Some(Success(ref result)) => {
+                self.working = true;
+                self.failures = 0;
+                self.last_error = None;
+                self.last_warning = result.warning_message.clone();
+                self.seeders = Some(result.complete);
+                self.leechers = Some(result.incomplete);
+                self.peers_received = result.peers.len();
+                if result.tracker_id.is_some() {
+                    self.tracker_id = result.tracker_id.clone();
+                }
+                let interval = result.min_interval.map_or(result.interval, |min| result.interval.max(min));
+                self.next_announce = Some(Timespec::new(now.sec + interval as i64, now.nsec));
+                self.apply_retry_in(&result.retry_in, now);
+                match self.event.take() {
+                    Some(Started) => self.started = true,
+                    Some(Completed) => self.completed = true,
+                    _ => ()
+                }
+            },
+            _ => {
+                self.working = false;
+                self.last_error = match *response {
+                    Some(Failure(ref message, _)) => Some(message.clone()),
+                    _ => Some("tracker did not respond".to_str())
+                };
+                let backoff = RETRY_BACKOFF << self.failures.min(6);
+                self.failures += 1;
+                self.next_announce = Some(Timespec::new(now.sec + backoff.min(MAX_RETRY_BACKOFF), now.nsec));
+                match *response {
+                    Some(Failure(_, ref retry_in)) => self.apply_retry_in(retry_in, now),
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    /// The tracker's own idea of when to announce next overrides ours
+    fn apply_retry_in(&mut self, retry_in: &Option<RetryIn>, now: Timespec) {
+        match *retry_in {
+            Some(RetryAfter(minutes)) => {
+                self.next_announce = Some(Timespec::new(now.sec + minutes as i64 * 60, now.nsec));
+            },
+            Some(RetryNever) => {
+                self.disabled = true;
+                self.next_announce = None;
+            },
+            None => ()
+        }
+    }
+
+    /// Whether the tracker should be announced to at `now`
+    pub fn is_due(&self, now: Timespec) -> bool {
+        !self.disabled && self.next_announce.map_or(true, |next| next <= now)
+    }
+
+    /// Event of the next announce: `started` until the tracker answered
+    /// one, then `completed` once if the download finished, none for the
+    /// regular re-announces
+    fn next_event(&self, completed: bool) -> Option<AnnounceEvent> {
+        if !self.started {
+            Some(Started)
+        } else if completed && !self.completed {
+            Some(Completed)
+        } else {
+            None
+        }
+    }
+}
+
 impl Torrent {
+    pub fn new(info: TorrentInfo, storage: Box<Storage>) -> Torrent {
+        let num_pieces = info.num_pieces();
+        Torrent {
+            trackers: TrackerStatus::from_info(&info),
+            info: info,
+            status: Stopped,
+            storage: SharedStorage::new(storage),
+            traffic: TrafficInfo { downloaded_bytes: 0, uploaded_bytes: 0, duplicate_bytes: 0 },
+            session: SessionInfo { peers: Vec::new() },
+            tex: TrackerExchange::new(),
+            have: Bitfield::new(num_pieces),
+            availability: PieceAvailability::new(num_pieces),
+            picker: PiecePicker::new(num_pieces, RarestFirst),
+            downloads: HashMap::new(),
+            hash_failures: 0,
+            trust: HashMap::new(),
+            smart_ban: SmartBan::new(),
+            next_check: 0,
+            choker: Choker::new(),
+            upload_slots: None,
+            listen_port: DEFAULT_PORT,
+            completed: false,
+        }
+    }
+
+    /// Start hashing the existing data to find the pieces we have. The
+    /// check runs a piece per `check_step`, so it can be interleaved with
+    /// other work, and moves the torrent to `Downloading` or `Seeding` when
+    /// it's done.
+    pub fn start_check(&mut self) {
+        self.status = Checking;
+        self.next_check = 0;
+        self.have = Bitfield::new(self.info.num_pieces());
+        self.downloads.clear();
+        for index in range(0, self.have.len()) {
+            self.picker.piece_stopped(index);
+        }
+    }
+
+    /// Hash the next piece of a running check. Pieces that are missing from
+    /// storage simply aren't had; other storage errors stop the check with
+    /// the torrent `Stopped`. Returns whether there's more to check.
+    pub fn check_step(&mut self) -> StorageResult<bool> {
+        if self.status != Checking {
+            return Ok(false);
+        }
+        if self.next_check < self.info.num_pieces() {
+            let index = self.next_check;
+            match self.storage.read_block(index, 0, self.info.piece_size(index)) {
+                Ok(data) => if self.info.verify_piece(index, data.as_slice()) {
+                    self.have.set(index);
+                },
+                Err(MissingData) => (),
+                Err(e) => {
+                    self.status = Stopped;
+                    return Err(e);
+                }
+            }
+            self.next_check += 1;
+        }
+        if self.next_check < self.info.num_pieces() {
+            return Ok(true);
+        }
+        self.status = if self.have.is_full() { Seeding } else { Downloading };
+        Ok(false)
+    }
+
+    /// Fraction of the pieces checked, `None` unless checking
+    pub fn check_progress(&self) -> Option<f64> {
+        match self.status {
+            Checking if self.info.num_pieces() > 0 => Some(self.next_check as f64 / self.info.num_pieces() as f64),
+            Checking => Some(1.0),
+            _ => None
+        }
+    }
+
+    /// Start exchanging data with peers: `Downloading`, or `Seeding` when
+    /// we have every piece. A running check has to finish first.
+    pub fn start(&mut self) {
+        if self.status == Stopped {
+            self.status = if self.have.is_full() { Seeding } else { Downloading };
+        }
+    }
+
+    /// Stop a running check. The pieces found so far are kept.
+    pub fn cancel_check(&mut self) {
+        if self.status == Checking {
+            self.status = Stopped;
+        }
+    }
+
+    /// Fast-resume data for the torrent as it is now. Storage is flushed
+    /// first so the recorded file sizes and mtimes are final. Partial
+    /// pieces only record the blocks `blocks_written` reported as stored.
+    pub fn resume_data(&mut self) -> StorageResult<ResumeData> {
+        try!(self.storage.flush());
+        Ok(ResumeData {
+            info_hash: self.info.infohash.clone(),
+            pieces: Vec::from_slice(self.have.as_bytes()),
+            partial: self.downloads.iter().map(|(&index, download)| {
+                let written = download.written_blocks();
+                let mut blocks = Bitfield::new(written.len());
+                for (block, &written) in written.iter().enumerate() {
+                    if written {
+                        blocks.set(block);
+                    }
+                }
+                (index, Vec::from_slice(blocks.as_bytes()))
+            }).collect(),
+            files: self.storage.file_stats(),
+            uploaded: self.traffic.uploaded_bytes,
+            downloaded: self.traffic.downloaded_bytes,
+            duplicate: self.traffic.duplicate_bytes,
+            trackers: self.trackers.clone(),
+            peers: self.session.peers.clone(),
+        })
+    }
+
+    /// Restore the state saved in `resume`. The pieces and partial pieces
+    /// are only trusted when the files are the same as when it was saved;
+    /// otherwise the torrent starts `Checking`. Returns whether the pieces
+    /// were trusted.
+    pub fn apply_resume(&mut self, resume: &ResumeData) -> bool {
+        if resume.info_hash != self.info.infohash {
+            return false;
+        }
+        self.traffic.uploaded_bytes = resume.uploaded;
+        self.traffic.downloaded_bytes = resume.downloaded;
+        self.traffic.duplicate_bytes = resume.duplicate;
+        for saved in resume.trackers.iter() {
+            match self.trackers.iter().position(|tracker| tracker.url == saved.url) {
+                Some(position) => {
+                    let tracker = self.trackers.get_mut(position);
+                    tracker.last_announce = saved.last_announce;
+                    tracker.next_announce = saved.next_announce;
+                    tracker.seeders = saved.seeders;
+                    tracker.leechers = saved.leechers;
+                    tracker.downloaded = saved.downloaded;
+                    tracker.tracker_id = saved.tracker_id.clone();
+                    tracker.failures = saved.failures;
+                    tracker.disabled = saved.disabled;
+                },
+                // learned through lt_tex
+                None => self.trackers.push(saved.clone())
+            }
+        }
+        for peer in resume.peers.iter() {
+            if !self.session.peers.iter().any(|known| known.address == peer.address) {
+                self.session.peers.push(peer.clone());
+            }
+        }
+
+        let have = Bitfield::from_bytes(resume.pieces.as_slice(), self.info.num_pieces());
+        if have.is_none() || resume.files != self.storage.file_stats() {
+            self.start_check();
+            return false;
+        }
+        self.have = have.unwrap();
+        self.downloads.clear();
+        for &(index, ref blocks) in resume.partial.iter() {
+            if index >= self.info.num_pieces() || self.have.get(index) {
+                continue;
+            }
+            let layout = self.info.blocks(index);
+            let received = match Bitfield::from_bytes(blocks.as_slice(), layout.len()) {
+                Some(received) => received,
+                None => continue
+            };
+            let received: Vec<bool> = range(0, layout.len()).map(|block| received.get(block)).collect();
+            self.downloads.insert(index, PieceDownload::restore(index, layout, received.as_slice()));
+            self.picker.piece_started(index);
+        }
+        self.status = if self.have.is_full() { Seeding } else { Downloading };
+        true
+    }
+
+    /// Next piece to download from the peer on `connection`
+    pub fn pick_piece(&self, connection: &PeerConnection) -> Option<uint> {
+        match connection.bitfield {
+            Some(ref peer_has) => self.picker.pick(&self.have, peer_has, &self.availability),
+            None => None
+        }
+    }
+
+    /// Fraction of the pieces we have
+    pub fn progress(&self) -> f64 {
+        if self.have.len() == 0 {
+            return 1.0;
+        }
+        self.have.count() as f64 / self.have.len() as f64
+    }
+
+    /// Pass a message from a peer of this torrent to its connection, keeping
+    /// piece availability in sync with what the peer announces
+    pub fn receive(&mut self, connection: &mut PeerConnection, message: &Message, now: u64) -> Result<(), ProtocolError> {
+        let new_piece = match *message {
+            Have(index) if (index as uint) < self.have.len() => !connection.has_piece(index as uint),
+            _ => false
+        };
+        match *message {
+            Request(piece, begin, length) if !self.can_serve(piece, begin, length) => {
+                return Err(InvalidRequest(BlockRequest { piece: piece, begin: begin, length: length }));
+            },
+            _ => ()
+        }
+        match *message {
+            wire::Choke => {
+                // the peer drops our requests, someone else may serve them
+                for request in connection.our_requests.iter() {
+                    self.request_failed(request, &connection.address);
+                }
+            },
+            RejectRequest(piece, begin, length) if connection.fast => {
+                self.request_failed(&BlockRequest { piece: piece, begin: begin, length: length }, &connection.address);
+            },
+            _ => ()
+        }
+        let interest_changed = match *message {
+            wire::Interested => !connection.peer_interested,
+            wire::NotInterested => connection.peer_interested,
+            _ => false
+        };
+        try!(connection.receive(message, now));
+        if interest_changed {
+            // an interested peer may take a free upload slot right away
+            // rather than at the next rechoke
+            self.choker.schedule();
+        }
+        match *message {
+            Have(index) if new_piece => self.availability.add_piece(index as uint),
+            wire::Bitfield(..) | HaveAll => self.availability.add_bitfield(connection.bitfield.as_ref().unwrap()),
+            wire::Extended(id, ref payload) if id == tex::MESSAGE_ID => self.receive_tex(payload.as_slice()),
+            _ => ()
+        }
+        Ok(())
+    }
+
+    /// Whether we're in end-game mode: every block we still want has been
+    /// requested, so the remaining ones are requested from several peers at
+    /// once rather than waiting for slow peers to deliver them
+    pub fn in_endgame(&self) -> bool {
+        let unstarted = range(0, self.have.len()).any(|index| {
+            !self.have.get(index) && self.picker.priority(index) != PRIORITY_SKIP && !self.picker.is_partial(index)
+        });
+        !unstarted && !self.downloads.is_empty() && self.downloads.values().all(|download| download.is_fully_requested())
+    }
+
+    /// Next block to request from the peer on `connection`, marked as
+    /// requested from it. `None` if the peer has nothing we want.
+    pub fn next_request(&mut self, connection: &PeerConnection) -> Option<BlockRequest> {
+        let peer = connection.address;
+        let endgame = self.in_endgame();
+        let mut request = None;
+        for &index in self.picker.partial_pieces().iter() {
+            if !connection.has_piece(index) {
+                continue;
+            }
+            request = self.downloads.find(&index).and_then(|download| download.pick_block(&peer, endgame));
+            if request.is_some() {
+                break;
+            }
+        }
+        if request.is_none() && !endgame {
+            request = self.pick_piece_new(connection).and_then(|index| {
+                let download = PieceDownload::new(index, self.info.blocks(index));
+                let request = download.pick_block(&peer, false);
+                self.downloads.insert(index, download);
+                self.picker.piece_started(index);
+                request
+            });
+        }
+        match request {
+            Some(ref request) => self.downloads.find_mut(&(request.piece as uint)).unwrap().mark_requested(request, peer),
+            None => ()
+        }
+        request
+    }
+
+    /// Whether a peer may request the block: it's inside a piece we have,
+    /// and so have told the peer about, and no bigger than a block
+    fn can_serve(&self, piece: u32, begin: u32, length: u32) -> bool {
+        let piece = piece as uint;
+        length > 0 && length as uint <= BLOCK_SIZE
+            && piece < self.have.len() && self.have.get(piece)
+            && begin as uint + length as uint <= self.info.piece_size(piece)
+    }
+
+    /// Send a block read from storage for `request` of the peer on
+    /// `connection`, see `DiskPool::read`. Returns whether it was sent:
+    /// the peer may have cancelled the request, or been choked, while the
+    /// block was read.
+    pub fn serve_block(&mut self, connection: &mut PeerConnection, request: &BlockRequest, data: Vec<u8>, now: u64) -> bool {
+        if !connection.peer_requests.iter().any(|queued| queued == request) {
+            return false;
+        }
+        self.traffic.uploaded_bytes += data.len();
+        connection.send(Piece(request.piece, request.begin, data), now).unwrap();
+        true
+    }
+
+    /// The block for `request` of the peer on `connection` couldn't be read
+    /// from storage. The request is dropped, and rejected if the peer
+    /// speaks the fast extension so it can ask someone else.
+    pub fn serve_failed(&mut self, connection: &mut PeerConnection, request: &BlockRequest, now: u64) {
+        if connection.fast {
+            connection.send(RejectRequest(request.piece, request.begin, request.length), now).unwrap();
+        } else {
+            let remaining: RingBuf<BlockRequest> = connection.peer_requests.iter().filter(|queued| *queued != request).map(|queued| queued.clone()).collect();
+            connection.peer_requests = remaining;
+        }
+    }
+
+    /// Choke and unchoke the peers on `connections` as the choker decides,
+    /// if a rechoke is due. `default_slots` is the client's number of upload
+    /// slots, used unless the torrent has its own.
+    pub fn rechoke(&mut self, connections: &mut [PeerConnection], default_slots: uint, now: u64) {
+        if !self.choker.is_due(now) {
+            return;
+        }
+        let stats: Vec<PeerStats> = connections.iter().map(PeerStats::from_connection).collect();
+        let slots = self.upload_slots.unwrap_or(default_slots);
+        let unchoked = self.choker.rechoke(stats.as_slice(), slots, self.status == Seeding, now);
+        for connection in connections.mut_iter() {
+            let message = if unchoked.contains(&connection.address) { wire::Unchoke } else { wire::Choke };
+            // choking and unchoking are always allowed
+            connection.send(message, now).unwrap();
+        }
+    }
+
+    /// Tell the peer on `connection` whether it has pieces we want
+    pub fn update_interest(&self, connection: &mut PeerConnection, now: u64) {
+        let interested = self.status == Downloading && self.pick_piece(connection).is_some();
+        // interest can always be sent, and is only sent when it changes
+        connection.send(if interested { wire::Interested } else { wire::NotInterested }, now).unwrap();
+    }
+
+    /// Top up the requests queued with the peer on `connection` to its
+    /// request depth
+    pub fn fill_requests(&mut self, connection: &mut PeerConnection, now: u64) {
+        if !connection.can_request() {
+            return;
+        }
+        while connection.our_requests.len() < connection.request_depth() {
+            match self.next_request(connection) {
+                Some(request) => connection.send(Request(request.piece, request.begin, request.length), now).unwrap(),
+                None => break
+            }
+        }
+    }
+
+    /// Give up on the requests the peer on `connection` has left unanswered
+    /// for too long, so other peers can be asked for the blocks
+    pub fn expire_requests(&mut self, connection: &mut PeerConnection, now: u64) {
+        for request in connection.expire_requests(now).iter() {
+            self.request_failed(request, &connection.address);
+        }
+    }
+
+    fn pick_piece_new(&self, connection: &PeerConnection) -> Option<uint> {
+        match connection.bitfield {
+            Some(ref peer_has) => self.picker.pick_new(&self.have, peer_has, &self.availability),
+            None => None
+        }
+    }
+
+    /// A block we requested arrived from `peer`. The result says which other
+    /// peers to cancel the block with, and whether the piece is complete.
+    /// `None` if we aren't downloading the block at all.
+    pub fn block_received(&mut self, request: &BlockRequest, peer: &SocketAddr) -> Option<BlockReceived> {
+        let received = match self.downloads.find_mut(&(request.piece as uint)) {
+            Some(download) => download.block_received(request, peer),
+            None => None
+        };
+        match received {
+            Some(ref received) if received.duplicate => self.traffic.duplicate_bytes += request.length as uint,
+            Some(_) => self.traffic.downloaded_bytes += request.length as uint,
+            None => self.traffic.duplicate_bytes += request.length as uint
+        }
+        received
+    }
+
+    /// `length` bytes at `begin` of `piece` were written to storage, see
+    /// `DiskPool::write`
+    pub fn blocks_written(&mut self, piece: uint, begin: uint, length: uint) {
+        match self.downloads.find_mut(&piece) {
+            Some(download) => download.blocks_written(begin, length),
+            None => ()
+        }
+    }
+
+    /// A request to `peer` won't be answered, the block can be requested
+    /// from someone else
+    pub fn request_failed(&mut self, request: &BlockRequest, peer: &SocketAddr) {
+        match self.downloads.find_mut(&(request.piece as uint)) {
+            Some(download) => download.request_failed(request, peer),
+            None => ()
+        }
+    }
+
+    /// Piece `index` was read back from storage as `data` and compared with
+    /// its hash. A good piece is added to the pieces we have and should be
+    /// announced to every peer with `have`; a bad one is thrown away and
+    /// downloaded again, and the peers that sent it lose trust. Once a piece
+    /// that failed before passes, the peers that sent corrupt blocks for it
+    /// are banned, see `smartban`; connections to `is_banned` peers should
+    /// be dropped after every call.
+    pub fn piece_hashed(&mut self, index: uint, passed: bool, data: &[u8]) {
+        let (contributors, senders) = match self.downloads.find(&index) {
+            Some(download) => (download.contributors(), download.senders()),
+            None => (Vec::new(), Vec::new())
+        };
+        if passed {
+            self.downloads.remove(&index);
+            self.picker.piece_stopped(index);
+            self.have.set(index);
+            if self.have.is_full() && self.status == Downloading {
+                self.status = Seeding;
+                self.completed = true;
+            }
+            for peer in contributors.iter() {
+                self.adjust_trust(peer.ip, TRUST_GAIN);
+            }
+            self.smart_ban.piece_passed(index, data);
+        } else {
+            self.hash_failures += 1;
+            match self.downloads.find_mut(&index) {
+                Some(download) => download.reset(),
+                None => ()
+            }
+            for peer in contributors.iter() {
+                self.adjust_trust(peer.ip, -TRUST_PENALTY);
+            }
+            let senders: Vec<(BlockRequest, IpAddr)> = senders.move_iter().map(|(block, peer)| (block, peer.ip)).collect();
+            self.smart_ban.piece_failed(index, senders.as_slice(), data);
+        }
+    }
+
+    fn adjust_trust(&mut self, ip: IpAddr, change: int) {
+        let trust = self.trust.find_or_insert(ip, 0);
+        *trust = (*trust + change).max(MIN_TRUST).min(MAX_TRUST);
+    }
+
+    /// Trust in the peers at `ip`, 0 for peers we know nothing about
+    pub fn trust(&self, ip: IpAddr) -> int {
+        self.trust.find(&ip).map_or(0, |trust| *trust)
+    }
+
+    /// Whether peers at `ip` were caught sending corrupt blocks, or sent so
+    /// many bad pieces that they should be disconnected and not connected
+    /// to again
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.smart_ban.is_banned(ip) || self.trust(ip) <= MIN_TRUST
+    }
+
+    /// A peer of this torrent disconnected, its pieces are no longer available
+    pub fn peer_disconnected(&mut self, connection: &PeerConnection) {
+        match connection.bitfield {
+            Some(ref bitfield) => self.availability.remove_bitfield(bitfield),
+            None => ()
+        }
+        for request in connection.our_requests.iter() {
+            self.request_failed(request, &connection.address);
+        }
+        self.tex.peer_disconnected(&connection.address);
+    }
+
+    /// The message telling a newly connected peer which pieces we have.
+    /// `None` when there's nothing to send, which only happens without the
+    /// fast extension when we have no pieces.
+    pub fn bitfield_message(&self, fast: bool) -> Option<Message> {
+        if fast && self.have.is_full() {
+            Some(HaveAll)
+        } else if self.have.is_empty() {
+            if fast { Some(HaveNone) } else { None }
+        } else {
+            Some(wire::Bitfield(Vec::from_slice(self.have.as_bytes())))
+        }
+    }
+
     // oh god, i hope this goes away soon
     pub fn scrape_url(&self) -> Url {
         from_str(self.info.announce.replace("announce", "scrape").as_slice()).unwrap()
@@ -229,7 +971,7 @@ impl Torrent {
     fn _scrape_url(&self) -> String {
         self.info.announce.replace("announce", "scrape")
     }
-    pub fn scrape(&self) -> Option<TorrentScrape> {
+    pub fn scrape(&mut self) -> Option<TorrentScrape> {
         let mut scrape_url = self._scrape_url();
         scrape_url = scrape_url.append(String::from_str("?info_hash=").append(self.info.urlencoded_hash().as_slice()).as_slice());
         let response = match curl::http::handle().get(scrape_url.as_slice()).exec() {
@@ -245,25 +987,301 @@ impl Torrent {
             Some(scrape) => scrape,
             _ => return None
         };
-        scrape.torrents.find(&self.info.infohash).map(|x| (*x).clone())
-    }
-    pub fn announce(&self, peer_id: String) -> Option<AnnounceResponse> {
-        use announce::{Failure};
-        let mut query = String::from_str("?");
-        for &(key, ref value) in vec![("info_hash", self.info.urlencoded_hash()),
-                                      ("peer_id", peer_id),
-                                      ("port", 44000u.to_str()), 
-                                      ("uploaded", self.traffic.uploaded_bytes.to_str()),
-                                      ("downloaded", self.traffic.downloaded_bytes.to_str()),
-                                      ("left", self.info.payload_size().to_str()),
-                                      ("event", String::from_str("started")),
+        let result = scrape.torrents.find(&self.info.infohash).map(|x| (*x).clone());
+        match result {
+            Some(ref result) => {
+                let announce = self.info.announce.clone();
+                for tracker in self.trackers.mut_iter().filter(|tracker| tracker.url == announce) {
+                    tracker.seeders = Some(result.complete);
+                    tracker.leechers = Some(result.incomplete);
+                    tracker.downloaded = Some(result.downloaded);
+                }
+            },
+            None => ()
+        }
+        result
+    }
+    /// Earliest time one of the trackers is due for an announce, `None` if
+    /// every tracker is disabled
+    pub fn next_announce(&self) -> Option<Timespec> {
+        self.trackers.iter()
+            .filter(|tracker| !tracker.disabled)
+            .map(|tracker| tracker.next_announce.unwrap_or(Timespec::new(0, 0)))
+            .min()
+    }
+
+    /// Announce to the trackers that are due in tier order until one of them
+    /// answers, recording the outcome of every attempt in `trackers`
+    pub fn announce(&mut self, peer_id: String) -> Option<AnnounceResponse> {
+        match self.announce_job(peer_id) {
+            Some(job) => self.announce_done(job.run()),
+            None => None
+        }
+    }
+
+    /// The requests of an announce to the trackers that are due, to be run
+    /// anywhere and passed back to `announce_done`. `None` if no tracker is
+    /// due.
+    pub fn announce_job(&mut self, peer_id: String) -> Option<AnnounceJob> {
+        let now = get_time();
+        let completed = self.completed;
+        let mut trackers = Vec::new();
+        for (index, tracker) in self.trackers.mut_iter().enumerate() {
+            if tracker.is_due(now) {
+                tracker.event = tracker.next_event(completed);
+                trackers.push((index, tracker.url.clone(), tracker.tracker_id.clone(), tracker.event.clone()));
+            }
+        }
+        if trackers.is_empty() {
+            return None;
+        }
+        Some(self.job(peer_id, trackers))
+    }
+
+    /// Bytes of the pieces we don't have yet
+    pub fn left(&self) -> uint {
+        range(0, self.info.num_pieces())
+            .filter(|&index| !self.have.get(index))
+            .map(|index| self.info.piece_size(index))
+            .sum()
+    }
+
+    fn job(&self, peer_id: String, trackers: Vec<(uint, String, Option<String>, Option<AnnounceEvent>)>) -> AnnounceJob {
+        AnnounceJob {
+            info_hash: self.info.infohash.clone(),
+            urlencoded_hash: self.info.urlencoded_hash(),
+            peer_id: peer_id,
+            port: self.listen_port,
+            uploaded: self.traffic.uploaded_bytes,
+            downloaded: self.traffic.downloaded_bytes,
+            left: self.left(),
+            trackers: trackers,
+        }
+    }
+
+    /// Record the responses of an `AnnounceJob` in `trackers` and add the
+    /// peers they returned. A tracker that answered moves to the front of
+    /// its tier (BEP 12). Returns the last response, as `announce` does.
+    pub fn announce_done(&mut self, results: Vec<(uint, Option<AnnounceResponse>)>) -> Option<AnnounceResponse> {
+        let mut last_response = None;
+        let mut answered = None;
+        for (index, response) in results.move_iter() {
+            if index >= self.trackers.len() {
+                continue;
+            }
+            self.trackers.get_mut(index).update(&response, get_time());
+            match response {
+                Some(Success(ref result)) => {
+                    for peer in result.peers.iter() {
+                        if !self.session.peers.contains(peer) {
+                            self.session.peers.push(peer.clone());
+                        }
+                    }
+                    answered = Some(index);
+                },
+                _ => ()
+            }
+            last_response = response;
+        }
+        // moving it shifts the trackers after it, so it waits for the loop
+        match answered {
+            Some(index) => self.promote_tracker(index),
+            None => ()
+        }
+        last_response
+    }
+
+    /// Move the tracker at `index` in front of the others of its tier
+    fn promote_tracker(&mut self, index: uint) {
+        let tier = self.trackers.get(index).tier;
+        match self.trackers.iter().position(|tracker| tracker.tier == tier) {
+            Some(first) if first < index => {
+                let tracker = self.trackers.remove(index).unwrap();
+                self.trackers.insert(first, tracker);
+            },
+            _ => ()
+        }
+    }
+
+    /// URLs of the trackers that answered their last announce
+    pub fn working_trackers(&self) -> Vec<String> {
+        self.trackers.iter().filter(|tracker| tracker.working).map(|tracker| tracker.url.clone()).collect()
+    }
+
+    /// `lt_tex` payload telling `peer` about the working trackers it hasn't
+    /// heard about from us yet. Private torrents never share trackers.
+    pub fn tex_message(&mut self, peer: SocketAddr) -> Option<Vec<u8>> {
+        if self.info.metainfo.private {
+            return None;
+        }
+        let working = self.working_trackers();
+        let unsent = self.tex.unsent(peer, working.as_slice());
+        if unsent.is_empty() {
+            None
+        } else {
+            Some(tex::encode_message(unsent.as_slice()))
+        }
+    }
+
+    /// Handle an `lt_tex` message from a peer. The trackers in it are only
+    /// used once they answered an announce, see `tex_job`.
+    pub fn receive_tex(&mut self, payload: &[u8]) {
+        if self.info.metainfo.private {
+            return;
+        }
+        let known: Vec<String> = self.trackers.iter().map(|tracker| tracker.url.clone()).collect();
+        match tex::decode_message(payload) {
+            Some(urls) => self.tex.receive(urls, known.as_slice()),
+            None => ()
+        }
+    }
+
+    /// Our extended handshake (BEP 10), offering `lt_tex` with the hash of
+    /// the trackers we'd send. Private torrents offer nothing.
+    pub fn extended_handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::new();
+        if !self.info.metainfo.private {
+            handshake.messages.push((String::from_str(tex::EXTENSION_NAME), tex::MESSAGE_ID));
+            let hash = tex::tracker_list_hash(self.working_trackers().as_slice());
+            handshake.tracker_hash = Some(Vec::from_slice(hash));
+        }
+        handshake
+    }
+
+    /// Send `connection` the trackers it hasn't heard about from us, at most
+    /// every `TEX_INTERVAL`. Peers that don't speak `lt_tex`, or whose
+    /// tracker list hashes like ours, are sent nothing.
+    pub fn send_tex(&mut self, connection: &mut PeerConnection, now: u64) {
+        match connection.tex_sent_at {
+            Some(sent_at) if now - sent_at < tex::TEX_INTERVAL => return,
+            _ => ()
+        }
+        let (id, tracker_hash) = match connection.extended {
+            Some(ref handshake) => match handshake.id(tex::EXTENSION_NAME) {
+                Some(id) => (id, handshake.tracker_hash.clone()),
+                None => return
+            },
+            None => return
+        };
+        connection.tex_sent_at = Some(now);
+        let ours = tex::tracker_list_hash(self.working_trackers().as_slice());
+        if tracker_hash == Some(Vec::from_slice(ours)) {
+            return;
+        }
+        match self.tex_message(connection.address) {
+            Some(payload) => connection.send(wire::Extended(id, payload), now).unwrap(),
+            None => ()
+        }
+    }
+
+    /// Announce to the trackers learned through `lt_tex`, see `tex_job`
+    pub fn verify_tex_trackers(&mut self, peer_id: String) {
+        match self.tex_job(peer_id) {
+            Some(job) => self.tex_done(job.run()),
+            None => ()
+        }
+    }
+
+    /// The announces to the trackers learned through `lt_tex`, to be run
+    /// anywhere and passed back to `tex_done`. `None` if there are none.
+    pub fn tex_job(&mut self, peer_id: String) -> Option<TexJob> {
+        if self.info.metainfo.private {
+            return None;
+        }
+        let urls = self.tex.take_candidates();
+        if urls.is_empty() {
+            return None;
+        }
+        Some(TexJob { job: self.job(peer_id, Vec::new()), urls: urls })
+    }
+
+    /// Record the responses of a `TexJob`. The trackers that answered join
+    /// the tracker list in a tier after the torrent's own, the others are
+    /// never tried again.
+    pub fn tex_done(&mut self, results: Vec<(String, Option<AnnounceResponse>)>) {
+        let tier = self.trackers.iter().map(|tracker| tracker.tier + 1).max().unwrap_or(0);
+        for (url, response) in results.move_iter() {
+            if self.trackers.iter().any(|tracker| tracker.url == url) {
+                continue;
+            }
+            match response {
+                Some(Success(ref result)) => {
+                    let mut tracker = TrackerStatus::new(url.clone(), tier);
+                    tracker.event = Some(Started);
+                    tracker.update(&response, get_time());
+                    self.trackers.push(tracker);
+                    for peer in result.peers.iter() {
+                        if !self.session.peers.contains(peer) {
+                            self.session.peers.push(peer.clone());
+                        }
+                    }
+                },
+                _ => self.tex.reject(url)
+            }
+        }
+    }
+}
+
+/// The tracker requests of an announce, copied out of the torrent so they
+/// can block on another thread
+pub struct AnnounceJob {
+    pub info_hash: Vec<u8>,
+    urlencoded_hash: String,
+    peer_id: String,
+    port: u16,
+    uploaded: uint,
+    downloaded: uint,
+    left: uint,
+    /// Index, URL, tracker ID and event of the trackers due, in tier order
+    trackers: Vec<(uint, String, Option<String>, Option<AnnounceEvent>)>,
+}
+
+impl AnnounceJob {
+    /// Announce to the trackers in order until one of them answers. Returns
+    /// the index in `Torrent::trackers` of every tracker tried, with its
+    /// response.
+    pub fn run(&self) -> Vec<(uint, Option<AnnounceResponse>)> {
+        let mut results = Vec::new();
+        for &(index, ref url, ref tracker_id, ref event) in self.trackers.iter() {
+            let response = self.announce_to(url.as_slice(), tracker_id.clone(), event.clone());
+            let answered = match response {
+                Some(Success(_)) => true,
+                _ => false
+            };
+            results.push((index, response));
+            if answered {
+                break;
+            }
+        }
+        results
+    }
+    /// Announce to the tracker at `url`, `None` if it couldn't be reached
+    fn announce_to(&self, url: &str, tracker_id: Option<String>, event: Option<AnnounceEvent>) -> Option<AnnounceResponse> {
+        if url.starts_with("udp://") {
+            return self.announce_udp(url, event);
+        }
+        let mut query = String::from_str(if url.contains_char('?') { "&" } else { "?" });
+        for &(key, ref value) in vec![("info_hash", self.urlencoded_hash.clone()),
+                                      ("peer_id", self.peer_id.clone()),
+                                      ("port", self.port.to_str()),
+                                      ("uploaded", self.uploaded.to_str()),
+                                      ("downloaded", self.downloaded.to_str()),
+                                      ("left", self.left.to_str()),
                                       ("key", "BqNcyuLEsZ".to_str()),//random_string(10)),
                                       ("compact", 1u.to_str())].iter() {
             query.push_str(format!("{}={}&", key, value).as_slice());
         }
-        let url = self.info.announce.clone().append(query.as_slice());
-        println!("{}", url);
-        let response = match curl::http::handle().get(url.as_slice()).exec() {
+        match event {
+            Some(Started) => query.push_str("event=started&"),
+            Some(Completed) => query.push_str("event=completed&"),
+            _ => ()
+        }
+        match tracker_id {
+            Some(ref tracker_id) => query.push_str(format!("trackerid={}&", url::encode_component(tracker_id.as_slice())).as_slice()),
+            None => ()
+        }
+        let url = String::from_str(url).append(query.as_slice());
+        let response = match curl::http::handle().timeout(ANNOUNCE_TIMEOUT).get(url.as_slice()).exec() {
             Ok(response) => response,
             _ => return None
         };
@@ -272,11 +1290,53 @@ impl Torrent {
             Ok(bencode) => {
                 match FromBencode::from_bencode(&bencode) {
                     Some(result) => result,
-                    _ => Failure("error".to_str())
+                    _ => Failure("error".to_str(), None)
                 }
             },
             _ => return None
         };
         Some(announce_response)
     }
+    fn announce_udp(&self, url: &str, event: Option<AnnounceEvent>) -> Option<AnnounceResponse> {
+        use std::io::net::ip::Ipv4Addr;
+        use tracker::AnnounceRequest;
+        use tracker::udp::{UdpTrackerClient, tracker_address};
+        let tracker = match tracker_address(url) {
+            Some(tracker) => tracker,
+            None => return None
+        };
+        let mut id = [0u8, ..20];
+        id.copy_from(self.peer_id.as_bytes());
+        let request = AnnounceRequest {
+            info_hash: self.info_hash.clone(),
+            peer_id: id,
+            address: SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: self.port },
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left,
+            event: event,
+            numwant: None,
+        };
+        UdpTrackerClient::new(tracker).and_then(|mut client| {
+            // connecting and announcing wait up to 1 + 2 timeouts each
+            client.timeout = ANNOUNCE_TIMEOUT as u64 / 6;
+            client.retries = 1;
+            client.announce(&request)
+        }).ok()
+    }
+}
+
+/// Announces to the trackers learned through `lt_tex`, copied out of the
+/// torrent like an `AnnounceJob`
+pub struct TexJob {
+    job: AnnounceJob,
+    urls: Vec<String>,
+}
+
+impl TexJob {
+    /// Announce to every candidate tracker, returning each URL with its
+    /// response
+    pub fn run(&self) -> Vec<(String, Option<AnnounceResponse>)> {
+        self.urls.iter().map(|url| (url.clone(), self.job.announce_to(url.as_slice(), None, Some(Started)))).collect()
+    }
 }
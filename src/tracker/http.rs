@@ -0,0 +1,246 @@
+//! HTTP frontend for the tracker, serving `/announce` and `/scrape`
+
+use std::io::{IoResult, BufferedReader, Listener, Acceptor};
+use std::io::net::tcp::{TcpListener, TcpAcceptor, TcpStream};
+use std::io::net::ip::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomics::{AtomicUint, SeqCst};
+
+use bencode::ToBencode;
+
+use announce::Failure;
+use tracker::{SwarmStore, AnnounceRequest, Started, Completed, Stopped};
+
+
+/// Connections served at once by default; more are closed right away
+pub static DEFAULT_MAX_CONNECTIONS: uint = 64;
+/// Longest request or header line we read, in bytes
+pub static MAX_LINE_LENGTH: uint = 4096;
+
+pub struct HttpTracker {
+    store: Arc<Mutex<SwarmStore>>,
+    acceptor: TcpAcceptor,
+    address: SocketAddr,
+    /// Connections being served
+    active: Arc<AtomicUint>,
+    pub max_connections: uint,
+}
+
+impl HttpTracker {
+    pub fn bind(store: Arc<Mutex<SwarmStore>>, address: SocketAddr) -> IoResult<HttpTracker> {
+        let mut listener = try!(TcpListener::bind(address.ip.to_str().as_slice(), address.port));
+        let address = try!(listener.socket_name());
+        Ok(HttpTracker {
+            store: store,
+            acceptor: try!(listener.listen()),
+            address: address,
+            active: Arc::new(AtomicUint::new(0)),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    pub fn socket_name(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Accept connections forever, serving each one on its own task, up to
+    /// `max_connections` at once
+    pub fn serve(&mut self) -> IoResult<()> {
+        for stream in self.acceptor.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+            if self.active.load(SeqCst) >= self.max_connections {
+                // dropping the stream closes the connection
+                continue;
+            }
+            self.active.fetch_add(1, SeqCst);
+            let (store, active) = (self.store.clone(), self.active.clone());
+            spawn(proc() {
+                let _ = handle_connection(stream, store);
+                active.fetch_sub(1, SeqCst);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, store: Arc<Mutex<SwarmStore>>) -> IoResult<()> {
+    let remote = try!(stream.peer_name());
+    stream.set_read_timeout(Some(10000));
+    let target = {
+        let mut reader = BufferedReader::new(stream.clone());
+        let request_line = match try!(read_line(&mut reader)) {
+            Some(line) => line,
+            None => return write_response(&mut stream, "400 Bad Request", [])
+        };
+        // skip the headers, nothing in them is of interest
+        loop {
+            match try!(read_line(&mut reader)) {
+                Some(ref line) if line.as_slice().trim().is_empty() => break,
+                Some(_) => (),
+                None => return write_response(&mut stream, "400 Bad Request", [])
+            }
+        }
+        let mut parts = request_line.as_slice().trim().split(' ');
+        match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => target.to_str(),
+            _ => return write_response(&mut stream, "400 Bad Request", [])
+        }
+    };
+    let (path, query) = match target.as_slice().find('?') {
+        Some(index) => (target.as_slice().slice_to(index), target.as_slice().slice_from(index + 1)),
+        None => (target.as_slice(), "")
+    };
+    let params = match parse_query(query) {
+        Some(params) => params,
+        None => return write_response(&mut stream, "400 Bad Request", [])
+    };
+    let body = match path {
+        "/announce" => {
+            let compact = param(&params, "compact").map(|value| value.as_slice() == b"1").unwrap_or(true);
+            let response = match parse_announce(&params, remote) {
+                Ok(request) => store.lock().announce(&request),
+                Err(message) => Failure(message.to_str(), None)
+            };
+            response.to_bencode(compact)
+        },
+        "/scrape" => {
+            let info_hashes: Vec<Vec<u8>> = params.iter()
+                .filter(|&&(ref key, _)| key.as_slice() == "info_hash")
+                .map(|&(_, ref value)| value.clone())
+                .collect();
+            store.lock().scrape(info_hashes.as_slice()).to_bencode()
+        },
+        _ => return write_response(&mut stream, "404 Not Found", [])
+    };
+    write_response(&mut stream, "200 OK", try!(body.to_bytes()).as_slice())
+}
+
+/// The next line of `reader`, `None` if it's longer than `MAX_LINE_LENGTH`
+/// or not UTF-8
+fn read_line<R: Reader>(reader: &mut R) -> IoResult<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        let byte = try!(reader.read_byte());
+        if byte == b'\n' {
+            return Ok(String::from_utf8(line).ok());
+        }
+        if line.len() >= MAX_LINE_LENGTH {
+            return Ok(None);
+        }
+        line.push(byte);
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> IoResult<()> {
+    try!(stream.write_str(format!("HTTP/1.0 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+                                  status, body.len()).as_slice()));
+    try!(stream.write(body));
+    stream.flush()
+}
+
+fn parse_announce(params: &Vec<(String, Vec<u8>)>, remote: SocketAddr) -> Result<AnnounceRequest, &'static str> {
+    let info_hash = match param(params, "info_hash") {
+        Some(info_hash) if info_hash.len() == 20 => info_hash.clone(),
+        _ => return Err("invalid info_hash")
+    };
+    let peer_id = match param(params, "peer_id") {
+        Some(peer_id) if peer_id.len() == 20 => {
+            let mut bytes = [0u8, ..20];
+            bytes.copy_from(peer_id.as_slice());
+            bytes
+        },
+        _ => return Err("invalid peer_id")
+    };
+    let port = match number_param(params, "port") {
+        Some(port) if port > 0 && port <= 65535 => port as u16,
+        _ => return Err("invalid port")
+    };
+    // the client may tell us its address, otherwise use the one it connected from
+    let ip = match param(params, "ip").and_then(|ip| String::from_utf8(ip.clone()).ok()) {
+        Some(ip) => match from_str(ip.as_slice()) {
+            Some(ip) => ip,
+            None => return Err("invalid ip")
+        },
+        None => remote.ip
+    };
+    let event = param(params, "event").and_then(|event| String::from_utf8(event.clone()).ok()).unwrap_or(String::new());
+    let event = match event.as_slice() {
+        "started" => Some(Started),
+        "completed" => Some(Completed),
+        "stopped" => Some(Stopped),
+        "" => None,
+        _ => return Err("invalid event")
+    };
+    Ok(AnnounceRequest {
+        info_hash: info_hash,
+        peer_id: peer_id,
+        address: SocketAddr { ip: ip, port: port },
+        uploaded: number_param(params, "uploaded").unwrap_or(0),
+        downloaded: number_param(params, "downloaded").unwrap_or(0),
+        left: number_param(params, "left").unwrap_or(0),
+        event: event,
+        numwant: number_param(params, "numwant"),
+    })
+}
+
+fn param<'a>(params: &'a Vec<(String, Vec<u8>)>, key: &str) -> Option<&'a Vec<u8>> {
+    params.iter().find(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref value)| value)
+}
+
+fn number_param(params: &Vec<(String, Vec<u8>)>, key: &str) -> Option<uint> {
+    param(params, key)
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+        .and_then(|value| from_str(value.as_slice()))
+}
+
+/// Split a query string into its key-value pairs. Values are kept as raw
+/// bytes because `info_hash` and `peer_id` are binary.
+pub fn parse_query(query: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    let mut params = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(index) => (pair.slice_to(index), pair.slice_from(index + 1)),
+            None => (pair, "")
+        };
+        let key = match percent_decode(key).and_then(|key| String::from_utf8(key).ok()) {
+            Some(key) => key,
+            None => return None
+        };
+        let value = match percent_decode(value) {
+            Some(value) => value,
+            None => return None
+        };
+        params.push((key, value));
+    }
+    Some(params)
+}
+
+/// Decode `%XX` escapes and `+` for space, `None` if an escape is cut short
+/// or isn't hex
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0u;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                if i + 3 > bytes.len() { return None; }
+                let hex = match ::std::str::from_utf8(bytes.slice(i + 1, i + 3)) {
+                    Some(hex) => hex,
+                    None => return None
+                };
+                match ::std::num::from_str_radix::<u8>(hex, 16) {
+                    Some(byte) => output.push(byte),
+                    None => return None
+                }
+                i += 3;
+            },
+            b'+' => { output.push(b' '); i += 1; },
+            byte => { output.push(byte); i += 1; }
+        }
+    }
+    Some(output)
+}
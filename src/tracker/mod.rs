@@ -0,0 +1,225 @@
+//! A small built-in BitTorrent tracker for private swarms.
+//!
+//! `SwarmStore` keeps track of every peer announced for every torrent and is
+//! shared by the frontends; `http` serves it over HTTP and `udp` over the
+//! BEP 15 UDP tracker protocol. `udp` also holds the client we announce to
+//! UDP trackers with.
+
+use std::collections::hashmap::{HashMap, HashSet};
+use std::io::net::ip::SocketAddr;
+use std::rand::{Rng, task_rng};
+
+use time;
+
+use announce::{AnnounceResponse, AnnounceResult, Success, Failure, RetryNever};
+use peer::Peer;
+use scrape::{ScrapeInfo, TorrentScrape};
+
+pub mod http;
+pub mod udp;
+
+
+#[deriving(Show, PartialEq, Clone)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+/// A single announce, as parsed by one of the tracker frontends
+#[deriving(Show, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: [u8, ..20],
+    pub address: SocketAddr,
+    pub uploaded: uint,
+    pub downloaded: uint,
+    pub left: uint,
+    pub event: Option<AnnounceEvent>,
+    pub numwant: Option<uint>,
+}
+
+#[deriving(Clone)]
+pub struct TrackerConfig {
+    /// Announce interval sent to clients, in seconds
+    pub interval: uint,
+    pub min_interval: Option<uint>,
+    /// Peers that haven't announced for this many seconds are dropped
+    pub peer_expiry: i64,
+    /// Number of peers returned when the client doesn't specify `numwant`
+    pub default_numwant: uint,
+    pub max_numwant: uint,
+    /// If set, only these infohashes are tracked
+    pub whitelist: Option<HashSet<Vec<u8>>>,
+}
+
+impl TrackerConfig {
+    pub fn new() -> TrackerConfig {
+        TrackerConfig {
+            interval: 1800,
+            min_interval: Some(900),
+            // two missed announces
+            peer_expiry: 3600,
+            default_numwant: 50,
+            max_numwant: 200,
+            whitelist: None,
+        }
+    }
+}
+
+struct SwarmPeer {
+    peer: Peer,
+    left: uint,
+    last_seen: i64,
+}
+
+struct Swarm {
+    peers: HashMap<Vec<u8>, SwarmPeer>,
+    downloaded: uint,
+}
+
+impl Swarm {
+    fn new() -> Swarm {
+        Swarm { peers: HashMap::new(), downloaded: 0 }
+    }
+
+    fn expire(&mut self, deadline: i64) {
+        let expired: Vec<Vec<u8>> = self.peers.iter()
+            .filter(|&(_, peer)| peer.last_seen < deadline)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        for peer_id in expired.iter() {
+            self.peers.remove(peer_id);
+        }
+    }
+
+    fn scrape(&self) -> TorrentScrape {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count();
+        TorrentScrape {
+            complete: complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() - complete,
+            name: None
+        }
+    }
+}
+
+/// Peer store shared by the tracker frontends
+pub struct SwarmStore {
+    config: TrackerConfig,
+    swarms: HashMap<Vec<u8>, Swarm>,
+}
+
+impl SwarmStore {
+    pub fn new(config: TrackerConfig) -> SwarmStore {
+        SwarmStore { config: config, swarms: HashMap::new() }
+    }
+
+    pub fn config<'a>(&'a self) -> &'a TrackerConfig {
+        &self.config
+    }
+
+    /// Whether `info_hash` may be tracked under the configured whitelist
+    pub fn is_allowed(&self, info_hash: &Vec<u8>) -> bool {
+        match self.config.whitelist {
+            Some(ref whitelist) => whitelist.contains(info_hash),
+            None => true
+        }
+    }
+
+    /// Record an announce and build the response for it
+    pub fn announce(&mut self, request: &AnnounceRequest) -> AnnounceResponse {
+        if !self.is_allowed(&request.info_hash) {
+            return Failure("torrent is not tracked".to_str(), Some(RetryNever));
+        }
+        let now = time::get_time().sec;
+        let deadline = now - self.config.peer_expiry;
+        let numwant = request.numwant.unwrap_or(self.config.default_numwant).min(self.config.max_numwant);
+        let (interval, min_interval) = (self.config.interval, self.config.min_interval);
+        let swarm = self.swarms.find_or_insert_with(request.info_hash.clone(), |_| Swarm::new());
+        swarm.expire(deadline);
+
+        let key = Vec::from_slice(request.peer_id.as_slice());
+        match request.event {
+            Some(Stopped) => { swarm.peers.remove(&key); },
+            _ => {
+                let newly_completed = match swarm.peers.find(&key) {
+                    Some(peer) => peer.left > 0 && request.left == 0,
+                    None => request.event == Some(Completed)
+                };
+                if newly_completed {
+                    swarm.downloaded += 1;
+                }
+                swarm.peers.insert(key.clone(), SwarmPeer {
+                    peer: Peer { address: request.address, peer_id: Some(request.peer_id) },
+                    left: request.left,
+                    last_seen: now
+                });
+            }
+        }
+
+        let mut peers: Vec<Peer> = swarm.peers.iter()
+            .filter(|&(peer_id, peer)| *peer_id != key && !(request.left == 0 && peer.left == 0))
+            .map(|(_, peer)| peer.peer.clone())
+            .collect();
+        task_rng().shuffle(peers.as_mut_slice());
+        peers.truncate(numwant);
+
+        let scrape = swarm.scrape();
+        Success(AnnounceResult {
+            warning_message: None,
+            interval: interval,
+            min_interval: min_interval,
+            tracker_id: None,
+            complete: scrape.complete,
+            incomplete: scrape.incomplete,
+            peers: peers,
+            external_ip: Some(request.address.ip),
+            retry_in: None,
+        })
+    }
+
+    /// Scrape the given infohashes, or every tracked torrent if `info_hashes`
+    /// is empty
+    pub fn scrape(&mut self, info_hashes: &[Vec<u8>]) -> ScrapeInfo {
+        let deadline = time::get_time().sec - self.config.peer_expiry;
+        let mut torrents = HashMap::new();
+        if info_hashes.is_empty() {
+            for (info_hash, swarm) in self.swarms.mut_iter() {
+                swarm.expire(deadline);
+                torrents.insert(info_hash.clone(), swarm.scrape());
+            }
+        } else {
+            for info_hash in info_hashes.iter() {
+                let allowed = self.is_allowed(info_hash);
+                match self.swarms.find_mut(info_hash) {
+                    Some(swarm) => {
+                        swarm.expire(deadline);
+                        torrents.insert(info_hash.clone(), swarm.scrape());
+                    },
+                    None if allowed => {
+                        torrents.insert(info_hash.clone(), Swarm::new().scrape());
+                    },
+                    None => ()
+                }
+            }
+        }
+        ScrapeInfo { torrents: torrents }
+    }
+
+    /// Drop expired peers from every swarm, and swarms that were never
+    /// completed by anyone and have no peers left
+    pub fn expire(&mut self) {
+        let deadline = time::get_time().sec - self.config.peer_expiry;
+        let mut empty = Vec::new();
+        for (info_hash, swarm) in self.swarms.mut_iter() {
+            swarm.expire(deadline);
+            if swarm.peers.is_empty() && swarm.downloaded == 0 {
+                empty.push(info_hash.clone());
+            }
+        }
+        for info_hash in empty.iter() {
+            self.swarms.remove(info_hash);
+        }
+    }
+}
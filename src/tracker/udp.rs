@@ -0,0 +1,495 @@
+//! UDP tracker protocol (BEP 15), both ends of it
+//!
+//! `UdpTrackerClient` announces to and scrapes UDP trackers; `UdpTracker`
+//! serves a `SwarmStore` to them.
+//!
+//! The server stores no connection IDs: they are derived from a secret, the
+//! client's address and the current minute, so the tracker only needs to
+//! recompute them to verify one.
+
+use std::io::{IoResult, IoError, OtherIoError, TimedOut, MemWriter, BufReader};
+use std::io::net::ip::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::net::udp::UdpSocket;
+use std::io::net::addrinfo::get_host_addresses;
+use std::collections::hashmap::HashMap;
+use std::rand::{Rng, task_rng, random};
+use std::sync::{Arc, Mutex};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use time;
+
+use announce::{AnnounceResponse, AnnounceResult, Success, Failure};
+use peer::Peer;
+use scrape::{ScrapeInfo, TorrentScrape};
+use tracker::{SwarmStore, AnnounceRequest, AnnounceEvent, Started, Completed, Stopped};
+
+
+pub static PROTOCOL_ID: u64 = 0x41727101980;
+
+pub static ACTION_CONNECT: u32 = 0;
+pub static ACTION_ANNOUNCE: u32 = 1;
+pub static ACTION_SCRAPE: u32 = 2;
+pub static ACTION_ERROR: u32 = 3;
+
+/// A connection ID may be used for this long after it was received
+static CONNECTION_ID_LIFETIME_NS: u64 = 60 * 1_000_000_000;
+
+pub fn event_to_u32(event: Option<AnnounceEvent>) -> u32 {
+    match event {
+        None => 0,
+        Some(Completed) => 1,
+        Some(Started) => 2,
+        Some(Stopped) => 3
+    }
+}
+
+pub fn event_from_u32(event: u32) -> Option<AnnounceEvent> {
+    match event {
+        1 => Some(Completed),
+        2 => Some(Started),
+        3 => Some(Stopped),
+        _ => None
+    }
+}
+
+/// Resolve the tracker address of an `udp://host:port/...` announce URL
+pub fn tracker_address(url: &str) -> Option<SocketAddr> {
+    if !url.starts_with("udp://") {
+        return None;
+    }
+    let authority = url.slice_from(6);
+    let authority = match authority.find('/') {
+        Some(index) => authority.slice_to(index),
+        None => authority
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(index) => (authority.slice_to(index), authority.slice_from(index + 1)),
+        None => return None
+    };
+    let port: u16 = match from_str(port) {
+        Some(port) => port,
+        None => return None
+    };
+    let host = host.trim_chars(|c: char| c == '[' || c == ']');
+    let ip = match from_str(host) {
+        Some(ip) => ip,
+        None => match get_host_addresses(host) {
+            Ok(ref addresses) if !addresses.is_empty() => *addresses.get(0),
+            _ => return None
+        }
+    };
+    Some(SocketAddr { ip: ip, port: port })
+}
+
+pub struct UdpTrackerClient {
+    socket: UdpSocket,
+    tracker: SocketAddr,
+    connection: Option<(u64, u64)>,
+    /// Milliseconds to wait for the first reply, doubled on every retry
+    pub timeout: u64,
+    pub retries: uint,
+}
+
+impl UdpTrackerClient {
+    /// Bind a local socket for talking to the tracker at `tracker`
+    pub fn new(tracker: SocketAddr) -> IoResult<UdpTrackerClient> {
+        let local = match tracker.ip {
+            Ipv4Addr(..) => SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 },
+            Ipv6Addr(..) => SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0), port: 0 }
+        };
+        Ok(UdpTrackerClient {
+            socket: try!(UdpSocket::bind(local)),
+            tracker: tracker,
+            connection: None,
+            // BEP 15 suggests 15 * 2 ^ n seconds
+            timeout: 15000,
+            retries: 2,
+        })
+    }
+
+    pub fn announce(&mut self, request: &AnnounceRequest) -> IoResult<AnnounceResponse> {
+        let connection_id = try!(self.connection_id());
+        let transaction_id = random::<u32>();
+        let mut packet = MemWriter::with_capacity(98);
+        try!(packet.write_be_u64(connection_id));
+        try!(packet.write_be_u32(ACTION_ANNOUNCE));
+        try!(packet.write_be_u32(transaction_id));
+        try!(packet.write(request.info_hash.as_slice()));
+        try!(packet.write(request.peer_id.as_slice()));
+        try!(packet.write_be_u64(request.downloaded as u64));
+        try!(packet.write_be_u64(request.left as u64));
+        try!(packet.write_be_u64(request.uploaded as u64));
+        try!(packet.write_be_u32(event_to_u32(request.event.clone())));
+        // the tracker uses the address the packet came from
+        try!(packet.write_be_u32(0));
+        try!(packet.write_be_u32(random::<u32>()));
+        try!(packet.write_be_i32(request.numwant.map(|n| n as i32).unwrap_or(-1)));
+        try!(packet.write_be_u16(request.address.port));
+
+        let reply = try!(self.transact(packet.unwrap().as_slice(), transaction_id));
+        let mut reader = BufReader::new(reply.as_slice());
+        match try!(reader.read_be_u32()) {
+            ACTION_ERROR => return Ok(Failure(error_message(&mut reader), None)),
+            ACTION_ANNOUNCE => (),
+            _ => return Err(protocol_error("unexpected action in announce reply"))
+        }
+        try!(reader.read_be_u32());
+        let interval = try!(reader.read_be_u32()) as uint;
+        let leechers = try!(reader.read_be_u32()) as uint;
+        let seeders = try!(reader.read_be_u32()) as uint;
+        let rest = try!(reader.read_to_end());
+        let mut peers = Vec::new();
+        match self.tracker.ip {
+            Ipv4Addr(..) => for bytes in rest.as_slice().chunks(6).filter(|bytes| bytes.len() == 6) {
+                let mut v = [0u8, ..6]; v.copy_from(bytes);
+                peers.push(Peer::from_6byte(&v));
+            },
+            Ipv6Addr(..) => for bytes in rest.as_slice().chunks(18).filter(|bytes| bytes.len() == 18) {
+                let mut v = [0u8, ..18]; v.copy_from(bytes);
+                peers.push(Peer::from_18byte(&v));
+            }
+        }
+        Ok(Success(AnnounceResult {
+            warning_message: None,
+            interval: interval,
+            min_interval: None,
+            tracker_id: None,
+            complete: seeders,
+            incomplete: leechers,
+            peers: peers,
+            external_ip: None,
+            retry_in: None,
+        }))
+    }
+
+    /// Scrape the given infohashes. BEP 15 trackers don't report names, and
+    /// an error reply is reported as an `OtherIoError` carrying the message.
+    pub fn scrape(&mut self, info_hashes: &[Vec<u8>]) -> IoResult<ScrapeInfo> {
+        let connection_id = try!(self.connection_id());
+        let transaction_id = random::<u32>();
+        let mut packet = MemWriter::with_capacity(16 + 20 * info_hashes.len());
+        try!(packet.write_be_u64(connection_id));
+        try!(packet.write_be_u32(ACTION_SCRAPE));
+        try!(packet.write_be_u32(transaction_id));
+        for info_hash in info_hashes.iter() {
+            try!(packet.write(info_hash.as_slice()));
+        }
+
+        let reply = try!(self.transact(packet.unwrap().as_slice(), transaction_id));
+        let mut reader = BufReader::new(reply.as_slice());
+        match try!(reader.read_be_u32()) {
+            ACTION_ERROR => return Err(IoError {
+                kind: OtherIoError,
+                desc: "tracker returned an error",
+                detail: Some(error_message(&mut reader))
+            }),
+            ACTION_SCRAPE => (),
+            _ => return Err(protocol_error("unexpected action in scrape reply"))
+        }
+        try!(reader.read_be_u32());
+        let mut torrents = HashMap::new();
+        for info_hash in info_hashes.iter() {
+            let seeders = try!(reader.read_be_u32()) as uint;
+            let completed = try!(reader.read_be_u32()) as uint;
+            let leechers = try!(reader.read_be_u32()) as uint;
+            torrents.insert(info_hash.clone(), TorrentScrape {
+                complete: seeders,
+                downloaded: completed,
+                incomplete: leechers,
+                name: None
+            });
+        }
+        Ok(ScrapeInfo { torrents: torrents })
+    }
+
+    fn connection_id(&mut self) -> IoResult<u64> {
+        let now = time::precise_time_ns();
+        match self.connection {
+            Some((connection_id, received)) if now - received < CONNECTION_ID_LIFETIME_NS => return Ok(connection_id),
+            _ => ()
+        }
+        let transaction_id = random::<u32>();
+        let mut packet = MemWriter::with_capacity(16);
+        try!(packet.write_be_u64(PROTOCOL_ID));
+        try!(packet.write_be_u32(ACTION_CONNECT));
+        try!(packet.write_be_u32(transaction_id));
+        let reply = try!(self.transact(packet.unwrap().as_slice(), transaction_id));
+        let mut reader = BufReader::new(reply.as_slice());
+        match try!(reader.read_be_u32()) {
+            ACTION_CONNECT => (),
+            ACTION_ERROR => return Err(IoError {
+                kind: OtherIoError,
+                desc: "tracker refused to connect",
+                detail: Some(error_message(&mut reader))
+            }),
+            _ => return Err(protocol_error("unexpected action in connect reply"))
+        }
+        try!(reader.read_be_u32());
+        let connection_id = try!(reader.read_be_u64());
+        self.connection = Some((connection_id, now));
+        Ok(connection_id)
+    }
+
+    /// Send `packet` and wait for the reply carrying `transaction_id`,
+    /// retransmitting with exponential backoff
+    fn transact(&mut self, packet: &[u8], transaction_id: u32) -> IoResult<Vec<u8>> {
+        let mut buf = [0u8, ..2048];
+        let mut timeout = self.timeout;
+        for _ in range(0, self.retries + 1) {
+            try!(self.socket.send_to(packet, self.tracker));
+            let deadline = time::precise_time_ns() + timeout * 1_000_000;
+            loop {
+                let now = time::precise_time_ns();
+                if now >= deadline { break; }
+                self.socket.set_read_timeout(Some((deadline - now) / 1_000_000 + 1));
+                let (len, from) = match self.socket.recv_from(buf) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind == TimedOut => break,
+                    Err(e) => return Err(e)
+                };
+                if from != self.tracker || len < 8 {
+                    continue;
+                }
+                let mut reader = BufReader::new(buf.slice(4, 8));
+                if try!(reader.read_be_u32()) == transaction_id {
+                    return Ok(Vec::from_slice(buf.slice_to(len)));
+                }
+            }
+            timeout *= 2;
+        }
+        // the connection ID might be what the tracker didn't like
+        self.connection = None;
+        Err(IoError { kind: TimedOut, desc: "udp tracker did not reply", detail: None })
+    }
+}
+
+fn error_message(reader: &mut BufReader) -> String {
+    let _ = reader.read_be_u32();
+    reader.read_to_end().ok()
+        .and_then(|message| String::from_utf8(message).ok())
+        .unwrap_or("unknown error".to_str())
+}
+
+fn protocol_error(desc: &'static str) -> IoError {
+    IoError { kind: OtherIoError, desc: desc, detail: None }
+}
+
+/// Connection IDs are valid for the minute they were issued in and the next
+static CONNECTION_ID_PERIOD: i64 = 60;
+
+/// Most info hashes BEP 15 allows in a single scrape
+static MAX_SCRAPE: uint = 74;
+
+/// Token bucket rate limiter, keyed by source IP
+pub struct RateLimiter {
+    /// Requests allowed per second, on average
+    pub rate: f64,
+    /// Requests allowed in a burst
+    pub burst: f64,
+    buckets: HashMap<IpAddr, (f64, f64)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter { rate: rate, burst: burst, buckets: HashMap::new() }
+    }
+
+    /// Take a token for `ip` at time `now` (in seconds), returning whether
+    /// the request should be served
+    pub fn allow(&mut self, ip: IpAddr, now: f64) -> bool {
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.find_or_insert(ip, (burst, now));
+        let (tokens, last) = *bucket;
+        let tokens = (tokens + (now - last) * rate).min(burst);
+        if tokens < 1.0 {
+            *bucket = (tokens, now);
+            false
+        } else {
+            *bucket = (tokens - 1.0, now);
+            true
+        }
+    }
+
+    /// Forget sources whose buckets have filled up again
+    pub fn prune(&mut self, now: f64) {
+        let (rate, burst) = (self.rate, self.burst);
+        let full: Vec<IpAddr> = self.buckets.iter()
+            .filter(|&(_, &(tokens, last))| tokens + (now - last) * rate >= burst)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in full.iter() {
+            self.buckets.remove(ip);
+        }
+    }
+}
+
+pub struct UdpTracker {
+    store: Arc<Mutex<SwarmStore>>,
+    socket: UdpSocket,
+    secret: [u8, ..20],
+    pub limiter: RateLimiter,
+}
+
+impl UdpTracker {
+    pub fn bind(store: Arc<Mutex<SwarmStore>>, address: SocketAddr) -> IoResult<UdpTracker> {
+        let mut secret = [0u8, ..20];
+        task_rng().fill_bytes(secret);
+        Ok(UdpTracker {
+            store: store,
+            socket: try!(UdpSocket::bind(address)),
+            secret: secret,
+            limiter: RateLimiter::new(5.0, 20.0),
+        })
+    }
+
+    pub fn socket_name(&mut self) -> IoResult<SocketAddr> {
+        self.socket.socket_name()
+    }
+
+    /// Serve requests forever
+    pub fn serve(&mut self) -> IoResult<()> {
+        let mut buf = [0u8, ..2048];
+        let mut last_prune = 0f64;
+        loop {
+            let (len, from) = try!(self.socket.recv_from(buf));
+            let now = time::precise_time_s();
+            if now - last_prune > 60.0 {
+                self.limiter.prune(now);
+                last_prune = now;
+            }
+            if !self.limiter.allow(from.ip, now) {
+                continue;
+            }
+            match self.handle_packet(buf.slice_to(len), from) {
+                Some(reply) => { let _ = self.socket.send_to(reply.as_slice(), from); },
+                None => ()
+            }
+        }
+    }
+
+    fn connection_id(&self, from: SocketAddr, period: i64) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.input(self.secret);
+        hasher.input_str(from.to_str().as_slice());
+        hasher.input_str(period.to_str().as_slice());
+        let mut hash = [0u8, ..20];
+        hasher.result(hash);
+        hash.iter().take(8).fold(0u64, |id, &byte| (id << 8) | byte as u64)
+    }
+
+    fn verify_connection_id(&self, from: SocketAddr, connection_id: u64) -> bool {
+        let period = time::get_time().sec / CONNECTION_ID_PERIOD;
+        connection_id == self.connection_id(from, period) ||
+            connection_id == self.connection_id(from, period - 1)
+    }
+
+    /// Build the reply to a single packet, `None` if it should be ignored
+    fn handle_packet(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let mut reader = BufReader::new(packet);
+        let connection_id = reader.read_be_u64().unwrap();
+        let action = reader.read_be_u32().unwrap();
+        let transaction_id = reader.read_be_u32().unwrap();
+        let mut reply = MemWriter::new();
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            let period = time::get_time().sec / CONNECTION_ID_PERIOD;
+            reply.write_be_u32(ACTION_CONNECT).unwrap();
+            reply.write_be_u32(transaction_id).unwrap();
+            reply.write_be_u64(self.connection_id(from, period)).unwrap();
+            return Some(reply.unwrap());
+        }
+        if !self.verify_connection_id(from, connection_id) {
+            return Some(error_reply(transaction_id, "invalid connection id"));
+        }
+
+        match action {
+            ACTION_ANNOUNCE => {
+                let request = match read_announce(&mut reader, from) {
+                    Ok(request) => request,
+                    Err(_) => return Some(error_reply(transaction_id, "malformed announce"))
+                };
+                let result = match self.store.lock().announce(&request) {
+                    Success(result) => result,
+                    Failure(message, _) => return Some(error_reply(transaction_id, message.as_slice()))
+                };
+                reply.write_be_u32(ACTION_ANNOUNCE).unwrap();
+                reply.write_be_u32(transaction_id).unwrap();
+                reply.write_be_u32(result.interval as u32).unwrap();
+                reply.write_be_u32(result.incomplete as u32).unwrap();
+                reply.write_be_u32(result.complete as u32).unwrap();
+                // only peers of the same address family fit in the reply
+                for peer in result.peers.iter() {
+                    match from.ip {
+                        Ipv4Addr(..) => peer.to_6byte().map(|bytes| reply.write(bytes).unwrap()),
+                        Ipv6Addr(..) => peer.to_18byte().map(|bytes| reply.write(bytes).unwrap())
+                    };
+                }
+            },
+            ACTION_SCRAPE => {
+                let hashes = reader.read_to_end().unwrap();
+                let info_hashes: Vec<Vec<u8>> = hashes.as_slice().chunks(20)
+                    .filter(|hash| hash.len() == 20)
+                    .take(MAX_SCRAPE)
+                    .map(|hash| Vec::from_slice(hash))
+                    .collect();
+                if info_hashes.is_empty() {
+                    return Some(error_reply(transaction_id, "no info hashes"));
+                }
+                let scrape = self.store.lock().scrape(info_hashes.as_slice());
+                reply.write_be_u32(ACTION_SCRAPE).unwrap();
+                reply.write_be_u32(transaction_id).unwrap();
+                for info_hash in info_hashes.iter() {
+                    let (seeders, completed, leechers) = match scrape.torrents.find(info_hash) {
+                        Some(torrent) => (torrent.complete, torrent.downloaded, torrent.incomplete),
+                        None => (0, 0, 0)
+                    };
+                    reply.write_be_u32(seeders as u32).unwrap();
+                    reply.write_be_u32(completed as u32).unwrap();
+                    reply.write_be_u32(leechers as u32).unwrap();
+                }
+            },
+            _ => return Some(error_reply(transaction_id, "unknown action"))
+        }
+        Some(reply.unwrap())
+    }
+}
+
+fn read_announce(reader: &mut BufReader, from: SocketAddr) -> IoResult<AnnounceRequest> {
+    let info_hash = try!(reader.read_exact(20));
+    let mut peer_id = [0u8, ..20];
+    peer_id.copy_from(try!(reader.read_exact(20)).as_slice());
+    let downloaded = try!(reader.read_be_u64());
+    let left = try!(reader.read_be_u64());
+    let uploaded = try!(reader.read_be_u64());
+    let event = try!(reader.read_be_u32());
+    // the ip field is ignored, peers are announced from the source address
+    try!(reader.read_be_u32());
+    let _key = try!(reader.read_be_u32());
+    let numwant = try!(reader.read_be_i32());
+    let port = try!(reader.read_be_u16());
+    Ok(AnnounceRequest {
+        info_hash: info_hash,
+        peer_id: peer_id,
+        address: SocketAddr { ip: from.ip, port: port },
+        uploaded: uploaded as uint,
+        downloaded: downloaded as uint,
+        left: left as uint,
+        event: event_from_u32(event),
+        numwant: if numwant < 0 { None } else { Some(numwant as uint) },
+    })
+}
+
+fn error_reply(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = MemWriter::new();
+    reply.write_be_u32(ACTION_ERROR).unwrap();
+    reply.write_be_u32(transaction_id).unwrap();
+    reply.write_str(message).unwrap();
+    reply.unwrap()
+}
@@ -0,0 +1,244 @@
+//! Peer wire protocol messages (BEP 3), with the `port` message from BEP 5,
+//! `have_all`, `have_none` and `reject_request` from the fast extension
+//! (BEP 6) and the extension protocol message from BEP 10
+
+use std::io::{IoResult, IoError, InvalidInput, MemWriter, BufReader};
+
+
+/// Longest message we accept by default: a 16 KiB block plus the piece
+/// header, with room to spare for bitfields of large torrents
+pub static MAX_MESSAGE_LENGTH: uint = 1 << 17;
+
+pub static CHOKE: u8 = 0;
+pub static UNCHOKE: u8 = 1;
+pub static INTERESTED: u8 = 2;
+pub static NOT_INTERESTED: u8 = 3;
+pub static HAVE: u8 = 4;
+pub static BITFIELD: u8 = 5;
+pub static REQUEST: u8 = 6;
+pub static PIECE: u8 = 7;
+pub static CANCEL: u8 = 8;
+pub static PORT: u8 = 9;
+pub static HAVE_ALL: u8 = 14;
+pub static HAVE_NONE: u8 = 15;
+pub static REJECT_REQUEST: u8 = 16;
+pub static EXTENDED: u8 = 20;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    /// Piece index
+    Have(u32),
+    /// Raw bitfield, high bit of the first byte is piece 0
+    Bitfield(Vec<u8>),
+    /// Piece index, offset in the piece, length
+    Request(u32, u32, u32),
+    /// Piece index, offset in the piece, data
+    Piece(u32, u32, Vec<u8>),
+    /// Piece index, offset in the piece, length
+    Cancel(u32, u32, u32),
+    /// DHT port
+    Port(u16),
+    HaveAll,
+    HaveNone,
+    /// Piece index, offset in the piece, length of a request that won't be
+    /// served
+    RejectRequest(u32, u32, u32),
+    /// Extended message ID and payload
+    Extended(u8, Vec<u8>),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum WireError {
+    /// The length prefix exceeds the maximum message length
+    MessageTooLong(uint),
+    /// Message ID we don't know
+    UnknownMessage(u8),
+    /// Message ID and length that don't go together
+    InvalidLength(u8, uint),
+}
+
+impl WireError {
+    pub fn to_io_error(&self) -> IoError {
+        IoError {
+            kind: InvalidInput,
+            desc: "invalid peer wire message",
+            detail: Some(format!("{}", self))
+        }
+    }
+}
+
+impl Message {
+    /// Length of the encoded message, not counting the length prefix
+    pub fn len(&self) -> uint {
+        match *self {
+            KeepAlive => 0,
+            Choke | Unchoke | Interested | NotInterested | HaveAll | HaveNone => 1,
+            Have(_) => 5,
+            Bitfield(ref bits) => 1 + bits.len(),
+            Request(..) | Cancel(..) | RejectRequest(..) => 13,
+            Piece(_, _, ref data) => 9 + data.len(),
+            Port(_) => 3,
+            Extended(_, ref payload) => 2 + payload.len()
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut Writer) -> IoResult<()> {
+        try!(writer.write_be_u32(self.len() as u32));
+        match *self {
+            KeepAlive => Ok(()),
+            Choke => writer.write_u8(CHOKE),
+            Unchoke => writer.write_u8(UNCHOKE),
+            Interested => writer.write_u8(INTERESTED),
+            NotInterested => writer.write_u8(NOT_INTERESTED),
+            Have(index) => {
+                try!(writer.write_u8(HAVE));
+                writer.write_be_u32(index)
+            },
+            Bitfield(ref bits) => {
+                try!(writer.write_u8(BITFIELD));
+                writer.write(bits.as_slice())
+            },
+            Request(index, begin, length) | Cancel(index, begin, length) | RejectRequest(index, begin, length) => {
+                try!(writer.write_u8(match *self { Request(..) => REQUEST, Cancel(..) => CANCEL, _ => REJECT_REQUEST }));
+                try!(writer.write_be_u32(index));
+                try!(writer.write_be_u32(begin));
+                writer.write_be_u32(length)
+            },
+            Piece(index, begin, ref data) => {
+                try!(writer.write_u8(PIECE));
+                try!(writer.write_be_u32(index));
+                try!(writer.write_be_u32(begin));
+                writer.write(data.as_slice())
+            },
+            Port(port) => {
+                try!(writer.write_u8(PORT));
+                writer.write_be_u16(port)
+            },
+            HaveAll => writer.write_u8(HAVE_ALL),
+            HaveNone => writer.write_u8(HAVE_NONE),
+            Extended(id, ref payload) => {
+                try!(writer.write_u8(EXTENDED));
+                try!(writer.write_u8(id));
+                writer.write(payload.as_slice())
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = MemWriter::with_capacity(4 + self.len());
+        // writing to memory can't fail
+        self.write_to(&mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    /// Decode a message body, the length prefix already stripped
+    pub fn decode(body: &[u8]) -> Result<Message, WireError> {
+        if body.is_empty() {
+            return Ok(KeepAlive);
+        }
+        let (id, len) = (body[0], body.len());
+        let mut reader = BufReader::new(body.slice_from(1));
+        let fixed = |expected: uint| if len == expected { Ok(()) } else { Err(InvalidLength(id, len)) };
+        // the lengths are checked before reading, so the reads can't fail
+        match id {
+            CHOKE => fixed(1).map(|_| Choke),
+            UNCHOKE => fixed(1).map(|_| Unchoke),
+            INTERESTED => fixed(1).map(|_| Interested),
+            NOT_INTERESTED => fixed(1).map(|_| NotInterested),
+            HAVE => fixed(5).map(|_| Have(reader.read_be_u32().unwrap())),
+            BITFIELD => Ok(Bitfield(Vec::from_slice(body.slice_from(1)))),
+            REQUEST | CANCEL | REJECT_REQUEST => fixed(13).map(|_| {
+                let index = reader.read_be_u32().unwrap();
+                let begin = reader.read_be_u32().unwrap();
+                let length = reader.read_be_u32().unwrap();
+                match id {
+                    REQUEST => Request(index, begin, length),
+                    CANCEL => Cancel(index, begin, length),
+                    _ => RejectRequest(index, begin, length)
+                }
+            }),
+            PIECE if len >= 9 => {
+                let index = reader.read_be_u32().unwrap();
+                let begin = reader.read_be_u32().unwrap();
+                Ok(Piece(index, begin, Vec::from_slice(body.slice_from(9))))
+            },
+            PORT => fixed(3).map(|_| Port(reader.read_be_u16().unwrap())),
+            HAVE_ALL => fixed(1).map(|_| HaveAll),
+            HAVE_NONE => fixed(1).map(|_| HaveNone),
+            EXTENDED if len >= 2 => Ok(Extended(body[1], Vec::from_slice(body.slice_from(2)))),
+            PIECE | EXTENDED => Err(InvalidLength(id, len)),
+            _ => Err(UnknownMessage(id))
+        }
+    }
+
+    /// Read a single message from a blocking reader
+    pub fn read_from(reader: &mut Reader, max_length: uint) -> IoResult<Message> {
+        let len = try!(reader.read_be_u32()) as uint;
+        if len > max_length {
+            return Err(MessageTooLong(len).to_io_error());
+        }
+        let body = try!(reader.read_exact(len));
+        Message::decode(body.as_slice()).map_err(|e| e.to_io_error())
+    }
+}
+
+/// Streaming decoder: feed it whatever the socket returned and take the
+/// complete messages out
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// Start of the bytes not decoded yet
+    position: uint,
+    max_length: uint,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::with_max_length(MAX_MESSAGE_LENGTH)
+    }
+
+    pub fn with_max_length(max_length: uint) -> Decoder {
+        Decoder { buffer: Vec::new(), position: 0, max_length: max_length }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        // decoded bytes are only dropped once they're most of the buffer,
+        // so every byte is moved a bounded number of times
+        if self.position == self.buffer.len() {
+            self.buffer.clear();
+            self.position = 0;
+        } else if self.position > self.buffer.len() / 2 {
+            self.buffer = Vec::from_slice(self.buffer.slice_from(self.position));
+            self.position = 0;
+        }
+        self.buffer.push_all(data);
+    }
+
+    /// Number of bytes fed but not decoded yet
+    pub fn buffered(&self) -> uint {
+        self.buffer.len() - self.position
+    }
+
+    /// Next complete message, `Ok(None)` if more data is needed. An error
+    /// means the stream is broken and the connection should be dropped.
+    pub fn next(&mut self) -> Result<Option<Message>, WireError> {
+        let start = self.position;
+        if self.buffered() < 4 {
+            return Ok(None);
+        }
+        let len = self.buffer.slice(start, start + 4).iter().fold(0u, |len, &byte| (len << 8) | byte as uint);
+        if len > self.max_length {
+            return Err(MessageTooLong(len));
+        }
+        if self.buffered() < 4 + len {
+            return Ok(None);
+        }
+        let message = Message::decode(self.buffer.slice(start + 4, start + 4 + len));
+        self.position += 4 + len;
+        message.map(|message| Some(message))
+    }
+}
@@ -0,0 +1,46 @@
+extern crate tensai;
+extern crate bencode;
+
+use bencode::FromBencode;
+
+use tensai::announce::{AnnounceResponse, Success, Failure, RetryIn, RetryAfter, RetryNever};
+
+
+fn decode(response: &str) -> AnnounceResponse {
+    FromBencode::from_bencode(&bencode::from_vec(Vec::from_slice(response.as_bytes())).unwrap()).unwrap()
+}
+
+/// The `retry in` of a response, whether it failed or not
+fn retry_in(response: &str) -> Option<RetryIn> {
+    match decode(response) {
+        Failure(_, retry_in) => retry_in,
+        Success(result) => result.retry_in
+    }
+}
+
+#[test]
+fn failures_carry_retry_in() {
+    assert_eq!(retry_in("d14:failure reason4:busy8:retry ini30ee"), Some(RetryAfter(30)));
+    assert_eq!(retry_in("d14:failure reason7:go away8:retry in5:nevere"), Some(RetryNever));
+    assert_eq!(retry_in("d14:failure reason4:busye"), None);
+}
+
+#[test]
+fn successes_carry_retry_in() {
+    assert_eq!(retry_in("d8:intervali1800e5:peers0:8:retry ini5ee"), Some(RetryAfter(5)));
+    assert_eq!(retry_in("d8:intervali1800e5:peers0:e"), None);
+}
+
+#[test]
+fn malformed_retry_in_is_ignored() {
+    assert_eq!(retry_in("d14:failure reason4:busy8:retry ini-1ee"), None);
+    assert_eq!(retry_in("d14:failure reason4:busy8:retry in5:latere"), None);
+}
+
+#[test]
+fn failure_reason_is_decoded() {
+    match decode("d14:failure reason4:busy8:retry in5:nevere") {
+        Failure(message, _) => assert_eq!(message.as_slice(), "busy"),
+        Success(_) => fail!("expected a failure")
+    }
+}
@@ -0,0 +1,81 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::availability::PieceAvailability;
+use tensai::bitfield::Bitfield;
+use tensai::connection::PeerConnection;
+use tensai::torrent::Torrent;
+use tensai::wire::{Have, HaveNone};
+
+mod common;
+
+
+/// A torrent of 10 pieces
+fn torrent() -> Torrent {
+    common::memory_torrent(2500, 256)
+}
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: port }
+}
+
+fn connection(port: u16, fast: bool) -> PeerConnection {
+    let mut connection = PeerConnection::new(address(port), 10, 0);
+    connection.fast = fast;
+    connection
+}
+
+fn counts(torrent: &Torrent) -> Vec<uint> {
+    range(0, torrent.availability.len()).map(|index| torrent.availability.get(index)).collect()
+}
+
+#[test]
+fn bitfield_with_spare_bits_set_is_rejected() {
+    assert!(Bitfield::from_bytes([0xff, 0xc0], 10).is_some());
+    assert!(Bitfield::from_bytes([0xff, 0xe0], 10).is_none());
+    assert!(Bitfield::from_bytes([0xff, 0x01], 10).is_none());
+    assert!(Bitfield::from_bytes([0xff], 8).is_some());
+    assert!(Bitfield::from_bytes([0xff, 0x00], 8).is_none());
+}
+
+#[test]
+fn removing_a_bitfield_never_added_saturates() {
+    let mut availability = PieceAvailability::new(10);
+    availability.add_piece(3);
+    availability.remove_bitfield(&Bitfield::full(10));
+    assert_eq!(availability.get(3), 0);
+    assert_eq!(availability.get(4), 0);
+}
+
+#[test]
+fn have_all_and_have_none_count_towards_availability() {
+    let mut torrent = torrent();
+    common::seed(&mut torrent, address(1));
+    let mut leech = connection(2, true);
+    torrent.receive(&mut leech, &HaveNone, 0).unwrap();
+    assert_eq!(counts(&torrent), Vec::from_elem(10, 1u));
+
+    torrent.receive(&mut leech, &Have(4), 0).unwrap();
+    // a repeated have isn't counted twice
+    torrent.receive(&mut leech, &Have(4), 0).unwrap();
+    assert_eq!(torrent.availability.get(4), 2);
+    assert_eq!(torrent.availability.distributed_copies(), 1);
+}
+
+#[test]
+fn disconnected_peers_no_longer_count() {
+    let mut torrent = torrent();
+    let seed = common::seed(&mut torrent, address(1));
+    let mut leech = connection(2, false);
+    torrent.receive(&mut leech, &Have(4), 0).unwrap();
+
+    torrent.peer_disconnected(&seed);
+    let mut expected = Vec::from_elem(10, 0u);
+    *expected.get_mut(4) = 1;
+    assert_eq!(counts(&torrent), expected);
+    torrent.peer_disconnected(&leech);
+    assert_eq!(counts(&torrent), Vec::from_elem(10, 0u));
+}
@@ -0,0 +1,55 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::{PeerConnection, BlockRequest};
+use tensai::download::BLOCK_SIZE;
+use tensai::wire::Have;
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 2 * BLOCK_SIZE;
+/// Two full pieces and a last one of a block and a bit
+static LENGTH: uint = 2 * PIECE_LENGTH + BLOCK_SIZE + 1000;
+
+fn block(piece: u32, begin: uint, length: uint) -> BlockRequest {
+    BlockRequest { piece: piece, begin: begin as u32, length: length as u32 }
+}
+
+#[test]
+fn last_piece_is_shorter() {
+    let info = common::unhashed_torrent_info(LENGTH, PIECE_LENGTH);
+    assert_eq!(info.num_pieces(), 3);
+    assert_eq!(info.piece_size(0), PIECE_LENGTH);
+    assert_eq!(info.piece_size(2), BLOCK_SIZE + 1000);
+    assert_eq!(info.blocks(0), vec![block(0, 0, BLOCK_SIZE), block(0, BLOCK_SIZE, BLOCK_SIZE)]);
+    assert_eq!(info.blocks(2), vec![block(2, 0, BLOCK_SIZE), block(2, BLOCK_SIZE, 1000)]);
+}
+
+#[test]
+fn last_piece_may_be_a_single_short_block() {
+    let info = common::unhashed_torrent_info(PIECE_LENGTH + 1000, PIECE_LENGTH);
+    assert_eq!(info.blocks(1), vec![block(1, 0, 1000)]);
+    let info = common::unhashed_torrent_info(2 * PIECE_LENGTH, PIECE_LENGTH);
+    assert_eq!(info.blocks(1), vec![block(1, 0, BLOCK_SIZE), block(1, BLOCK_SIZE, BLOCK_SIZE)]);
+}
+
+#[test]
+fn short_last_block_is_requested_and_completes_the_piece() {
+    let mut torrent = common::memory_torrent(LENGTH, PIECE_LENGTH);
+    let mut connection = PeerConnection::new(SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 }, 3, 0);
+    torrent.receive(&mut connection, &Have(2), 0).unwrap();
+
+    let first = torrent.next_request(&connection).unwrap();
+    let last = torrent.next_request(&connection).unwrap();
+    assert_eq!(first, block(2, 0, BLOCK_SIZE));
+    assert_eq!(last, block(2, BLOCK_SIZE, 1000));
+    assert_eq!(torrent.next_request(&connection), None);
+
+    assert!(!torrent.block_received(&first, &connection.address).unwrap().piece_complete);
+    assert!(torrent.block_received(&last, &connection.address).unwrap().piece_complete);
+    assert_eq!(torrent.traffic.downloaded_bytes, BLOCK_SIZE + 1000);
+}
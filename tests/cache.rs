@@ -0,0 +1,194 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::TempDir;
+
+use tensai::torrent::TorrentInfo;
+use tensai::storage::{Storage, CachedStorage, CacheStats, PieceCache, DiskStorage, MemoryStorage};
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 1024;
+static TOTAL: uint = 6000;
+
+fn torrent_info() -> TorrentInfo {
+    common::unhashed_torrent_info(TOTAL, PIECE_LENGTH)
+}
+
+fn payload() -> Vec<u8> {
+    common::payload(TOTAL)
+}
+
+/// Write the whole payload to `storage`, a piece at a time
+fn fill(storage: &mut Storage) {
+    let data = payload();
+    for (index, piece) in data.as_slice().chunks(PIECE_LENGTH).enumerate() {
+        storage.write_block(index, 0, piece).unwrap();
+    }
+    storage.flush().unwrap();
+}
+
+/// Read a block for a peer and check it
+fn read(cache: &mut CachedStorage, piece: uint, begin: uint, length: uint) {
+    let offset = piece * PIECE_LENGTH + begin;
+    assert_eq!(cache.read_for_peer(piece, begin, length).unwrap().as_slice(), payload().slice(offset, offset + length));
+}
+
+fn stats(hits: uint, misses: uint, read_ahead: uint) -> CacheStats {
+    CacheStats { hits: hits, misses: misses, read_ahead: read_ahead }
+}
+
+/// Least recently used pieces make room for others
+fn check_eviction(storage: Box<Storage>) {
+    let info = torrent_info();
+    let mut cache = CachedStorage::new(&info, storage, &PieceCache::new(2 * PIECE_LENGTH));
+    cache.read_ahead = 0;
+    read(&mut cache, 0, 0, 256);
+    read(&mut cache, 0, 256, 256);
+    assert_eq!(cache.stats(), stats(1, 1, 0));
+    read(&mut cache, 2, 0, 256);
+    read(&mut cache, 0, 512, 256);
+    // piece 2 is the least recently used now
+    read(&mut cache, 4, 0, 256);
+    assert_eq!(cache.size(), 2 * PIECE_LENGTH);
+    read(&mut cache, 0, 768, 256);
+    read(&mut cache, 2, 256, 256);
+    assert_eq!(cache.stats(), stats(3, 4, 0));
+    assert_eq!(cache.cache_stats(), Some(cache.stats()));
+}
+
+/// Reading pieces in order reads the next ones ahead
+fn check_read_ahead(storage: Box<Storage>) {
+    let info = torrent_info();
+    let mut cache = CachedStorage::new(&info, storage, &PieceCache::new(4 * PIECE_LENGTH));
+    read(&mut cache, 1, 0, 1024);
+    assert_eq!(cache.stats(), stats(0, 1, 0));
+    read(&mut cache, 2, 0, 1024);
+    assert_eq!(cache.stats(), stats(0, 2, 2));
+    read(&mut cache, 3, 0, 1024);
+    read(&mut cache, 4, 0, 1024);
+    // the last piece is short, and there's nothing after it
+    read(&mut cache, 5, 0, TOTAL - 5 * PIECE_LENGTH);
+    assert_eq!(cache.stats(), stats(3, 2, 3));
+}
+
+/// Written pieces are read from storage again
+fn check_writes(storage: Box<Storage>) {
+    let info = torrent_info();
+    let mut cache = CachedStorage::new(&info, storage, &PieceCache::new(4 * PIECE_LENGTH));
+    read(&mut cache, 0, 0, 16);
+    cache.write_block(0, 0, [7u8, ..16]).unwrap();
+    assert_eq!(cache.read_for_peer(0, 0, 16).unwrap(), Vec::from_elem(16, 7u8));
+    assert_eq!(cache.stats(), stats(0, 2, 0));
+}
+
+fn memory_storage() -> Box<Storage> {
+    let mut storage = MemoryStorage::new(&torrent_info());
+    fill(&mut storage);
+    box storage as Box<Storage>
+}
+
+fn disk_storage(dir: &TempDir) -> Box<Storage> {
+    let mut storage = DiskStorage::new(&torrent_info(), dir.path().clone());
+    fill(&mut storage);
+    box storage as Box<Storage>
+}
+
+#[test]
+fn memory_storage_evicts_least_recently_used() {
+    check_eviction(memory_storage());
+}
+
+#[test]
+fn memory_storage_reads_ahead() {
+    check_read_ahead(memory_storage());
+}
+
+#[test]
+fn memory_storage_writes_replace_cached_pieces() {
+    check_writes(memory_storage());
+}
+
+#[test]
+fn disk_storage_evicts_least_recently_used() {
+    let dir = TempDir::new("tensai-cache").unwrap();
+    check_eviction(disk_storage(&dir));
+}
+
+#[test]
+fn disk_storage_reads_ahead() {
+    let dir = TempDir::new("tensai-cache").unwrap();
+    check_read_ahead(disk_storage(&dir));
+}
+
+#[test]
+fn disk_storage_writes_replace_cached_pieces() {
+    let dir = TempDir::new("tensai-cache").unwrap();
+    check_writes(disk_storage(&dir));
+}
+
+#[test]
+fn pieces_bigger_than_the_cache_are_not_cached() {
+    let info = torrent_info();
+    let mut cache = CachedStorage::new(&info, memory_storage(), &PieceCache::new(PIECE_LENGTH / 2));
+    read(&mut cache, 0, 0, 256);
+    read(&mut cache, 0, 256, 256);
+    assert_eq!(cache.stats(), stats(0, 2, 0));
+    assert_eq!(cache.size(), 0);
+}
+
+#[test]
+fn our_own_reads_are_not_cached() {
+    let info = torrent_info();
+    let mut cache = CachedStorage::new(&info, memory_storage(), &PieceCache::new(4 * PIECE_LENGTH));
+    // hashing goes through the pieces in order, without reading ahead
+    for index in range(0, 3) {
+        assert_eq!(cache.read_block(index, 0, PIECE_LENGTH).unwrap().as_slice(),
+                   payload().slice(index * PIECE_LENGTH, (index + 1) * PIECE_LENGTH));
+    }
+    assert_eq!(cache.size(), 0);
+    assert_eq!(cache.stats(), stats(0, 0, 0));
+
+    // but pieces peers read are used
+    read(&mut cache, 4, 0, 256);
+    assert_eq!(cache.read_block(4, 256, 16).unwrap().as_slice(), payload().slice(4 * PIECE_LENGTH + 256, 4 * PIECE_LENGTH + 272));
+    assert_eq!(cache.stats(), stats(0, 1, 0));
+}
+
+#[test]
+fn torrents_share_the_cache() {
+    let info = torrent_info();
+    let shared = PieceCache::new(2 * PIECE_LENGTH);
+    let mut first = CachedStorage::new(&info, memory_storage(), &shared);
+    let mut second = CachedStorage::new(&info, memory_storage(), &shared);
+    first.read_ahead = 0;
+    second.read_ahead = 0;
+    read(&mut first, 0, 0, 256);
+    read(&mut second, 2, 0, 256);
+    assert_eq!(shared.size(), 2 * PIECE_LENGTH);
+
+    // the least recently used piece goes, whichever torrent it's of
+    read(&mut second, 4, 0, 256);
+    assert_eq!(first.size(), 0);
+    assert_eq!(second.size(), 2 * PIECE_LENGTH);
+
+    drop(second);
+    assert_eq!(shared.size(), 0);
+}
+
+#[test]
+fn shrinking_the_cache_evicts() {
+    let info = torrent_info();
+    let shared = PieceCache::new(4 * PIECE_LENGTH);
+    let mut cache = CachedStorage::new(&info, memory_storage(), &shared);
+    cache.read_ahead = 0;
+    for index in range(0, 3) {
+        read(&mut cache, index * 2, 0, 256);
+    }
+    shared.set_capacity(PIECE_LENGTH);
+    assert_eq!(cache.size(), PIECE_LENGTH);
+    read(&mut cache, 4, 0, 256);
+    assert_eq!(cache.stats(), stats(1, 3, 0));
+}
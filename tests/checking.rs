@@ -0,0 +1,80 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use tensai::client::Client;
+use tensai::torrent::{TorrentInfo, Checking, Downloading, Seeding, Stopped};
+use tensai::storage::{Storage, MemoryStorage};
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 256;
+
+fn payload() -> Vec<u8> {
+    common::payload(1000)
+}
+
+fn torrent_info() -> TorrentInfo {
+    common::torrent_info(payload().as_slice(), PIECE_LENGTH)
+}
+
+/// Memory storage holding `payload()` with piece `corrupt` damaged
+fn storage(info: &TorrentInfo, corrupt: Option<uint>) -> Box<Storage> {
+    let mut data = payload();
+    match corrupt {
+        Some(index) => *data.get_mut(index * PIECE_LENGTH) ^= 0xff,
+        None => ()
+    }
+    common::memory_storage(info, data.as_slice())
+}
+
+fn check_all(client: &mut Client) {
+    while client.is_checking() {
+        assert!(client.check_torrents().is_empty());
+    }
+}
+
+#[test]
+fn complete_data_is_seeded() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    assert_eq!(client.add_torrent_with_storage(&info, storage(&info, None)).status, Checking);
+    check_all(&mut client);
+    let torrent = client.find_torrent(info.infohash.as_slice()).unwrap();
+    assert_eq!(torrent.status, Seeding);
+    assert!(torrent.have.is_full());
+}
+
+#[test]
+fn corrupt_pieces_are_downloaded_again() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    client.add_torrent_with_storage(&info, storage(&info, Some(2)));
+    check_all(&mut client);
+    let torrent = client.find_torrent(info.infohash.as_slice()).unwrap();
+    assert_eq!(torrent.status, Downloading);
+    assert_eq!(torrent.have.ones(), vec![0, 1, 3]);
+}
+
+#[test]
+fn empty_storage_is_not_checked() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    let torrent = client.add_torrent_with_storage(&info, box MemoryStorage::new(&info) as Box<Storage>);
+    assert_eq!(torrent.status, Stopped);
+}
+
+#[test]
+fn checks_report_progress_and_can_be_cancelled() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    let torrent = client.add_torrent_with_storage(&info, storage(&info, None));
+    assert_eq!(torrent.check_progress(), Some(0.0));
+    assert!(torrent.check_step().unwrap());
+    assert_eq!(torrent.check_progress(), Some(0.25));
+    torrent.cancel_check();
+    assert_eq!(torrent.status, Stopped);
+    assert_eq!(torrent.check_progress(), None);
+    assert_eq!(torrent.have.ones(), vec![0]);
+}
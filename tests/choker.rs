@@ -0,0 +1,123 @@
+extern crate tensai;
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::choker::{Choker, PeerStats, RECHOKE_INTERVAL, OPTIMISTIC_INTERVAL};
+
+
+static SECOND: u64 = 1_000_000_000;
+
+fn address(n: u8) -> SocketAddr {
+    SocketAddr { ip: Ipv4Addr(10, 0, 0, n), port: 6881 }
+}
+
+/// An interested peer, connected long ago, with the given rates in KiB/s
+fn peer(n: u8, download: f64, upload: f64) -> PeerStats {
+    PeerStats {
+        address: address(n),
+        interested: true,
+        download_rate: download * 1024.0,
+        upload_rate: upload * 1024.0,
+        connected_at: 0,
+        snubbed: false,
+    }
+}
+
+fn sorted(mut addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    addresses.sort_by(|a, b| a.to_str().cmp(&b.to_str()));
+    addresses
+}
+
+static NOW: u64 = 1000 * 1_000_000_000;
+
+#[test]
+fn fastest_uploaders_are_unchoked_while_downloading() {
+    let peers = [peer(1, 10.0, 0.0), peer(2, 50.0, 0.0), peer(3, 30.0, 100.0), peer(4, 1.0, 0.0)];
+    let mut choker = Choker::new();
+    let unchoked = choker.rechoke(peers, 3, false, NOW);
+    assert_eq!(unchoked.len(), 3);
+    assert_eq!(unchoked.slice_to(2), [address(2), address(3)].as_slice());
+    // the third slot is the optimistic one, taken by one of the others
+    let optimistic = choker.optimistic().unwrap();
+    assert!(optimistic == address(1) || optimistic == address(4));
+    assert_eq!(*unchoked.get(2), optimistic);
+}
+
+#[test]
+fn fastest_downloaders_are_unchoked_while_seeding() {
+    let peers = [peer(1, 0.0, 10.0), peer(2, 0.0, 50.0), peer(3, 100.0, 30.0)];
+    let mut choker = Choker::new();
+    let unchoked = choker.rechoke(peers, 3, true, NOW);
+    assert_eq!(unchoked.slice_to(2), [address(2), address(3)].as_slice());
+    assert_eq!(sorted(unchoked), sorted(vec![address(1), address(2), address(3)]));
+}
+
+#[test]
+fn uninterested_peers_stay_choked() {
+    let mut lazy = peer(1, 100.0, 100.0);
+    lazy.interested = false;
+    let peers = [lazy, peer(2, 1.0, 1.0)];
+    let mut choker = Choker::new();
+    assert_eq!(choker.rechoke(peers, 4, false, NOW), vec![address(2)]);
+}
+
+#[test]
+fn snubbed_peers_only_get_the_optimistic_slot() {
+    let mut snubbing = peer(1, 100.0, 0.0);
+    snubbing.snubbed = true;
+    let peers = [snubbing, peer(2, 10.0, 0.0), peer(3, 5.0, 0.0)];
+    let mut choker = Choker::new();
+    let unchoked = choker.rechoke(peers, 2, false, NOW);
+    assert_eq!(*unchoked.get(0), address(2));
+    // seeding doesn't care about what the peers send us
+    let mut choker = Choker::new();
+    assert_eq!(*choker.rechoke(peers, 2, true, NOW).get(0), address(1));
+}
+
+#[test]
+fn optimistic_unchoke_rotates_every_30_seconds() {
+    let peers: Vec<PeerStats> = range(1u8, 20).map(|n| peer(n, n as f64, 0.0)).collect();
+    let mut choker = Choker::new();
+    assert!(choker.is_due(NOW));
+    choker.rechoke(peers.as_slice(), 2, false, NOW);
+    let optimistic = choker.optimistic();
+    assert!(!choker.is_due(NOW + RECHOKE_INTERVAL - 1));
+    assert!(choker.is_due(NOW + RECHOKE_INTERVAL));
+
+    for i in range(1, 3) {
+        choker.rechoke(peers.as_slice(), 2, false, NOW + i * RECHOKE_INTERVAL);
+        assert_eq!(choker.optimistic(), optimistic);
+    }
+    // with 18 candidates, rotating 20 times never picking another one
+    // doesn't happen
+    let mut rotated = false;
+    for i in range(0, 20) {
+        choker.rechoke(peers.as_slice(), 2, false, NOW + (i + 1) * OPTIMISTIC_INTERVAL);
+        rotated = rotated || choker.optimistic() != optimistic;
+    }
+    assert!(rotated);
+}
+
+#[test]
+fn new_peers_are_preferred_for_the_optimistic_unchoke() {
+    let mut new = peer(2, 0.0, 0.0);
+    new.connected_at = NOW - 10 * SECOND;
+    let peers = [peer(1, 0.0, 0.0), new, peer(3, 1000.0, 0.0)];
+    let mut new_picked = 0u;
+    for _ in range(0u, 1000) {
+        let mut choker = Choker::new();
+        choker.rechoke(peers, 2, false, NOW);
+        if choker.optimistic() == Some(address(2)) {
+            new_picked += 1;
+        }
+    }
+    // three to one odds
+    assert!(new_picked > 650 && new_picked < 850, "new peer picked {} times", new_picked);
+}
+
+#[test]
+fn no_slots_means_no_uploads() {
+    let peers = [peer(1, 10.0, 10.0)];
+    let mut choker = Choker::new();
+    assert!(choker.rechoke(peers, 0, false, NOW).is_empty());
+}
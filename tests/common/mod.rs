@@ -0,0 +1,96 @@
+//! Torrents and payloads shared by the integration tests and benchmarks.
+//! Crates using this need `bencode` and `rust-crypto` as extern crates.
+#![allow(dead_code)]
+
+use std::io::net::ip::SocketAddr;
+
+use bencode;
+use bencode::FromBencode;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use tensai::announce::{AnnounceResponse, AnnounceResult, Success};
+use tensai::connection::PeerConnection;
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::torrent::{Torrent, TorrentInfo};
+use tensai::wire::HaveAll;
+
+
+/// `length` bytes that don't repeat within a piece of the sizes tests use
+pub fn payload(length: uint) -> Vec<u8> {
+    Vec::from_fn(length, |i| (i % 251) as u8)
+}
+
+/// A single-file torrent of `data` with real piece hashes
+pub fn torrent_info(data: &[u8], piece_length: uint) -> TorrentInfo {
+    let mut pieces = Vec::new();
+    for piece in data.chunks(piece_length) {
+        let mut hasher = Sha1::new();
+        hasher.input(piece);
+        let mut hash = [0u8, ..20];
+        hasher.result(hash);
+        pieces.push_all(hash);
+    }
+    single_file(data.len(), piece_length, pieces.as_slice())
+}
+
+/// A single-file torrent of `length` bytes whose piece hashes are all
+/// zeros, for tests that never check them
+pub fn unhashed_torrent_info(length: uint, piece_length: uint) -> TorrentInfo {
+    let num_pieces = (length + piece_length - 1) / piece_length;
+    single_file(length, piece_length, Vec::from_elem(num_pieces * 20, 0u8).as_slice())
+}
+
+fn single_file(length: uint, piece_length: uint, pieces: &[u8]) -> TorrentInfo {
+    let mut torrent = Vec::from_slice(format!("d8:announce14:http://tracker4:infod6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces{}:",
+                                              length, piece_length, pieces.len()).as_bytes());
+    torrent.push_all(pieces);
+    torrent.push_all(b"ee");
+    FromBencode::from_bencode(&bencode::from_vec(torrent).unwrap()).unwrap()
+}
+
+/// Memory storage of `info` holding `data`
+pub fn memory_storage(info: &TorrentInfo, data: &[u8]) -> Box<Storage> {
+    let mut storage = MemoryStorage::new(info);
+    for (index, piece) in data.chunks(info.metainfo.piece_length as uint).enumerate() {
+        storage.write_block(index, 0, piece).unwrap();
+    }
+    box storage as Box<Storage>
+}
+
+/// A torrent of `length` bytes kept in memory, whose piece hashes are all
+/// zeros
+pub fn memory_torrent(length: uint, piece_length: uint) -> Torrent {
+    let info = unhashed_torrent_info(length, piece_length);
+    let storage = box MemoryStorage::new(&info) as Box<Storage>;
+    Torrent::new(info, storage)
+}
+
+/// A connection to a seed at `address` that speaks the fast extension and
+/// sent `have_all`
+pub fn seed(torrent: &mut Torrent, address: SocketAddr) -> PeerConnection {
+    let mut connection = PeerConnection::new(address, torrent.info.num_pieces(), 0);
+    connection.fast = true;
+    torrent.receive(&mut connection, &HaveAll, 0).unwrap();
+    connection
+}
+
+/// Announce URL of the tracker at host `name`
+pub fn url(name: &str) -> String {
+    format!("http://{}/announce", name)
+}
+
+/// A successful announce response, with no peers
+pub fn success() -> Option<AnnounceResponse> {
+    Some(Success(AnnounceResult {
+        warning_message: None,
+        interval: 1800,
+        min_interval: None,
+        tracker_id: None,
+        complete: 0,
+        incomplete: 0,
+        peers: Vec::new(),
+        external_ip: None,
+        retry_in: None,
+    }))
+}
@@ -0,0 +1,120 @@
+extern crate tensai;
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::{PeerConnection, BlockRequest, KEEPALIVE_INTERVAL, PEER_TIMEOUT};
+use tensai::connection::{MIN_REQUEST_DEPTH, MAX_REQUEST_DEPTH};
+use tensai::connection::{Timeout, BitfieldNotFirst, FastNotNegotiated, InvalidBitfield, InvalidPieceIndex};
+use tensai::connection::{RequestWhileChoked, RequestWhileChoking, UnrequestedPiece};
+use tensai::download::BLOCK_SIZE;
+use tensai::wire::{KeepAlive, Choke, Unchoke, Interested, Have, Bitfield, HaveAll, Request, Piece, RejectRequest};
+
+
+static NUM_PIECES: uint = 10;
+static SECOND: u64 = 1_000_000_000;
+
+fn connection() -> PeerConnection {
+    let address = SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 };
+    PeerConnection::new(address, NUM_PIECES, 0)
+}
+
+#[test]
+fn requests_while_choked_are_violations() {
+    let mut connection = connection();
+    assert_eq!(connection.receive(&Request(0, 0, 16384), 0), Err(RequestWhileChoked));
+}
+
+#[test]
+fn requests_in_flight_when_we_choked_are_discarded() {
+    let mut connection = connection();
+    connection.send(Unchoke, 0).unwrap();
+    connection.receive(&Request(0, 0, 16384), 0).unwrap();
+    connection.send(Choke, SECOND).unwrap();
+    assert!(connection.peer_requests.is_empty());
+
+    connection.receive(&Request(0, 16384, 16384), 2 * SECOND).unwrap();
+    assert!(connection.peer_requests.is_empty());
+    assert_eq!(connection.receive(&Request(0, 16384, 16384), 11 * SECOND), Err(RequestWhileChoked));
+}
+
+#[test]
+fn bitfield_must_come_first() {
+    let mut connection = connection();
+    connection.receive(&KeepAlive, 0).unwrap();
+    connection.receive(&Bitfield(vec![0xff, 0xc0]), 0).unwrap();
+    assert!(connection.has_piece(9));
+
+    let mut connection = self::connection();
+    connection.receive(&Interested, 0).unwrap();
+    assert_eq!(connection.receive(&Bitfield(vec![0xff, 0xc0]), 0), Err(BitfieldNotFirst));
+}
+
+#[test]
+fn bitfield_of_the_wrong_length_is_invalid() {
+    let mut connection = connection();
+    assert_eq!(connection.receive(&Bitfield(vec![0xff]), 0), Err(InvalidBitfield));
+}
+
+#[test]
+fn have_all_needs_the_fast_extension() {
+    let mut connection = connection();
+    assert_eq!(connection.receive(&HaveAll, 0), Err(FastNotNegotiated));
+    assert_eq!(connection.receive(&RejectRequest(0, 0, 16384), 0), Err(FastNotNegotiated));
+}
+
+#[test]
+fn have_beyond_the_last_piece_is_invalid() {
+    let mut connection = connection();
+    connection.receive(&Have(9), 0).unwrap();
+    assert!(connection.has_piece(9));
+    assert_eq!(connection.receive(&Have(10), 0), Err(InvalidPieceIndex(10)));
+}
+
+#[test]
+fn tick_sends_keep_alives_and_times_out() {
+    let mut connection = connection();
+    connection.tick(KEEPALIVE_INTERVAL - 1).unwrap();
+    assert!(connection.take_outgoing().is_empty());
+    connection.tick(KEEPALIVE_INTERVAL).unwrap();
+    assert_eq!(connection.take_outgoing(), vec![KeepAlive]);
+
+    connection.receive(&KeepAlive, SECOND).unwrap();
+    connection.tick(SECOND + PEER_TIMEOUT - 1).unwrap();
+    assert_eq!(connection.tick(SECOND + PEER_TIMEOUT), Err(Timeout));
+}
+
+#[test]
+fn choke_drops_our_requests() {
+    let mut connection = connection();
+    assert_eq!(connection.send(Request(0, 0, 16384), 0), Err(RequestWhileChoking));
+    connection.receive(&Unchoke, 0).unwrap();
+    connection.send(Request(0, 0, 16384), 0).unwrap();
+    connection.send(Request(0, 16384, 16384), 0).unwrap();
+    connection.receive(&Choke, 0).unwrap();
+    assert!(connection.our_requests.is_empty());
+
+    // blocks already on their way are still accepted, but only once
+    let block = Piece(0, 0, Vec::from_elem(16384, 0u8));
+    connection.receive(&block, 0).unwrap();
+    let request = BlockRequest { piece: 0, begin: 0, length: 16384 };
+    assert_eq!(connection.receive(&block, 0), Err(UnrequestedPiece(request)));
+}
+
+#[test]
+fn request_depth_follows_the_download_rate() {
+    let mut connection = connection();
+    assert_eq!(connection.request_depth(), MIN_REQUEST_DEPTH);
+
+    // a quarter of the sample goes into the average: 10 blocks a second
+    // cover 30 blocks of queue time
+    connection.download_rate.add(40 * BLOCK_SIZE);
+    connection.download_rate.update(SECOND);
+    assert_eq!(connection.request_depth(), 30);
+
+    connection.download_rate.add(1 << 30);
+    connection.download_rate.update(2 * SECOND);
+    assert_eq!(connection.request_depth(), MAX_REQUEST_DEPTH);
+
+    connection.snubbed = true;
+    assert_eq!(connection.request_depth(), 1);
+}
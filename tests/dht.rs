@@ -0,0 +1,70 @@
+extern crate tensai;
+
+use std::io::net::ip::{IpAddr, Ipv4Addr};
+
+use tensai::dht::{node_id, is_valid_node_id};
+
+
+fn from_hex(hex: &str) -> [u8, ..20] {
+    let mut id = [0u8, ..20];
+    for i in range(0u, 20) {
+        id[i] = std::num::from_str_radix(hex.slice(2 * i, 2 * i + 2), 16).unwrap();
+    }
+    id
+}
+
+/// The examples of BEP 42: address, the random byte r and the node ID
+fn test_vectors() -> Vec<(IpAddr, u8, [u8, ..20])> {
+    vec![(Ipv4Addr(124, 31, 75, 21), 1, from_hex("5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401")),
+         (Ipv4Addr(21, 75, 31, 124), 86, from_hex("5a3ce9c14e7a08645677bbd1cfe7d8f956d53256")),
+         (Ipv4Addr(65, 23, 51, 170), 22, from_hex("a5d43220bc8f112a3d426c84764f8c2a1150e616")),
+         (Ipv4Addr(84, 124, 73, 14), 65, from_hex("1b0321dd1bb1fe518101ceef99462b947a01ff41")),
+         (Ipv4Addr(43, 213, 53, 83), 90, from_hex("e56f6cbf5b7c4be0237986d5243b87aa6d51305a"))]
+}
+
+#[test]
+fn spec_examples_are_valid() {
+    for &(ip, r, id) in test_vectors().iter() {
+        assert_eq!(id[19], r);
+        assert!(is_valid_node_id(&id, ip), "{} should be valid for {}", id.as_slice(), ip);
+    }
+}
+
+#[test]
+fn spec_examples_are_invalid_for_other_addresses() {
+    let vectors = test_vectors();
+    for (i, &(_, _, id)) in vectors.iter().enumerate() {
+        let &(other, _, _) = vectors.get((i + 1) % vectors.len());
+        assert!(!is_valid_node_id(&id, other));
+    }
+}
+
+#[test]
+fn changed_prefix_bits_are_invalid() {
+    let (ip, _, id) = *test_vectors().get(0);
+    // the first 21 bits come from the address, the rest are free
+    for &(byte, bit) in [(0u, 0x80u8), (1, 0x01), (2, 0x08)].iter() {
+        let mut changed = id;
+        changed[byte] ^= bit;
+        assert!(!is_valid_node_id(&changed, ip));
+    }
+    let mut changed = id;
+    changed[2] ^= 0x07;
+    changed[10] ^= 0xff;
+    assert!(is_valid_node_id(&changed, ip));
+}
+
+#[test]
+fn generated_ids_are_valid_and_keep_all_of_r() {
+    let ip = Ipv4Addr(124, 31, 75, 21);
+    let ids: Vec<[u8, ..20]> = range(0u, 100).map(|_| node_id(Some(ip))).collect();
+    assert!(ids.iter().all(|id| is_valid_node_id(id, ip)));
+    assert!(ids.iter().any(|id| id[19] > 7));
+}
+
+#[test]
+fn local_addresses_accept_any_id() {
+    assert!(is_valid_node_id(&[0u8, ..20], Ipv4Addr(192, 168, 1, 1)));
+    assert!(is_valid_node_id(&[0u8, ..20], Ipv4Addr(10, 0, 0, 1)));
+    assert!(!is_valid_node_id(&[0u8, ..20], Ipv4Addr(124, 31, 75, 21)));
+}
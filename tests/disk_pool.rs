@@ -0,0 +1,170 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+use tensai::storage::{Storage, StorageResult, MemoryStorage, DiskPool, DiskResult, SharedStorage};
+use tensai::storage::pool::{Written, Read, Hashed};
+use tensai::torrent::TorrentInfo;
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 32768;
+static BLOCK: uint = 16384;
+
+fn payload() -> Vec<u8> {
+    common::payload(100000)
+}
+
+fn torrent_info() -> TorrentInfo {
+    common::torrent_info(payload().as_slice(), PIECE_LENGTH)
+}
+
+/// Memory storage that records the piece, offset and length of every write
+struct CountingStorage {
+    storage: MemoryStorage,
+    writes: Arc<Mutex<Vec<(uint, uint, uint)>>>,
+}
+
+impl Storage for CountingStorage {
+    fn read_block(&mut self, piece: uint, begin: uint, length: uint) -> StorageResult<Vec<u8>> {
+        self.storage.read_block(piece, begin, length)
+    }
+
+    fn write_block(&mut self, piece: uint, begin: uint, data: &[u8]) -> StorageResult<()> {
+        self.writes.lock().push((piece, begin, data.len()));
+        self.storage.write_block(piece, begin, data)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.storage.flush()
+    }
+
+    fn exists(&self) -> bool {
+        self.storage.exists()
+    }
+
+    fn file_stats(&self) -> Vec<Option<(u64, u64)>> {
+        self.storage.file_stats()
+    }
+
+    fn move_to(&mut self, destination: &Path) -> StorageResult<()> {
+        self.storage.move_to(destination)
+    }
+
+    fn delete(&mut self) -> StorageResult<()> {
+        self.storage.delete()
+    }
+}
+
+fn counting_storage(info: &TorrentInfo) -> (SharedStorage, Arc<Mutex<Vec<(uint, uint, uint)>>>) {
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let storage = CountingStorage { storage: MemoryStorage::new(info), writes: writes.clone() };
+    (SharedStorage::new(box storage as Box<Storage>), writes)
+}
+
+/// Queue the blocks of `piece`, in reverse so they only end up adjacent
+/// once sorted
+fn write_piece(pool: &mut DiskPool, info: &TorrentInfo, storage: &SharedStorage, piece: uint) {
+    let data = payload();
+    let start = piece * PIECE_LENGTH;
+    let length = info.piece_size(piece);
+    let mut begin = (length - 1) / BLOCK * BLOCK;
+    loop {
+        let end = (begin + BLOCK).min(length);
+        pool.write(info.infohash.as_slice(), storage, piece, begin, Vec::from_slice(data.slice(start + begin, start + end)));
+        if begin == 0 {
+            break;
+        }
+        begin -= BLOCK;
+    }
+}
+
+#[test]
+fn adjacent_blocks_are_written_at_once() {
+    let info = torrent_info();
+    let (storage, writes) = counting_storage(&info);
+    let (mut pool, results) = DiskPool::new(2, 1 << 20);
+    write_piece(&mut pool, &info, &storage, 0);
+    write_piece(&mut pool, &info, &storage, 2);
+    pool.submit();
+    for _ in range(0u, 2) {
+        match results.recv() {
+            Written(ref key, _, 0, length, Ok(())) => {
+                assert_eq!(key, &info.infohash);
+                assert!(length == PIECE_LENGTH || length == info.piece_size(2));
+            },
+            _ => fail!("expected a write")
+        }
+    }
+    let mut writes = writes.lock().clone();
+    writes.sort();
+    assert_eq!(writes, vec![(0, 0, PIECE_LENGTH), (2, 0, info.piece_size(2))]);
+    assert_eq!(pool.queued_bytes(), 0);
+}
+
+#[test]
+fn pieces_are_hashed_after_their_writes() {
+    let info = torrent_info();
+    let (storage, _) = counting_storage(&info);
+    let (mut pool, results) = DiskPool::new(2, 1 << 20);
+    write_piece(&mut pool, &info, &storage, 1);
+    // held back writes of the piece go first
+    pool.hash(info.infohash.as_slice(), &storage, 1, PIECE_LENGTH, info.piece_hash(1));
+    pool.hash(info.infohash.as_slice(), &storage, 1, PIECE_LENGTH, info.piece_hash(0));
+    let mut hashed = Vec::new();
+    for _ in range(0u, 3) {
+        match results.recv() {
+            Written(_, 1, 0, length, Ok(())) => assert_eq!(length, PIECE_LENGTH),
+            Hashed(_, 1, Ok((passed, data))) => {
+                assert_eq!(data.as_slice(), payload().slice(PIECE_LENGTH, 2 * PIECE_LENGTH));
+                hashed.push(passed);
+            },
+            _ => fail!("unexpected result")
+        }
+    }
+    assert_eq!(hashed, vec![true, false]);
+}
+
+#[test]
+fn blocks_are_read_for_peers() {
+    let info = torrent_info();
+    let (storage, _) = counting_storage(&info);
+    let (mut pool, results) = DiskPool::new(2, 1 << 20);
+    write_piece(&mut pool, &info, &storage, 0);
+    pool.submit();
+    let peer = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6881 };
+    pool.read(info.infohash.as_slice(), &storage, peer, 0, BLOCK, BLOCK);
+    loop {
+        match results.recv() {
+            Written(..) => (),
+            Read(_, address, 0, begin, Ok(data)) => {
+                assert_eq!(address, peer);
+                assert_eq!(begin, BLOCK);
+                assert_eq!(data.as_slice(), payload().slice(BLOCK, 2 * BLOCK));
+                break;
+            },
+            _ => fail!("unexpected result")
+        }
+    }
+}
+
+#[test]
+fn queued_writes_past_the_budget_congest_the_pool() {
+    let info = torrent_info();
+    let (storage, _) = counting_storage(&info);
+    let (mut pool, results) = DiskPool::new(1, PIECE_LENGTH);
+    write_piece(&mut pool, &info, &storage, 0);
+    assert_eq!(pool.queued_bytes(), PIECE_LENGTH);
+    assert!(pool.is_congested());
+    pool.submit();
+    let result: DiskResult = results.recv();
+    match result {
+        Written(..) => (),
+        _ => fail!("expected a write")
+    }
+    assert!(!pool.is_congested());
+}
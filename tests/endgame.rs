@@ -0,0 +1,70 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::{PeerConnection, BlockRequest};
+use tensai::download::BLOCK_SIZE;
+use tensai::torrent::Torrent;
+
+mod common;
+
+
+/// A torrent of 2 pieces of 2 blocks each
+fn torrent() -> Torrent {
+    common::memory_torrent(4 * BLOCK_SIZE, 2 * BLOCK_SIZE)
+}
+
+fn seed(torrent: &mut Torrent, port: u16) -> PeerConnection {
+    common::seed(torrent, SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: port })
+}
+
+#[test]
+fn endgame_starts_once_every_block_is_requested() {
+    let mut torrent = torrent();
+    let a = seed(&mut torrent, 1);
+    for _ in range(0u, 3) {
+        assert!(torrent.next_request(&a).is_some());
+        assert!(!torrent.in_endgame());
+    }
+    assert!(torrent.next_request(&a).is_some());
+    assert!(torrent.in_endgame());
+    // blocks are only requested once from each peer
+    assert_eq!(torrent.next_request(&a), None);
+}
+
+#[test]
+fn endgame_requests_blocks_again_and_cancels_them() {
+    let mut torrent = torrent();
+    let a = seed(&mut torrent, 1);
+    let b = seed(&mut torrent, 2);
+    let mut requested = Vec::new();
+    for _ in range(0u, 4) {
+        requested.push(torrent.next_request(&a).unwrap());
+    }
+    let request = torrent.next_request(&b).unwrap();
+    assert!(requested.contains(&request));
+
+    let received = torrent.block_received(&request, &b.address).unwrap();
+    assert!(!received.duplicate);
+    assert_eq!(received.cancel, vec![a.address]);
+    assert_eq!(torrent.traffic.downloaded_bytes, BLOCK_SIZE);
+    assert_eq!(torrent.traffic.duplicate_bytes, 0);
+
+    // the cancel crossed the block on the wire
+    let received = torrent.block_received(&request, &a.address).unwrap();
+    assert!(received.duplicate);
+    assert!(received.cancel.is_empty());
+    assert_eq!(torrent.traffic.downloaded_bytes, BLOCK_SIZE);
+    assert_eq!(torrent.traffic.duplicate_bytes, BLOCK_SIZE);
+}
+
+#[test]
+fn blocks_we_never_asked_for_count_as_duplicates() {
+    let mut torrent = torrent();
+    let a = seed(&mut torrent, 1);
+    let request = BlockRequest { piece: 1, begin: 0, length: BLOCK_SIZE as u32 };
+    assert!(torrent.block_received(&request, &a.address).is_none());
+    assert_eq!(torrent.traffic.duplicate_bytes, BLOCK_SIZE);
+}
@@ -0,0 +1,105 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::client::Client;
+use tensai::engine::{Engine, ConnectBackoff, MAX_CONNECT_FAILURES};
+use tensai::listener::RandomPort;
+use tensai::peer::Peer;
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::torrent::TorrentInfo;
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 32768;
+static SECOND: u64 = 1_000_000_000;
+
+fn payload() -> Vec<u8> {
+    common::payload(100000)
+}
+
+fn torrent_info() -> TorrentInfo {
+    common::torrent_info(payload().as_slice(), PIECE_LENGTH)
+}
+
+fn local(port: u16) -> Peer {
+    Peer { address: SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: port }, peer_id: None }
+}
+
+#[test]
+fn failed_peers_are_retried_later_and_later() {
+    let mut backoff = ConnectBackoff::new();
+    let peer = local(6881).address;
+    assert!(backoff.can_connect(&peer, 0));
+    backoff.failed(&peer, 0);
+    assert!(!backoff.can_connect(&peer, 29 * SECOND));
+    assert!(backoff.can_connect(&peer, 30 * SECOND));
+    backoff.failed(&peer, 30 * SECOND);
+    assert!(!backoff.can_connect(&peer, 89 * SECOND));
+    assert!(backoff.can_connect(&peer, 90 * SECOND));
+    backoff.connected(&peer);
+    assert!(backoff.can_connect(&peer, 90 * SECOND));
+}
+
+#[test]
+fn peers_that_keep_failing_are_given_up_on() {
+    let mut backoff = ConnectBackoff::new();
+    let peer = local(6881).address;
+    for _ in range(0, MAX_CONNECT_FAILURES) {
+        backoff.failed(&peer, 0);
+    }
+    assert!(!backoff.can_connect(&peer, 1000000 * SECOND));
+}
+
+#[test]
+fn connections_are_limited_per_torrent() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    {
+        let torrent = client.add_torrent_with_storage(&info, box MemoryStorage::new(&info) as Box<Storage>);
+        torrent.start();
+        for port in range(1u16, 10) {
+            torrent.session.peers.push(local(port));
+        }
+    }
+    let mut engine = Engine::new(client).unwrap();
+    engine.limits.per_torrent = 3;
+    assert!(engine.step().is_empty());
+    assert_eq!(engine.num_connections(), 3);
+}
+
+#[test]
+fn torrent_is_downloaded_from_a_seeding_engine() {
+    let info = torrent_info();
+    let mut seeder = Client::new();
+    seeder.add_torrent_with_storage(&info, common::memory_storage(&info, payload().as_slice()));
+    let port = seeder.listen(RandomPort).unwrap();
+    let mut seeder = Engine::new(seeder).unwrap();
+
+    let mut leecher = Client::new();
+    {
+        let torrent = leecher.add_torrent_with_storage(&info, box MemoryStorage::new(&info) as Box<Storage>);
+        torrent.start();
+        torrent.session.peers.push(local(port));
+    }
+    let mut leecher = Engine::new(leecher).unwrap();
+
+    let info_hash = info.infohash.as_slice();
+    for _ in range(0, 1000u) {
+        assert!(seeder.step().is_empty());
+        assert!(leecher.step().is_empty());
+        if leecher.client.find_torrent(info_hash).unwrap().have.is_full() {
+            break;
+        }
+    }
+    assert_eq!(seeder.connections(info_hash).len(), 1);
+    let torrent = leecher.client.find_torrent(info_hash).unwrap();
+    assert!(torrent.have.is_full());
+    assert_eq!(torrent.hash_failures, 0);
+    for (index, piece) in payload().as_slice().chunks(PIECE_LENGTH).enumerate() {
+        assert_eq!(torrent.storage.read_block(index, 0, piece.len()).unwrap().as_slice(), piece);
+    }
+}
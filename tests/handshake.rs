@@ -0,0 +1,94 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::BufReader;
+
+use tensai::client::Client;
+use tensai::handshake::{Handshake, Capabilities, HANDSHAKE_LENGTH, PROTOCOL};
+use tensai::handshake::{InvalidProtocol, UnknownInfoHash, SelfConnection};
+use tensai::storage::{Storage, MemoryStorage};
+
+mod common;
+
+
+static PEER_ID: &'static [u8] = b"-XX0001-123456789012";
+
+fn all() -> Capabilities {
+    Capabilities { extensions: true, dht: true, fast: true }
+}
+
+#[test]
+fn round_trip() {
+    let handshake = Handshake::new([7u8, ..20], PEER_ID, &all());
+    let encoded = handshake.encode();
+    assert_eq!(encoded.len(), HANDSHAKE_LENGTH);
+    assert_eq!(encoded.slice(1, 20), PROTOCOL);
+    let decoded = Handshake::decode(encoded.as_slice()).unwrap();
+    assert_eq!(decoded.info_hash.as_slice(), [7u8, ..20].as_slice());
+    assert_eq!(decoded.peer_id.as_slice(), PEER_ID);
+    assert_eq!(decoded.capabilities(), all());
+    let read = Handshake::read_from(&mut BufReader::new(encoded.as_slice())).unwrap();
+    assert_eq!(read.reserved, decoded.reserved);
+}
+
+#[test]
+fn capabilities_use_their_reserved_bits() {
+    let none = Capabilities { extensions: false, dht: false, fast: false };
+    assert_eq!(Handshake::new([0u8, ..20], PEER_ID, &none).reserved, [0u8, ..8]);
+    let extensions = Capabilities { extensions: true, dht: false, fast: false };
+    assert_eq!(Handshake::new([0u8, ..20], PEER_ID, &extensions).reserved, [0, 0, 0, 0, 0, 0x10, 0, 0]);
+    assert_eq!(Handshake::new([0u8, ..20], PEER_ID, &all()).reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    let fast = Capabilities { extensions: false, dht: true, fast: true };
+    assert_eq!(all().intersect(&fast), fast);
+}
+
+#[test]
+fn other_protocols_are_rejected() {
+    let mut encoded = Handshake::new([0u8, ..20], PEER_ID, &all()).encode();
+    *encoded.get_mut(1) = b'b';
+    match Handshake::decode(encoded.as_slice()) {
+        Err(InvalidProtocol) => (),
+        _ => fail!("expected InvalidProtocol")
+    }
+    // a wrong length is caught before reading the rest
+    let mut reader = BufReader::new([18u8]);
+    match Handshake::read_from(&mut reader) {
+        Err(InvalidProtocol) => (),
+        _ => fail!("expected InvalidProtocol")
+    }
+    match Handshake::decode(encoded.slice_to(HANDSHAKE_LENGTH - 1)) {
+        Err(InvalidProtocol) => (),
+        _ => fail!("expected InvalidProtocol")
+    }
+}
+
+#[test]
+fn client_accepts_handshakes_for_its_torrents() {
+    let info = common::unhashed_torrent_info(1000, 256);
+    let mut client = Client::new();
+    client.add_torrent_with_storage(&info, box MemoryStorage::new(&info) as Box<Storage>);
+
+    let handshake = Handshake::new(info.infohash.as_slice(), PEER_ID, &all());
+    assert_eq!(client.accept_handshake(&handshake).unwrap().info.infohash, info.infohash);
+
+    let stranger = Handshake::new([7u8, ..20], PEER_ID, &all());
+    match client.accept_handshake(&stranger) {
+        Err(UnknownInfoHash) => (),
+        _ => fail!("handshake for an unknown torrent was accepted")
+    }
+
+    let ourselves = Handshake::new(info.infohash.as_slice(), client.peer_id().as_bytes(), &all());
+    match client.accept_handshake(&ourselves) {
+        Err(SelfConnection) => (),
+        _ => fail!("connection to ourselves was accepted")
+    }
+}
+
+#[test]
+fn client_advertises_only_what_it_speaks() {
+    let capabilities = Client::new().capabilities();
+    assert!(capabilities.extensions);
+    assert!(!capabilities.dht);
+    assert!(!capabilities.fast);
+}
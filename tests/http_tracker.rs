@@ -0,0 +1,141 @@
+extern crate tensai;
+extern crate bencode;
+
+use std::collections::hashmap::HashSet;
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::io::net::tcp::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use bencode::FromBencode;
+
+use tensai::announce::{AnnounceResponse, AnnounceResult, Success, Failure};
+use tensai::tracker::{SwarmStore, TrackerConfig};
+use tensai::tracker::http::{HttpTracker, MAX_LINE_LENGTH, parse_query, percent_decode};
+
+
+fn start_tracker(config: TrackerConfig) -> SocketAddr {
+    let store = Arc::new(Mutex::new(SwarmStore::new(config)));
+    let mut tracker = HttpTracker::bind(store, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let address = tracker.socket_name();
+    spawn(proc() {
+        tracker.serve().unwrap();
+    });
+    address
+}
+
+/// Send `request` as is, returning the status line and body of the response
+fn send(tracker: SocketAddr, request: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect("127.0.0.1", tracker.port).unwrap();
+    stream.write_str(request).unwrap();
+    let response = stream.read_to_end().unwrap();
+    let end = range(0, response.len() - 3).find(|&i| response.slice(i, i + 4) == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(Vec::from_slice(response.slice_to(end))).unwrap();
+    let status = head.as_slice().lines().next().unwrap().to_str();
+    (status, Vec::from_slice(response.slice_from(end + 4)))
+}
+
+fn get(tracker: SocketAddr, target: &str) -> (String, Vec<u8>) {
+    send(tracker, format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", target).as_slice())
+}
+
+fn status(response: (String, Vec<u8>)) -> String {
+    let (status, _) = response;
+    status
+}
+
+/// 20 bytes of `byte`, escaped for a query string
+fn escaped(byte: u8) -> String {
+    let mut escaped = String::new();
+    for _ in range(0u, 20) {
+        escaped.push_str(format!("%{:02x}", byte).as_slice());
+    }
+    escaped
+}
+
+/// Announce peer `peer` of the torrent with the infohash of 20 `0xaa`,
+/// with `extra` appended to the query
+fn announce(tracker: SocketAddr, peer: u8, port: u16, left: uint, extra: &str) -> AnnounceResult {
+    let target = format!("/announce?info_hash={}&peer_id={}&port={}&left={}{}", escaped(0xaa), escaped(peer), port, left, extra);
+    let (status, body) = get(tracker, target.as_slice());
+    assert_eq!(status.as_slice(), "HTTP/1.0 200 OK");
+    let response: AnnounceResponse = FromBencode::from_bencode(&bencode::from_vec(body).unwrap()).unwrap();
+    match response {
+        Success(result) => result,
+        Failure(message, _) => fail!("announce failed: {}", message)
+    }
+}
+
+#[test]
+fn compact_announce_returns_other_peers() {
+    let tracker = start_tracker(TrackerConfig::new());
+    assert!(announce(tracker, 1, 6881, 100, "&compact=1").peers.is_empty());
+    let result = announce(tracker, 2, 6882, 0, "&compact=1");
+    assert_eq!(result.peers.len(), 1);
+    assert_eq!(result.peers.get(0).address, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6881 });
+    assert_eq!((result.complete, result.incomplete), (1, 1));
+}
+
+#[test]
+fn non_compact_announce_lists_peers_with_their_ids() {
+    let tracker = start_tracker(TrackerConfig::new());
+    announce(tracker, 1, 6881, 100, "&compact=0");
+    let result = announce(tracker, 2, 6882, 0, "&compact=0");
+    assert_eq!(result.peers.len(), 1);
+    assert_eq!(result.peers.get(0).address, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6881 });
+    assert_eq!(result.peers.get(0).peer_id, Some([1u8, ..20]));
+}
+
+#[test]
+fn ipv6_peers_come_in_peers6() {
+    let tracker = start_tracker(TrackerConfig::new());
+    announce(tracker, 1, 6881, 100, "&ip=%3A%3A1");
+    let result = announce(tracker, 2, 6882, 0, "&compact=1");
+    assert_eq!(result.peers.len(), 1);
+    assert_eq!(result.peers.get(0).address, SocketAddr { ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 1), port: 6881 });
+}
+
+#[test]
+fn whitelist_rejects_unknown_torrents() {
+    let mut config = TrackerConfig::new();
+    let mut whitelist = HashSet::new();
+    whitelist.insert(Vec::from_elem(20, 0xbbu8));
+    config.whitelist = Some(whitelist);
+    let tracker = start_tracker(config);
+
+    let target = format!("/announce?info_hash={}&peer_id={}&port=6881", escaped(0xaa), escaped(1));
+    let (status, body) = get(tracker, target.as_slice());
+    assert_eq!(status.as_slice(), "HTTP/1.0 200 OK");
+    let response: AnnounceResponse = FromBencode::from_bencode(&bencode::from_vec(body).unwrap()).unwrap();
+    match response {
+        Failure(..) => (),
+        Success(_) => fail!("announce for a torrent outside the whitelist succeeded")
+    }
+}
+
+#[test]
+fn bad_requests_are_refused() {
+    let tracker = start_tracker(TrackerConfig::new());
+    assert_eq!(status(get(tracker, "/index.html")).as_slice(), "HTTP/1.0 404 Not Found");
+    assert_eq!(status(get(tracker, "/announce?info_hash=%zz")).as_slice(), "HTTP/1.0 400 Bad Request");
+    assert_eq!(status(send(tracker, "POST /announce HTTP/1.0\r\n\r\n")).as_slice(), "HTTP/1.0 400 Bad Request");
+    // one byte over the limit, without the line ever ending
+    let mut line = String::from_str("GET /");
+    for _ in range(0, MAX_LINE_LENGTH - 4) {
+        line.push_char('a');
+    }
+    assert_eq!(status(send(tracker, line.as_slice())).as_slice(), "HTTP/1.0 400 Bad Request");
+}
+
+#[test]
+fn query_values_are_percent_decoded() {
+    assert_eq!(percent_decode("a%20b+c%2F"), Some(Vec::from_slice(b"a b c/")));
+    assert_eq!(percent_decode("%aa%BB"), Some(vec![0xaa, 0xbb]));
+    assert_eq!(percent_decode("%2"), None);
+    assert_eq!(percent_decode("%zz"), None);
+
+    assert_eq!(parse_query("info_hash=%aa%bb&&flag&port=6881"),
+               Some(vec![(String::from_str("info_hash"), vec![0xaa, 0xbb]),
+                         (String::from_str("flag"), Vec::new()),
+                         (String::from_str("port"), Vec::from_slice(b"6881"))]));
+    assert_eq!(parse_query("bad=%g0"), None);
+}
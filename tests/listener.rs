@@ -0,0 +1,83 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::tcp::TcpStream;
+
+use tensai::client::Client;
+use tensai::engine::Engine;
+use tensai::handshake::Handshake;
+use tensai::listener::{Listener, Port, PortRange, RandomPort};
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::torrent::TorrentInfo;
+
+mod common;
+
+
+fn torrent_info() -> TorrentInfo {
+    common::unhashed_torrent_info(1000, 256)
+}
+
+/// Listeners on two adjacent ports, the lower one first
+fn adjacent_listeners() -> (Listener, Listener) {
+    loop {
+        let first = Listener::bind("127.0.0.1", RandomPort).unwrap();
+        let port = first.port();
+        if port == 65535 {
+            continue;
+        }
+        match Listener::bind("127.0.0.1", Port(port + 1)) {
+            Ok(second) => return (first, second),
+            // someone else has the next port, try elsewhere
+            Err(_) => ()
+        }
+    }
+}
+
+#[test]
+fn port_ranges_bind_inside_the_range() {
+    let (first, second) = adjacent_listeners();
+    let range = PortRange(first.port(), second.port());
+    assert!(Listener::bind("127.0.0.1", range.clone()).is_err());
+
+    // the only free port of the range is the second one
+    let port = second.port();
+    drop(second);
+    assert_eq!(Listener::bind("127.0.0.1", range).unwrap().port(), port);
+}
+
+#[test]
+fn incoming_connections_are_handed_to_their_torrent() {
+    let info = torrent_info();
+    let mut client = Client::new();
+    client.add_torrent_with_storage(&info, box MemoryStorage::new(&info) as Box<Storage>).start();
+    let port = client.listen(RandomPort).unwrap();
+    assert_eq!(client.listen_port(), port);
+    assert_eq!(client.find_torrent(info.infohash.as_slice()).unwrap().listen_port, port);
+    let capabilities = client.capabilities();
+    let peer_id = client.peer_id();
+    let mut engine = Engine::new(client).unwrap();
+
+    let mut peer = TcpStream::connect("127.0.0.1", port).unwrap();
+    Handshake::new(info.infohash.as_slice(), b"-XX0001-123456789012", &capabilities).write_to(&mut peer).unwrap();
+    let mut stranger = TcpStream::connect("127.0.0.1", port).unwrap();
+    Handshake::new([7u8, ..20].as_slice(), b"-XX0001-210987654321", &capabilities).write_to(&mut stranger).unwrap();
+
+    let info_hash = info.infohash.as_slice();
+    for _ in range(0, 100u) {
+        assert!(engine.step().is_empty());
+        // the stranger's connection is closed once its handshake is read
+        if engine.connections(info_hash).len() == 1 && engine.num_connections() == 1 {
+            break;
+        }
+    }
+    assert_eq!(engine.num_connections(), 1);
+    let connections = engine.connections(info_hash);
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].peer_id.unwrap().as_slice(), b"-XX0001-123456789012");
+
+    let reply = Handshake::read_from(&mut peer).unwrap();
+    assert_eq!(reply.info_hash.as_slice(), info_hash);
+    assert_eq!(reply.peer_id.as_slice(), peer_id.as_bytes());
+    assert!(Handshake::read_from(&mut stranger).is_err());
+}
@@ -0,0 +1,105 @@
+extern crate tensai;
+
+use tensai::availability::PieceAvailability;
+use tensai::bitfield::Bitfield;
+use tensai::picker::{PiecePicker, RarestFirst, Sequential, RANDOM_FIRST_PIECES};
+use tensai::picker::{PRIORITY_SKIP, PRIORITY_HIGHEST};
+
+
+static NUM_PIECES: uint = 8;
+
+/// Availability where piece `index` is had by `counts[index]` peers
+fn availability(counts: &[uint]) -> PieceAvailability {
+    let mut availability = PieceAvailability::new(counts.len());
+    for (index, &count) in counts.iter().enumerate() {
+        for _ in range(0, count) {
+            availability.add_piece(index);
+        }
+    }
+    availability
+}
+
+/// Bitfield with the first `count` pieces set
+fn first(count: uint) -> Bitfield {
+    let mut bitfield = Bitfield::new(NUM_PIECES);
+    for index in range(0, count) {
+        bitfield.set(index);
+    }
+    bitfield
+}
+
+#[test]
+fn rarest_piece_is_picked_once_we_have_a_few() {
+    let picker = PiecePicker::new(NUM_PIECES, RarestFirst);
+    let have = first(RANDOM_FIRST_PIECES);
+    let availability = availability([9, 9, 9, 9, 5, 2, 7, 3]);
+    for _ in range(0u, 20) {
+        assert_eq!(picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability), Some(5));
+    }
+}
+
+#[test]
+fn ties_for_rarest_are_broken_among_the_rarest() {
+    let picker = PiecePicker::new(NUM_PIECES, RarestFirst);
+    let have = first(RANDOM_FIRST_PIECES);
+    let availability = availability([9, 9, 9, 9, 2, 5, 2, 3]);
+    for _ in range(0u, 20) {
+        let picked = picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability);
+        assert!(picked == Some(4) || picked == Some(6));
+    }
+}
+
+#[test]
+fn first_pieces_are_picked_at_random() {
+    let picker = PiecePicker::new(NUM_PIECES, RarestFirst);
+    let have = first(RANDOM_FIRST_PIECES - 1);
+    let availability = availability([9, 9, 9, 9, 9, 9, 9, 1]);
+    // with piece 7 the rarest, rarest first would never pick anything else
+    let picks: Vec<uint> = range(0u, 100)
+        .map(|_| picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability).unwrap())
+        .collect();
+    assert!(picks.iter().all(|&index| index >= RANDOM_FIRST_PIECES - 1));
+    assert!(picks.iter().any(|&index| index != 7));
+}
+
+#[test]
+fn sequential_picks_in_index_order() {
+    let picker = PiecePicker::new(NUM_PIECES, Sequential);
+    let availability = availability([1, 1, 1, 1, 1, 1, 1, 9]);
+    let mut peer_has = Bitfield::full(NUM_PIECES);
+    peer_has.clear(2);
+    assert_eq!(picker.pick(&first(2), &peer_has, &availability), Some(3));
+}
+
+#[test]
+fn skipped_pieces_are_never_picked() {
+    let mut picker = PiecePicker::new(NUM_PIECES, Sequential);
+    let availability = availability([1, 1, 1, 1, 1, 1, 1, 1]);
+    for index in range(0, NUM_PIECES - 1) {
+        picker.set_priority(index, PRIORITY_SKIP);
+    }
+    assert_eq!(picker.pick(&Bitfield::new(NUM_PIECES), &Bitfield::full(NUM_PIECES), &availability), Some(7));
+    picker.set_priority(7, PRIORITY_SKIP);
+    assert_eq!(picker.pick(&Bitfield::new(NUM_PIECES), &Bitfield::full(NUM_PIECES), &availability), None);
+}
+
+#[test]
+fn higher_priority_comes_before_rarity() {
+    let mut picker = PiecePicker::new(NUM_PIECES, RarestFirst);
+    let have = first(RANDOM_FIRST_PIECES);
+    let availability = availability([9, 9, 9, 9, 1, 2, 9, 3]);
+    picker.set_priority(6, PRIORITY_HIGHEST);
+    assert_eq!(picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability), Some(6));
+}
+
+#[test]
+fn partial_pieces_are_finished_first() {
+    let mut picker = PiecePicker::new(NUM_PIECES, RarestFirst);
+    let have = first(RANDOM_FIRST_PIECES);
+    let availability = availability([9, 9, 9, 9, 1, 2, 9, 3]);
+    picker.piece_started(6);
+    assert_eq!(picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability), Some(6));
+    assert_eq!(picker.pick_new(&have, &Bitfield::full(NUM_PIECES), &availability), Some(4));
+    picker.piece_stopped(6);
+    assert_eq!(picker.pick(&have, &Bitfield::full(NUM_PIECES), &availability), Some(4));
+}
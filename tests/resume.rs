@@ -0,0 +1,130 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::TempDir;
+use std::io::fs::File;
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+
+use tensai::client::Client;
+use tensai::connection::PeerConnection;
+use tensai::download::BLOCK_SIZE;
+use tensai::peer::Peer;
+use tensai::resume::ResumeData;
+use tensai::torrent::{TorrentInfo, TrackerStatus, Checking, Downloading, Seeding};
+use tensai::storage::Storage;
+use tensai::wire::Have;
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 256;
+
+fn payload() -> Vec<u8> {
+    common::payload(1000)
+}
+
+fn torrent_info() -> TorrentInfo {
+    common::torrent_info(payload().as_slice(), PIECE_LENGTH)
+}
+
+#[test]
+fn resume_data_round_trip() {
+    let mut tracker = TrackerStatus::new(String::from_str("udp://tracker:80"), 1);
+    tracker.failures = 2;
+    tracker.tracker_id = Some(String::from_str("abc"));
+    let resume = ResumeData {
+        info_hash: Vec::from_elem(20, 1u8),
+        pieces: vec![0xa0],
+        partial: vec![(1, vec![0x80])],
+        files: vec![Some((1000, 1234567)), None],
+        uploaded: 10,
+        downloaded: 20,
+        duplicate: 30,
+        trackers: vec![tracker],
+        peers: vec![Peer { address: SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 }, peer_id: None },
+                    Peer { address: SocketAddr { ip: Ipv6Addr(1, 2, 3, 4, 5, 6, 7, 8), port: 6882 }, peer_id: None }],
+    };
+    let decoded = ResumeData::decode(resume.encode().as_slice()).unwrap();
+    assert_eq!(decoded.info_hash, resume.info_hash);
+    assert_eq!(decoded.pieces, resume.pieces);
+    assert_eq!(decoded.partial, resume.partial);
+    assert_eq!(decoded.files, resume.files);
+    assert_eq!((decoded.uploaded, decoded.downloaded, decoded.duplicate), (10, 20, 30));
+    assert_eq!(decoded.trackers.get(0).url, resume.trackers.get(0).url);
+    assert_eq!(decoded.trackers.get(0).tier, 1);
+    assert_eq!(decoded.trackers.get(0).failures, 2);
+    assert_eq!(decoded.trackers.get(0).tracker_id, Some(String::from_str("abc")));
+    let addresses: Vec<SocketAddr> = decoded.peers.iter().map(|peer| peer.address).collect();
+    assert_eq!(addresses, resume.peers.iter().map(|peer| peer.address).collect());
+
+    assert!(ResumeData::decode(b"d4:spam4:eggse").is_none());
+}
+
+#[test]
+fn resumed_torrents_skip_the_check() {
+    let dir = TempDir::new("tensai-resume").unwrap();
+    let info = torrent_info();
+
+    let mut client = Client::new();
+    client.set_resume_dir(dir.path().clone());
+    client.add_torrent_with_storage(&info, common::memory_storage(&info, payload().as_slice()));
+    while client.is_checking() {
+        client.check_torrents();
+    }
+    client.find_torrent(info.infohash.as_slice()).unwrap().traffic.uploaded_bytes = 42;
+    client.save_resume_data().unwrap();
+
+    let mut client = Client::new();
+    client.set_resume_dir(dir.path().clone());
+    let torrent = client.add_torrent_with_storage(&info, common::memory_storage(&info, payload().as_slice()));
+    assert_eq!(torrent.status, Seeding);
+    assert!(torrent.have.is_full());
+    assert_eq!(torrent.traffic.uploaded_bytes, 42);
+}
+
+#[test]
+fn changed_files_are_checked_again() {
+    let dir = TempDir::new("tensai-resume").unwrap();
+    let data = dir.path().join("data");
+    let info = torrent_info();
+
+    let mut client = Client::new();
+    client.set_resume_dir(dir.path().join("resume"));
+    {
+        let torrent = client.add_torrent(&info, data.clone()).unwrap();
+        torrent.storage.write_block(0, 0, payload().slice_to(PIECE_LENGTH)).unwrap();
+        torrent.start_check();
+        while torrent.check_step().unwrap() {}
+        assert_eq!(torrent.status, Downloading);
+    }
+    client.save_resume_data().unwrap();
+
+    // the file is completed and grows behind our back
+    let mut file = File::create(&data.join("test"));
+    file.write(payload().as_slice()).unwrap();
+    file.write([0u8]).unwrap();
+
+    let mut client = Client::new();
+    client.set_resume_dir(dir.path().join("resume"));
+    let torrent = client.add_torrent(&info, data.clone()).unwrap();
+    assert_eq!(torrent.status, Checking);
+    while torrent.check_step().unwrap() {}
+    assert_eq!(torrent.status, Seeding);
+}
+
+#[test]
+fn partial_pieces_record_only_written_blocks() {
+    let mut torrent = common::memory_torrent(4 * BLOCK_SIZE, 2 * BLOCK_SIZE);
+    let mut connection = PeerConnection::new(SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 }, 2, 0);
+    torrent.receive(&mut connection, &Have(0), 0).unwrap();
+    for _ in range(0u, 2) {
+        let request = torrent.next_request(&connection).unwrap();
+        torrent.block_received(&request, &connection.address).unwrap();
+    }
+    // both blocks arrived, only the first is on disk yet
+    torrent.blocks_written(0, 0, BLOCK_SIZE);
+    assert_eq!(torrent.resume_data().unwrap().partial, vec![(0, vec![0x80])]);
+    torrent.blocks_written(0, BLOCK_SIZE, BLOCK_SIZE);
+    assert_eq!(torrent.resume_data().unwrap().partial, vec![(0, vec![0xc0])]);
+}
@@ -0,0 +1,131 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::{PeerConnection, InvalidRequest, BlockRequest};
+use tensai::download::BLOCK_SIZE;
+use tensai::torrent::Torrent;
+use tensai::wire;
+
+mod common;
+
+
+static PIECE_LENGTH: uint = 256;
+
+fn payload() -> Vec<u8> {
+    common::payload(1000)
+}
+
+/// A torrent of `payload()` that has every piece but the last. The piece
+/// hashes aren't checked here, so they're all zeros.
+fn seeding_torrent() -> Torrent {
+    let info = common::unhashed_torrent_info(1000, PIECE_LENGTH);
+    let storage = common::memory_storage(&info, payload().as_slice());
+    let mut torrent = Torrent::new(info, storage);
+    for index in range(0, 3) {
+        torrent.have.set(index);
+    }
+    torrent
+}
+
+/// Serve `requests` the way the engine does once the disk pool read their
+/// blocks, returning how many were sent
+fn serve(torrent: &mut Torrent, peer: &mut PeerConnection, requests: &[BlockRequest]) -> uint {
+    let mut served = 0;
+    for request in requests.iter() {
+        let data = torrent.storage.read_block(request.piece as uint, request.begin as uint, request.length as uint).unwrap();
+        if torrent.serve_block(peer, request, data, 2) {
+            served += 1;
+        }
+    }
+    served
+}
+
+fn queued(peer: &PeerConnection) -> Vec<BlockRequest> {
+    peer.peer_requests.iter().map(|request| request.clone()).collect()
+}
+
+fn unchoked_peer() -> PeerConnection {
+    let mut connection = PeerConnection::new(SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 }, 4, 0);
+    connection.send(wire::Unchoke, 0).unwrap();
+    connection.take_outgoing();
+    connection
+}
+
+#[test]
+fn requests_are_served_from_storage() {
+    let mut torrent = seeding_torrent();
+    let mut peer = unchoked_peer();
+    torrent.receive(&mut peer, &wire::Request(1, 16, 100), 1).unwrap();
+    torrent.receive(&mut peer, &wire::Request(2, 0, 256), 1).unwrap();
+    let requests = queued(&peer);
+    assert_eq!(serve(&mut torrent, &mut peer, requests.as_slice()), 2);
+    assert!(peer.peer_requests.is_empty());
+    let data = payload();
+    assert_eq!(peer.take_outgoing(), vec![wire::Piece(1, 16, Vec::from_slice(data.slice(272, 372))),
+                                          wire::Piece(2, 0, Vec::from_slice(data.slice(512, 768)))]);
+    assert_eq!(torrent.traffic.uploaded_bytes, 356);
+    assert_eq!(peer.upload_rate.total, 356);
+}
+
+#[test]
+fn cancelled_requests_are_not_served() {
+    let mut torrent = seeding_torrent();
+    let mut peer = unchoked_peer();
+    torrent.receive(&mut peer, &wire::Request(0, 0, 128), 1).unwrap();
+    torrent.receive(&mut peer, &wire::Request(0, 128, 128), 1).unwrap();
+    // the cancel arrives while the blocks are read
+    let requests = queued(&peer);
+    torrent.receive(&mut peer, &wire::Cancel(0, 0, 128), 1).unwrap();
+    assert_eq!(serve(&mut torrent, &mut peer, requests.as_slice()), 1);
+    assert_eq!(torrent.traffic.uploaded_bytes, 128);
+}
+
+#[test]
+fn requests_for_pieces_we_lack_are_rejected() {
+    let mut torrent = seeding_torrent();
+    let mut peer = unchoked_peer();
+    assert_eq!(torrent.receive(&mut peer, &wire::Request(3, 0, 16), 1),
+               Err(InvalidRequest(BlockRequest { piece: 3, begin: 0, length: 16 })));
+    // past the end of the piece
+    assert_eq!(torrent.receive(&mut peer, &wire::Request(0, 200, 100), 1),
+               Err(InvalidRequest(BlockRequest { piece: 0, begin: 200, length: 100 })));
+}
+
+#[test]
+fn requests_must_be_one_block_at_most() {
+    let info = common::unhashed_torrent_info(2 * BLOCK_SIZE, 2 * BLOCK_SIZE);
+    let storage = common::memory_storage(&info, common::payload(2 * BLOCK_SIZE).as_slice());
+    let mut torrent = Torrent::new(info, storage);
+    torrent.have.set(0);
+    let mut peer = unchoked_peer();
+    let length = BLOCK_SIZE as u32;
+    assert_eq!(torrent.receive(&mut peer, &wire::Request(0, 0, length + 1), 1),
+               Err(InvalidRequest(BlockRequest { piece: 0, begin: 0, length: length + 1 })));
+    assert_eq!(torrent.receive(&mut peer, &wire::Request(0, 16, 0), 1),
+               Err(InvalidRequest(BlockRequest { piece: 0, begin: 16, length: 0 })));
+    torrent.receive(&mut peer, &wire::Request(0, length, length), 1).unwrap();
+    assert_eq!(queued(&peer).len(), 1);
+}
+
+#[test]
+fn failed_reads_drop_the_request() {
+    let mut torrent = seeding_torrent();
+    let mut peer = unchoked_peer();
+    torrent.receive(&mut peer, &wire::Request(0, 0, 128), 1).unwrap();
+    let request = BlockRequest { piece: 0, begin: 0, length: 128 };
+    torrent.serve_failed(&mut peer, &request, 2);
+    assert!(peer.peer_requests.is_empty());
+    assert!(peer.take_outgoing().is_empty());
+
+    // peers speaking the fast extension are told
+    let mut peer = unchoked_peer();
+    peer.fast = true;
+    torrent.receive(&mut peer, &wire::Request(0, 0, 128), 1).unwrap();
+    torrent.serve_failed(&mut peer, &request, 2);
+    assert!(peer.peer_requests.is_empty());
+    assert_eq!(peer.take_outgoing(), vec![wire::RejectRequest(0, 0, 128)]);
+    assert_eq!(torrent.traffic.uploaded_bytes, 0);
+}
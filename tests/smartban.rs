@@ -0,0 +1,55 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::PeerConnection;
+use tensai::download::BLOCK_SIZE;
+use tensai::torrent::Torrent;
+
+mod common;
+
+
+/// A torrent of a single piece of 2 blocks
+fn torrent() -> Torrent {
+    common::memory_torrent(2 * BLOCK_SIZE, 2 * BLOCK_SIZE)
+}
+
+fn seed(torrent: &mut Torrent, host: u8, port: u16) -> PeerConnection {
+    common::seed(torrent, SocketAddr { ip: Ipv4Addr(10, 0, 0, host), port: port })
+}
+
+/// Have the peer on `connection` deliver the next block
+fn deliver(torrent: &mut Torrent, connection: &PeerConnection) {
+    let request = torrent.next_request(connection).unwrap();
+    torrent.block_received(&request, &connection.address).unwrap();
+}
+
+#[test]
+fn only_the_peer_that_sent_the_corrupt_block_is_banned() {
+    let mut torrent = torrent();
+    let good = seed(&mut torrent, 1, 6881);
+    let bad = seed(&mut torrent, 2, 6881);
+    let piece = common::payload(2 * BLOCK_SIZE);
+    let mut corrupt = piece.clone();
+    *corrupt.get_mut(BLOCK_SIZE + 10) ^= 0xff;
+
+    deliver(&mut torrent, &good);
+    deliver(&mut torrent, &bad);
+    torrent.piece_hashed(0, false, corrupt.as_slice());
+    assert!(!torrent.is_banned(good.address.ip));
+    assert!(!torrent.is_banned(bad.address.ip));
+
+    deliver(&mut torrent, &good);
+    deliver(&mut torrent, &good);
+    torrent.piece_hashed(0, true, piece.as_slice());
+    assert!(!torrent.is_banned(good.address.ip));
+    assert!(torrent.is_banned(bad.address.ip));
+
+    // the ban outlives the connection and holds for the address, whatever
+    // the port
+    torrent.peer_disconnected(&bad);
+    let again = seed(&mut torrent, 2, 51413);
+    assert!(torrent.is_banned(again.address.ip));
+}
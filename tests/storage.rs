@@ -0,0 +1,121 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::TempDir;
+
+use bencode::FromBencode;
+
+use tensai::torrent::TorrentInfo;
+use tensai::storage::{Storage, StorageResult, FileLayout, FileSpan, DiskStorage, MemoryStorage, BlockOutOfRange, MissingData};
+use tensai::storage::{DiskOptions, Sparse, Full, Lazy};
+
+mod common;
+
+
+/// A multi-file torrent with files of the given names and lengths
+fn torrent_info(files: &[(&str, uint)], piece_length: uint) -> TorrentInfo {
+    let total = files.iter().map(|&(_, length)| length).fold(0, |a, b| a + b);
+    let num_pieces = (total + piece_length - 1) / piece_length;
+    let mut list = String::new();
+    for &(name, length) in files.iter() {
+        list.push_str(format!("d6:lengthi{}e4:pathl{}:{}ee", length, name.len(), name).as_slice());
+    }
+    let mut torrent = Vec::from_slice(format!("d8:announce14:http://tracker4:infod5:filesl{}e4:name4:test12:piece lengthi{}e6:pieces{}:",
+                                              list, piece_length, num_pieces * 20).as_bytes());
+    torrent.push_all(Vec::from_elem(num_pieces * 20, 0u8).as_slice());
+    torrent.push_all(b"ee");
+    FromBencode::from_bencode(&bencode::from_vec(torrent).unwrap()).unwrap()
+}
+
+/// Write the whole payload block by block and read it back the same way
+fn round_trip(storage: &mut Storage, total: uint, piece_length: uint, block: uint) -> StorageResult<()> {
+    let data = common::payload(total);
+    for offset in std::iter::range_step(0, total, block) {
+        let length = block.min(total - offset);
+        try!(storage.write_block(offset / piece_length, offset % piece_length, data.slice(offset, offset + length)));
+    }
+    try!(storage.flush());
+    for offset in std::iter::range_step(0, total, block) {
+        let length = block.min(total - offset);
+        let read = try!(storage.read_block(offset / piece_length, offset % piece_length, length));
+        assert_eq!(read.as_slice(), data.slice(offset, offset + length));
+    }
+    Ok(())
+}
+
+#[test]
+fn blocks_span_file_boundaries() {
+    let layout = FileLayout::new(&torrent_info([("a", 10), ("b", 0), ("c", 20)], 16));
+    assert_eq!(layout.spans(0, 8, 8).unwrap(),
+               vec![FileSpan { file: 0, offset: 8, length: 2 }, FileSpan { file: 2, offset: 0, length: 6 }]);
+    assert_eq!(layout.spans(1, 0, 14).unwrap(), vec![FileSpan { file: 2, offset: 6, length: 14 }]);
+    assert!(layout.spans(1, 0, 15).is_err());
+    assert!(layout.spans(0, 8, 9).is_err());
+}
+
+#[test]
+fn memory_storage_round_trip() {
+    let info = torrent_info([("a", 1000), ("b", 3000)], 1024);
+    let mut storage = MemoryStorage::new(&info);
+    assert!(!storage.exists());
+    match storage.read_block(0, 0, 16) {
+        Err(MissingData) => (),
+        other => fail!("unexpected {}", other)
+    }
+    round_trip(&mut storage, 4000, 1024, 256).unwrap();
+    assert_eq!(storage.data().unwrap(), common::payload(4000).as_slice());
+    match storage.write_block(3, 1000, [0u8, ..16]) {
+        Err(BlockOutOfRange) => (),
+        other => fail!("unexpected {}", other)
+    }
+}
+
+#[test]
+fn disk_storage_round_trip() {
+    let dir = TempDir::new("tensai-storage").unwrap();
+    let info = torrent_info([("a", 1000), ("b", 3000), ("c", 5)], 1024);
+    let mut storage = DiskStorage::new(&info, dir.path().clone());
+    assert!(!storage.exists());
+    round_trip(&mut storage, 4005, 1024, 300).unwrap();
+    assert!(storage.exists());
+    assert_eq!(dir.path().join("b").stat().unwrap().size, 3000);
+
+    let moved = dir.path().join("moved");
+    storage.move_to(&moved).unwrap();
+    assert!(!dir.path().join("a").exists());
+    assert_eq!(storage.read_block(3, 0, 5).unwrap(), Vec::from_slice(common::payload(4005).slice(3072, 3077)));
+
+    storage.delete().unwrap();
+    assert!(!storage.exists());
+}
+
+#[test]
+fn allocation_modes() {
+    let info = torrent_info([("a", 1000), ("dir/b", 3000)], 1024);
+    for &(allocation, created) in [(Sparse, true), (Full, true), (Lazy, false)].iter() {
+        let dir = TempDir::new("tensai-allocation").unwrap();
+        let mut storage = DiskStorage::new(&info, dir.path().clone());
+        storage.allocate(&DiskOptions { allocation: allocation, overwrite: false }).unwrap();
+        assert_eq!(storage.exists(), created);
+        if created {
+            assert_eq!(dir.path().join_many(["dir", "b"]).stat().unwrap().size, 3000);
+        }
+    }
+}
+
+#[test]
+fn existing_data_is_kept_unless_overwriting() {
+    let dir = TempDir::new("tensai-allocation").unwrap();
+    let info = torrent_info([("a", 1000), ("b", 3000)], 1024);
+    let mut storage = DiskStorage::new(&info, dir.path().clone());
+    storage.write_block(0, 0, [7u8, ..16]).unwrap();
+    storage.flush().unwrap();
+
+    let mut storage = DiskStorage::new(&info, dir.path().clone());
+    storage.allocate(&DiskOptions::new()).unwrap();
+    assert_eq!(storage.read_block(0, 0, 16).unwrap(), Vec::from_elem(16, 7u8));
+
+    storage.allocate(&DiskOptions { allocation: Sparse, overwrite: true }).unwrap();
+    assert_eq!(storage.read_block(0, 0, 16).unwrap(), Vec::from_elem(16, 0u8));
+}
@@ -0,0 +1,138 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::{PeerConnection, ExtensionsNotNegotiated};
+use tensai::extension::{ExtendedHandshake, HANDSHAKE_ID};
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::tex;
+use tensai::torrent::Torrent;
+use tensai::wire::Extended;
+
+mod common;
+
+
+fn torrent(private: bool) -> Torrent {
+    let mut info = common::unhashed_torrent_info(1000, 256);
+    info.announce_list = Some(vec![vec![common::url("a")], vec![common::url("b")]]);
+    info.metainfo.private = private;
+    let storage = box MemoryStorage::new(&info) as Box<Storage>;
+    Torrent::new(info, storage)
+}
+
+/// A connection to a peer that speaks `lt_tex` under `id`
+fn connection(id: u8, tracker_hash: Option<Vec<u8>>) -> PeerConnection {
+    let address = SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 };
+    let mut connection = PeerConnection::new(address, 4, 0);
+    connection.extensions = true;
+    let mut handshake = ExtendedHandshake::new();
+    handshake.messages.push((tex::EXTENSION_NAME.to_str(), id));
+    handshake.tracker_hash = tracker_hash;
+    connection.receive(&Extended(HANDSHAKE_ID, handshake.encode()), 0).unwrap();
+    connection
+}
+
+#[test]
+fn tex_message_round_trip() {
+    let urls = vec![common::url("a"), "udp://b:80".to_str()];
+    assert_eq!(tex::decode_message(tex::encode_message(urls.as_slice()).as_slice()), Some(urls));
+}
+
+#[test]
+fn tex_message_drops_urls_of_other_protocols() {
+    let urls = vec![common::url("a"), "ftp://b/announce".to_str()];
+    assert_eq!(tex::decode_message(tex::encode_message(urls.as_slice()).as_slice()), Some(vec![common::url("a")]));
+    assert_eq!(tex::decode_message(b"i42e"), None);
+}
+
+#[test]
+fn extended_handshake_round_trip() {
+    let mut handshake = ExtendedHandshake::new();
+    handshake.messages.push(("lt_tex".to_str(), 3));
+    handshake.messages.push(("ut_pex".to_str(), 1));
+    handshake.tracker_hash = Some(Vec::from_elem(20, 7u8));
+    let decoded = ExtendedHandshake::decode(handshake.encode().as_slice()).unwrap();
+    assert_eq!(decoded.id("lt_tex"), Some(3));
+    assert_eq!(decoded.id("ut_pex"), Some(1));
+    assert_eq!(decoded.id("ut_metadata"), None);
+    assert_eq!(decoded.tracker_hash, handshake.tracker_hash);
+}
+
+#[test]
+fn extended_handshake_leaves_out_disabled_extensions() {
+    let decoded = ExtendedHandshake::decode(b"d1:md6:lt_texi0e6:ut_pexi1eee").unwrap();
+    assert_eq!(decoded.messages, vec![("ut_pex".to_str(), 1)]);
+    assert_eq!(ExtendedHandshake::decode(b"d1:vi1ee"), None);
+}
+
+#[test]
+fn torrent_offers_lt_tex_unless_private() {
+    assert_eq!(torrent(false).extended_handshake().id(tex::EXTENSION_NAME), Some(tex::MESSAGE_ID));
+    let handshake = torrent(true).extended_handshake();
+    assert!(handshake.messages.is_empty());
+    assert_eq!(handshake.tracker_hash, None);
+}
+
+#[test]
+fn private_torrents_exchange_no_trackers() {
+    let mut torrent = torrent(true);
+    torrent.announce_done(vec![(0, common::success())]);
+    let mut connection = connection(3, None);
+    torrent.send_tex(&mut connection, 0);
+    assert!(connection.take_outgoing().is_empty());
+
+    torrent.receive_tex(tex::encode_message([common::url("c")]).as_slice());
+    assert!(torrent.tex_job("peer".to_str()).is_none());
+}
+
+#[test]
+fn working_trackers_are_sent_under_the_peers_id() {
+    let mut torrent = torrent(false);
+    torrent.announce_done(vec![(0, common::success())]);
+    let mut connection = connection(3, None);
+    torrent.send_tex(&mut connection, 0);
+    assert_eq!(connection.take_outgoing(), vec![Extended(3, tex::encode_message([common::url("a")]))]);
+
+    // nothing before the interval, and nothing new after it
+    torrent.send_tex(&mut connection, tex::TEX_INTERVAL - 1);
+    torrent.send_tex(&mut connection, tex::TEX_INTERVAL);
+    assert!(connection.take_outgoing().is_empty());
+}
+
+#[test]
+fn peers_with_our_tracker_list_are_sent_nothing() {
+    let mut torrent = torrent(false);
+    torrent.announce_done(vec![(0, common::success())]);
+    let mut connection = connection(3, torrent.extended_handshake().tracker_hash);
+    torrent.send_tex(&mut connection, 0);
+    assert!(connection.take_outgoing().is_empty());
+}
+
+#[test]
+fn extension_messages_need_the_extension_protocol() {
+    let mut torrent = torrent(false);
+    let address = SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 };
+    let mut connection = PeerConnection::new(address, 4, 0);
+    let message = Extended(tex::MESSAGE_ID, tex::encode_message([common::url("c")]));
+    assert_eq!(torrent.receive(&mut connection, &message, 0), Err(ExtensionsNotNegotiated));
+}
+
+#[test]
+fn received_trackers_are_only_added_once_they_answer() {
+    let mut torrent = torrent(false);
+    let mut connection = connection(3, None);
+    let message = Extended(tex::MESSAGE_ID, tex::encode_message([common::url("a"), common::url("c"), common::url("d")]));
+    torrent.receive(&mut connection, &message, 0).unwrap();
+    assert_eq!(torrent.trackers.len(), 2);
+    assert!(torrent.tex_job("peer".to_str()).is_some());
+
+    torrent.tex_done(vec![(common::url("c"), common::success()), (common::url("d"), None)]);
+    let trackers: Vec<(String, uint)> = torrent.trackers.iter().map(|tracker| (tracker.url.clone(), tracker.tier)).collect();
+    assert_eq!(trackers, vec![(common::url("a"), 0), (common::url("b"), 1), (common::url("c"), 2)]);
+
+    // the one that didn't answer isn't tried again
+    torrent.receive_tex(tex::encode_message([common::url("d")]).as_slice());
+    assert!(torrent.tex_job("peer".to_str()).is_none());
+}
@@ -0,0 +1,78 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use tensai::announce::Failure;
+use tensai::storage::{Storage, MemoryStorage};
+use tensai::torrent::Torrent;
+
+mod common;
+
+
+/// A torrent with trackers `a`, `b` and `c` in the first tier and `d` in
+/// the second
+fn torrent() -> Torrent {
+    let mut info = common::unhashed_torrent_info(1000, 256);
+    info.announce_list = Some(vec![vec![common::url("a"), common::url("b"), common::url("c")], vec![common::url("d")]]);
+    let storage = box MemoryStorage::new(&info) as Box<Storage>;
+    Torrent::new(info, storage)
+}
+
+fn order(torrent: &Torrent) -> Vec<(String, uint)> {
+    torrent.trackers.iter().map(|tracker| (tracker.url.clone(), tracker.tier)).collect()
+}
+
+#[test]
+fn trackers_are_kept_in_tier_order() {
+    let torrent = torrent();
+    let tiers: Vec<uint> = order(&torrent).iter().map(|&(_, tier)| tier).collect();
+    assert_eq!(tiers, vec![0, 0, 0, 1]);
+    assert_eq!(torrent.trackers.get(3).url, common::url("d"));
+}
+
+#[test]
+fn answering_tracker_moves_to_the_front_of_its_tier() {
+    let mut torrent = torrent();
+    let before = order(&torrent);
+    torrent.announce_done(vec![(0, None), (1, Some(Failure("down".to_str(), None))), (2, common::success())]);
+    let after = order(&torrent);
+    assert_eq!(after, vec![before.get(2).clone(), before.get(0).clone(), before.get(1).clone(), before.get(3).clone()]);
+    assert!(torrent.trackers.get(0).working);
+
+    // the front one answering again changes nothing
+    torrent.announce_done(vec![(0, common::success())]);
+    assert_eq!(order(&torrent), after);
+}
+
+#[test]
+fn answering_tracker_stays_in_its_tier() {
+    let mut torrent = torrent();
+    let before = order(&torrent);
+    torrent.announce_done(vec![(0, None), (1, None), (2, None), (3, common::success())]);
+    assert_eq!(order(&torrent), before);
+}
+
+#[test]
+fn left_counts_the_short_last_piece() {
+    let mut torrent = torrent();
+    assert_eq!(torrent.left(), 1000);
+    torrent.have.set(3);
+    assert_eq!(torrent.left(), 768);
+    torrent.have.set(0);
+    assert_eq!(torrent.left(), 512);
+}
+
+#[test]
+fn started_and_completed_are_announced_once() {
+    let mut torrent = torrent();
+    assert!(torrent.announce_job("peer".to_str()).is_some());
+    torrent.announce_done(vec![(0, None), (1, common::success())]);
+    assert!(torrent.trackers.get(0).started && !torrent.trackers.get(0).completed);
+    assert!(!torrent.trackers.get(1).started);
+
+    torrent.completed = true;
+    torrent.trackers.get_mut(0).next_announce = None;
+    assert!(torrent.announce_job("peer".to_str()).is_some());
+    torrent.announce_done(vec![(0, common::success())]);
+    assert!(torrent.trackers.get(0).completed);
+}
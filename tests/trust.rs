@@ -0,0 +1,59 @@
+extern crate tensai;
+extern crate bencode;
+extern crate crypto = "rust-crypto";
+
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+
+use tensai::connection::PeerConnection;
+use tensai::download::BLOCK_SIZE;
+use tensai::torrent::{Torrent, MIN_TRUST, TRUST_GAIN, TRUST_PENALTY};
+
+mod common;
+
+
+/// A torrent of 4 pieces of a single block
+fn torrent() -> Torrent {
+    common::memory_torrent(4 * BLOCK_SIZE, BLOCK_SIZE)
+}
+
+fn seed(torrent: &mut Torrent) -> PeerConnection {
+    common::seed(torrent, SocketAddr { ip: Ipv4Addr(10, 0, 0, 1), port: 6881 })
+}
+
+/// Have the peer on `connection` deliver a piece, and hash it
+fn download_piece(torrent: &mut Torrent, connection: &PeerConnection, passed: bool) {
+    let request = torrent.next_request(connection).unwrap();
+    torrent.block_received(&request, &connection.address).unwrap();
+    torrent.piece_hashed(request.piece as uint, passed, Vec::from_elem(BLOCK_SIZE, 0u8).as_slice());
+}
+
+#[test]
+fn failed_pieces_cost_their_senders_trust() {
+    let mut torrent = torrent();
+    let connection = seed(&mut torrent);
+    let ip = connection.address.ip;
+    download_piece(&mut torrent, &connection, true);
+    assert_eq!(torrent.trust(ip), TRUST_GAIN);
+    download_piece(&mut torrent, &connection, false);
+    assert_eq!(torrent.trust(ip), TRUST_GAIN - TRUST_PENALTY);
+    assert_eq!(torrent.hash_failures, 1);
+    assert!(!torrent.is_banned(ip));
+}
+
+#[test]
+fn peers_are_banned_at_min_trust() {
+    let mut torrent = torrent();
+    let connection = seed(&mut torrent);
+    let ip = connection.address.ip;
+    let mut failures = 0;
+    while torrent.trust(ip) - TRUST_PENALTY > MIN_TRUST {
+        download_piece(&mut torrent, &connection, false);
+        failures += 1;
+        assert!(!torrent.is_banned(ip));
+    }
+    download_piece(&mut torrent, &connection, false);
+    assert_eq!(torrent.hash_failures, failures + 1);
+    // trust doesn't sink below the minimum
+    assert_eq!(torrent.trust(ip), MIN_TRUST);
+    assert!(torrent.is_banned(ip));
+}
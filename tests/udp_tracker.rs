@@ -0,0 +1,124 @@
+extern crate tensai;
+
+use std::collections::hashmap::HashSet;
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+use tensai::announce::{Success, Failure};
+use tensai::tracker::{SwarmStore, TrackerConfig, AnnounceRequest, Started, Completed};
+use tensai::tracker::udp::{UdpTracker, UdpTrackerClient, RateLimiter};
+
+
+fn start_tracker(config: TrackerConfig) -> SocketAddr {
+    let store = Arc::new(Mutex::new(SwarmStore::new(config)));
+    let mut tracker = UdpTracker::bind(store, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 0 }).unwrap();
+    let address = tracker.socket_name().unwrap();
+    spawn(proc() {
+        tracker.serve().unwrap();
+    });
+    address
+}
+
+fn client(tracker: SocketAddr) -> UdpTrackerClient {
+    let mut client = UdpTrackerClient::new(tracker).unwrap();
+    client.timeout = 500;
+    client
+}
+
+fn request(peer: u8, port: u16, left: uint) -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: Vec::from_elem(20, 0xaau8),
+        peer_id: [peer, ..20],
+        address: SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: port },
+        uploaded: 0,
+        downloaded: 0,
+        left: left,
+        event: Some(Started),
+        numwant: None,
+    }
+}
+
+#[test]
+fn announce_returns_other_peers() {
+    let tracker = start_tracker(TrackerConfig::new());
+    let mut first = client(tracker);
+    let mut second = client(tracker);
+
+    match first.announce(&request(1, 6881, 100)).unwrap() {
+        Success(result) => assert!(result.peers.is_empty()),
+        Failure(message, _) => fail!("announce failed: {}", message)
+    }
+    match second.announce(&request(2, 6882, 0)).unwrap() {
+        Success(result) => {
+            assert_eq!(result.peers.len(), 1);
+            assert_eq!(result.peers.get(0).address, SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: 6881 });
+            assert_eq!(result.complete, 1);
+            assert_eq!(result.incomplete, 1);
+        },
+        Failure(message, _) => fail!("announce failed: {}", message)
+    }
+}
+
+#[test]
+fn scrape_counts_peers() {
+    let tracker = start_tracker(TrackerConfig::new());
+    let mut client = client(tracker);
+    client.announce(&request(1, 6881, 100)).unwrap();
+    let mut completed = request(2, 6882, 0);
+    completed.event = Some(Completed);
+    client.announce(&completed).unwrap();
+
+    let info_hash = Vec::from_elem(20, 0xaau8);
+    let scrape = client.scrape([info_hash.clone()]).unwrap();
+    let torrent = scrape.torrents.find(&info_hash).unwrap();
+    assert_eq!(torrent.complete, 1);
+    assert_eq!(torrent.incomplete, 1);
+    assert_eq!(torrent.downloaded, 1);
+}
+
+#[test]
+fn whitelist_rejects_unknown_torrents() {
+    let mut config = TrackerConfig::new();
+    let mut whitelist = HashSet::new();
+    whitelist.insert(Vec::from_elem(20, 0xbbu8));
+    config.whitelist = Some(whitelist);
+    let tracker = start_tracker(config);
+
+    match client(tracker).announce(&request(1, 6881, 100)).unwrap() {
+        Failure(..) => (),
+        Success(_) => fail!("announce for a torrent outside the whitelist succeeded")
+    }
+}
+
+#[test]
+fn rate_limiter_allows_a_burst_then_the_rate() {
+    let mut limiter = RateLimiter::new(5.0, 20.0);
+    let ip = Ipv4Addr(10, 0, 0, 1);
+    for _ in range(0u, 20) {
+        assert!(limiter.allow(ip, 100.0));
+    }
+    assert!(!limiter.allow(ip, 100.0));
+    // a token every 200 ms
+    assert!(!limiter.allow(ip, 100.125));
+    assert!(limiter.allow(ip, 100.25));
+    assert!(!limiter.allow(ip, 100.25));
+    let allowed = range(0u, 10).filter(|_| limiter.allow(ip, 101.25)).count();
+    assert_eq!(allowed, 5);
+    // other sources have buckets of their own
+    assert!(limiter.allow(Ipv4Addr(10, 0, 0, 2), 101.25));
+}
+
+#[test]
+fn rate_limiter_forgets_full_buckets() {
+    let mut limiter = RateLimiter::new(5.0, 20.0);
+    let ip = Ipv4Addr(10, 0, 0, 1);
+    for _ in range(0u, 20) {
+        limiter.allow(ip, 0.0);
+    }
+    // refilled after 4 seconds, so pruning loses nothing that matters
+    limiter.prune(4.0);
+    for _ in range(0u, 20) {
+        assert!(limiter.allow(ip, 4.0));
+    }
+    assert!(!limiter.allow(ip, 4.0));
+}
@@ -0,0 +1,115 @@
+extern crate tensai;
+
+use std::io::BufReader;
+
+use tensai::wire;
+use tensai::wire::{Message, Decoder, MessageTooLong, UnknownMessage, InvalidLength};
+
+
+fn all_messages() -> Vec<Message> {
+    vec![wire::KeepAlive,
+         wire::Choke,
+         wire::Unchoke,
+         wire::Interested,
+         wire::NotInterested,
+         wire::Have(42),
+         wire::Bitfield(vec![0xff, 0x80]),
+         wire::Request(1, 16384, 16384),
+         wire::Piece(1, 16384, Vec::from_elem(100, 7u8)),
+         wire::Cancel(1, 16384, 16384),
+         wire::Port(6881),
+         wire::HaveAll,
+         wire::HaveNone,
+         wire::RejectRequest(1, 16384, 16384),
+         wire::Extended(0, Vec::from_slice(b"d1:md6:lt_texi1eee"))]
+}
+
+#[test]
+fn round_trip() {
+    for message in all_messages().move_iter() {
+        let encoded = message.encode();
+        assert_eq!(encoded.len(), 4 + message.len());
+        let mut decoder = Decoder::new();
+        decoder.feed(encoded.as_slice());
+        assert_eq!(decoder.next(), Ok(Some(message)));
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
+
+#[test]
+fn round_trip_blocking_reader() {
+    for message in all_messages().move_iter() {
+        let encoded = message.encode();
+        let mut reader = BufReader::new(encoded.as_slice());
+        assert_eq!(Message::read_from(&mut reader, wire::MAX_MESSAGE_LENGTH).unwrap(), message);
+    }
+}
+
+#[test]
+fn decoder_accepts_partial_reads() {
+    let messages = all_messages();
+    let mut stream = Vec::new();
+    for message in messages.iter() {
+        stream.push_all(message.encode().as_slice());
+    }
+    let mut decoder = Decoder::new();
+    let mut decoded = Vec::new();
+    // one byte at a time is as partial as it gets
+    for byte in stream.iter() {
+        decoder.feed([*byte]);
+        loop {
+            match decoder.next() {
+                Ok(Some(message)) => decoded.push(message),
+                Ok(None) => break,
+                Err(e) => fail!("decoding failed: {}", e)
+            }
+        }
+    }
+    assert_eq!(decoded, messages);
+}
+
+#[test]
+fn decoder_enforces_max_length() {
+    let mut decoder = Decoder::with_max_length(16);
+    decoder.feed(wire::Piece(0, 0, Vec::from_elem(32, 0u8)).encode().as_slice());
+    assert_eq!(decoder.next(), Err(MessageTooLong(41)));
+}
+
+#[test]
+fn decoder_rejects_malformed_messages() {
+    let mut decoder = Decoder::new();
+    decoder.feed([0, 0, 0, 1, 99]);
+    assert_eq!(decoder.next(), Err(UnknownMessage(99)));
+
+    let mut decoder = Decoder::new();
+    decoder.feed([0, 0, 0, 2, wire::HAVE, 0]);
+    assert_eq!(decoder.next(), Err(InvalidLength(wire::HAVE, 2)));
+}
+
+#[test]
+fn decoder_keeps_undecoded_bytes_across_feeds() {
+    let messages = all_messages();
+    let mut stream = Vec::new();
+    for message in messages.iter() {
+        stream.push_all(message.encode().as_slice());
+    }
+    let mut decoder = Decoder::new();
+    let mut decoded = Vec::new();
+    let mut fed = 0;
+    // chunks that end mid-message, with decoded bytes still in the buffer
+    for chunk in stream.as_slice().chunks(37) {
+        decoder.feed(chunk);
+        fed += chunk.len();
+        loop {
+            match decoder.next() {
+                Ok(Some(message)) => decoded.push(message),
+                Ok(None) => break,
+                Err(e) => fail!("decoding failed: {}", e)
+            }
+        }
+        let consumed = decoded.iter().map(|message| 4 + message.len()).fold(0, |a, b| a + b);
+        assert_eq!(consumed + decoder.buffered(), fed);
+    }
+    assert_eq!(decoded, messages);
+    assert_eq!(decoder.buffered(), 0);
+}